@@ -1,8 +1,12 @@
+use rand::Rng;
 use std::convert::TryInto;
 use std::convert::TryFrom;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
 
+pub mod edns;
 pub mod header;
 pub mod message;
+pub mod nsec;
 pub mod question;
 pub mod resourcerecord;
 
@@ -13,9 +17,9 @@ mod tests;
 
 // EFFECTS: Extends given vector with string in RFC 1035 domain name format
 #[inline]
-fn append_rname(bytes: &mut Vec<u8>, s: &String) -> Result<(), Box<dyn std::error::Error>>
+fn append_rname(bytes: &mut Vec<u8>, s: &str) -> Result<(), Box<dyn std::error::Error>>
 {
-    for w in s.split(".")
+    for w in trim_root(s).split('.').filter(|w| !w.is_empty())
     {
         bytes.push(w.len()
                    .try_into()?);
@@ -27,16 +31,153 @@ fn append_rname(bytes: &mut Vec<u8>, s: &String) -> Result<(), Box<dyn std::erro
     Ok(())
 }
 
+// EFFECTS: Strips a single trailing root label dot, if present
+#[inline]
+pub fn trim_root(name: &str) -> &str
+{
+    name.strip_suffix('.').unwrap_or(name)
+}
+
+// EFFECTS: Compares two domain names, ignoring ASCII case and a trailing dot
+pub fn name_eq(a: &str, b: &str) -> bool
+{
+    trim_root(a).eq_ignore_ascii_case(trim_root(b))
+}
+
+// EFFECTS: Returns name with the case of each letter chosen at random, so
+//          that a response must echo bits an off-path attacker can't guess
+//          (draft-vixie-dnsext-dns0x20)
+pub fn randomize_case(name: &str) -> String
+{
+    let mut rng = rand::thread_rng();
+
+    name.chars()
+        .map(|c| if rng.gen() { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() })
+        .collect()
+}
+
+// EFFECTS: Returns true if name is zone or lies beneath it. Every name is
+//          within the root zone ("")
+pub fn in_zone(name: &str, zone: &str) -> bool
+{
+    let name = trim_root(name);
+    let zone = trim_root(zone);
+
+    if zone.is_empty() || name_eq(name, zone)
+    {
+        return true;
+    }
+
+    name.len() > zone.len()
+        && name.as_bytes()[name.len() - zone.len() - 1] == b'.'
+        && name_eq(&name[name.len() - zone.len()..], zone)
+}
+
+// EFFECTS: Returns the name PTR records for ip live under: its octets in
+//          reverse under in-addr.arpa (RFC 1035 3.5), or its nibbles in
+//          reverse under ip6.arpa (RFC 3596 2.5)
+pub fn reverse_name(ip: IpAddr) -> String
+{
+    match ip
+    {
+        IpAddr::V4(v4) => {
+            let o = v4.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", o[3], o[2], o[1], o[0])
+        },
+        IpAddr::V6(v6) => {
+            let nibbles: String = v6.octets()
+                                    .iter()
+                                    .rev()
+                                    .map(|b| format!("{:x}.{:x}.", b & 0xf, b >> 4))
+                                    .collect();
+            format!("{}ip6.arpa", nibbles)
+        },
+    }
+}
+
+// EFFECTS: Parses a name under in-addr.arpa or ip6.arpa back into the
+//          network its labels spell out and the length of its prefix, so
+//          "2.0.192.in-addr.arpa" gives (192.0.2.0, 24) and a full name the
+//          address itself. Returns None for any other name, or labels that
+//          aren't decimal octets or hex nibbles respectively
+pub fn parse_reverse_name(name: &str) -> Option<(IpAddr, u8)>
+{
+    let name = trim_root(name).to_ascii_lowercase();
+    let labels = |suffix: &str| -> Option<Vec<String>> {
+        if name == suffix
+        {
+            return Some(Vec::new());
+        }
+
+        let rest = name.strip_suffix(suffix)?.strip_suffix('.')?;
+        Some(rest.split('.').rev().map(String::from).collect())
+    };
+
+    if let Some(labels) = labels("in-addr.arpa")
+    {
+        let mut octets = [0; 4];
+        if labels.len() > octets.len()
+        {
+            return None;
+        }
+
+        for (o, label) in octets.iter_mut().zip(labels.iter())
+        {
+            // Octets are written without leading zeros
+            if label.is_empty() || (label.len() > 1 && label.starts_with('0'))
+                || !label.bytes().all(|b| b.is_ascii_digit())
+            {
+                return None;
+            }
+            *o = label.parse().ok()?;
+        }
+
+        Some((Ipv4Addr::from(octets).into(), labels.len() as u8 * 8))
+    } else if let Some(labels) = labels("ip6.arpa") {
+        let mut octets = [0; 16];
+        if labels.len() > octets.len() * 2
+        {
+            return None;
+        }
+
+        for (i, label) in labels.iter().enumerate()
+        {
+            if label.len() != 1
+            {
+                return None;
+            }
+            let nibble = u8::from_str_radix(label, 16).ok()?;
+            octets[i / 2] |= if i % 2 == 0 { nibble << 4 } else { nibble };
+        }
+
+        Some((Ipv6Addr::from(octets).into(), labels.len() as u8 * 4))
+    } else {
+        None
+    }
+}
+
 //  ------------ DNS Data Types -------------
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum QType
 {
     A     = 1,
     AAAA  = 28,
     NS    = 2,
     CNAME = 5,
+    SOA   = 6,
     PTR   = 12,
+    NSEC  = 47,
+    NSEC3 = 50,
+}
+
+impl QType
+{
+    // EFFECTS: Returns true if records of type t answer a question for self
+    pub fn matches(self, t: Type) -> bool
+    {
+        self as u16 == t as u16
+    }
 }
 
 impl TryFrom<u16> for QType
@@ -50,20 +191,62 @@ impl TryFrom<u16> for QType
             28 => Ok(QType::AAAA),
             2  => Ok(QType::NS),
             5  => Ok(QType::CNAME),
+            6  => Ok(QType::SOA),
             12 => Ok(QType::PTR),
+            47 => Ok(QType::NSEC),
+            50 => Ok(QType::NSEC3),
             _  => Err("QType Value Not Supported"),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Type
 {
     A     = 1,
     AAAA  = 28,
     NS    = 2,
     CNAME = 5,
+    SOA   = 6,
     PTR   = 12,
+    NSEC  = 47,
+    NSEC3 = 50,
+}
+
+impl From<QType> for Type
+{
+    fn from(q: QType) -> Self
+    {
+        match q
+        {
+            QType::A     => Type::A,
+            QType::AAAA  => Type::AAAA,
+            QType::NS    => Type::NS,
+            QType::CNAME => Type::CNAME,
+            QType::SOA   => Type::SOA,
+            QType::PTR   => Type::PTR,
+            QType::NSEC  => Type::NSEC,
+            QType::NSEC3 => Type::NSEC3,
+        }
+    }
+}
+
+impl From<Type> for QType
+{
+    fn from(t: Type) -> Self
+    {
+        match t
+        {
+            Type::A     => QType::A,
+            Type::AAAA  => QType::AAAA,
+            Type::NS    => QType::NS,
+            Type::CNAME => QType::CNAME,
+            Type::SOA   => QType::SOA,
+            Type::PTR   => QType::PTR,
+            Type::NSEC  => QType::NSEC,
+            Type::NSEC3 => QType::NSEC3,
+        }
+    }
 }
 
 impl TryFrom<u16> for Type
@@ -77,13 +260,16 @@ impl TryFrom<u16> for Type
             28 => Ok(Type::AAAA),
             2  => Ok(Type::NS),
             5  => Ok(Type::CNAME),
+            6  => Ok(Type::SOA),
             12 => Ok(Type::PTR),
+            47 => Ok(Type::NSEC),
+            50 => Ok(Type::NSEC3),
             _  => Err("(RR)Type Value Not Supported"),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum QClass
 {
     IN = 1,
@@ -104,7 +290,7 @@ impl TryFrom<u16> for QClass
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Class
 {
     IN = 1,
@@ -0,0 +1,65 @@
+use super::*;
+
+// ------------- EDNS(0) -------------
+// The OPT pseudo-RR of RFC 6891. It always has the root as its owner, and its
+// class and TTL fields carry the fields below rather than a class and a TTL
+pub const OPT_TYPE: u16 = 41;
+const DO_BIT: u16 = 0x8000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edns
+{
+    // Largest UDP payload the sender can reassemble
+    pub payload_size: u16,
+    // Upper 8 bits of the 12 bit extended RCODE
+    pub ext_rcode: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    // Raw {code, length, data} option list
+    pub options: Vec<u8>,
+}
+
+impl Edns
+{
+    pub fn new(payload_size: u16) -> Self
+    {
+        Edns {
+            payload_size,
+            ext_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let mut bytes = vec![0];
+        let flags: u16 = if self.dnssec_ok { DO_BIT } else { 0 };
+        let rd_len: u16 = self.options
+                              .len()
+                              .try_into()
+                              .expect("Edns::to_bytes couldn't fit options length in a u16");
+
+        bytes.extend(&OPT_TYPE.to_be_bytes());
+        bytes.extend(&self.payload_size.to_be_bytes());
+        bytes.push(self.ext_rcode);
+        bytes.push(self.version);
+        bytes.extend(&flags.to_be_bytes());
+        bytes.extend(&rd_len.to_be_bytes());
+        bytes.extend(&self.options);
+
+        bytes
+    }
+
+    pub fn from_flags(payload_size: u16, ext_rcode: u8, version: u8, flags: u16, options: &[u8]) -> Self
+    {
+        Edns {
+            payload_size,
+            ext_rcode,
+            version,
+            dnssec_ok: flags & DO_BIT != 0,
+            options: options.to_vec(),
+        }
+    }
+}
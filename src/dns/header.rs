@@ -78,7 +78,7 @@ impl TryFrom<u8> for RespCode
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Header
 {
     // r1
@@ -99,6 +99,7 @@ pub struct Header
     pub ar_count: u16,
 }
 
+#[allow(clippy::eq_op)]
 impl Header
 {
     const QR_SHIFT: u8 = 8 - 1;
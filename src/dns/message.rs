@@ -1,10 +1,14 @@
 use super::*;
+use super::edns::*;
 use super::header::*;
 use super::question::*;
 use super::resourcerecord::*;
 
+// RFC 1035 (2.3.4) limit for UDP messages without EDNS
+pub const UDP_MSG_SIZE: usize = 512;
+
 // ------------- Message -------------
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Message
 {
     pub header: Header,
@@ -12,7 +16,8 @@ pub struct Message
     pub answs:  Option<Vec<ResourceRecord>>,
     pub auths:  Option<Vec<ResourceRecord>>,
     pub adds:   Option<Vec<ResourceRecord>>,
-
+    // Written after (and counted in ar_count with) the additional records
+    pub edns:   Option<Edns>,
 }
 
 impl Message
@@ -30,17 +35,66 @@ impl Message
             }
         }
 
-        for aces in [&self.answs, &self.auths, &self.adds].iter() {
-            if let Some(ace) = aces {
-                for a in ace {
-                    bytes.extend(a.to_bytes());
-                }
+        for ace in [&self.answs, &self.auths, &self.adds].iter().copied().flatten() {
+            for a in ace {
+                bytes.extend(a.to_bytes());
             }
         }
 
+        if let Some(edns) = &self.edns {
+            bytes.extend(edns.to_bytes());
+        }
+
         bytes
     }
 
+    // EFFECTS: Attaches an OPT record, counting it in ar_count unless one
+    //          was already present
+    pub fn set_edns(&mut self, edns: Edns)
+    {
+        if self.edns.is_none() {
+            self.header.ar_count += 1;
+        }
+
+        self.edns = Some(edns);
+    }
+
+    // EFFECTS: Returns the largest response the sender can take over UDP
+    pub fn udp_payload_size(&self) -> usize
+    {
+        self.edns
+            .as_ref()
+            .map_or(UDP_MSG_SIZE, |e| usize::from(e.payload_size).max(UDP_MSG_SIZE))
+    }
+
+    // EFFECTS: Returns true if self is a response carrying the ID and echoing
+    //          the question section of query
+    pub fn is_response_to(&self, query: &Message) -> bool
+    {
+        let echoed = match (&self.quests, &query.quests)
+        {
+            (Some(rs), Some(qs)) => {
+                rs.len() == qs.len()
+                    && rs.iter().zip(qs).all(|(r, q)| {
+                        r.qtype == q.qtype && r.qclass == q.qclass && name_eq(&r.qname, &q.qname)
+                    })
+            },
+            (None, None) => true,
+            _ => false,
+        };
+
+        self.header.qr == QR::Response && self.header.id == query.header.id && echoed
+    }
+
+    // EFFECTS: Returns true if the question section of self spells each
+    //          name exactly as query did, letter case included
+    pub fn echoes_case(&self, query: &Message) -> bool
+    {
+        let names = |m: &Message| m.quests.iter().flatten().map(|q| q.qname.clone()).collect::<Vec<_>>();
+
+        names(self) == names(query)
+    }
+
     pub fn build_query(id: u16, quests: Vec<Question>) -> Message
     {
         let qd_count: u16 = quests.len()
@@ -67,6 +121,7 @@ impl Message
             answs: None,
             auths: None,
             adds: None,
+            edns: None,
         }
     }
 
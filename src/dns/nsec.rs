@@ -0,0 +1,114 @@
+use ring::digest;
+use std::cmp::Ordering;
+
+use super::{ append_rname, trim_root };
+
+// The only NSEC3 hash algorithm defined, SHA-1 (RFC 5155 section 11)
+pub const NSEC3_SHA1: u8 = 1;
+// Set when the span of an NSEC3 record may hold unsigned delegations, so
+// that it proves nothing about the names in it (RFC 5155 section 3.1.2.1)
+pub const NSEC3_OPT_OUT: u8 = 0x01;
+
+const BASE32HEX: &[u8; 32] = b"0123456789abcdefghijklmnopqrstuv";
+
+// EFFECTS: Returns the labels of name lowercased, the root's first, so that
+//          comparing the results orders names canonically (RFC 4034
+//          section 6.1)
+pub fn canonical_key(name: &str) -> Vec<String>
+{
+    trim_root(name).rsplit('.')
+                   .filter(|l| !l.is_empty())
+                   .map(|l| l.to_ascii_lowercase())
+                   .collect()
+}
+
+// EFFECTS: Orders two domain names canonically
+pub fn canonical_cmp(a: &str, b: &str) -> Ordering
+{
+    canonical_key(a).cmp(&canonical_key(b))
+}
+
+// EFFECTS: Returns true if x falls strictly between owner and next, the
+//          ends of the span an NSEC or NSEC3 record denies. The last record
+//          of a zone wraps around, its next being the first owner
+pub fn covers<T: Ord + ?Sized>(owner: &T, next: &T, x: &T) -> bool
+{
+    if owner < next
+    {
+        owner < x && x < next
+    } else {
+        owner < x || x < next
+    }
+}
+
+// EFFECTS: Returns the NSEC3 hash of name (RFC 5155 section 5): SHA-1 of
+//          its lowercase wire form and salt, taken again over the digest
+//          and salt iterations more times. None if name has a label too
+//          long to hash
+pub fn nsec3_hash(name: &str, salt: &[u8], iterations: u16) -> Option<Vec<u8>>
+{
+    let mut x = Vec::new();
+    append_rname(&mut x, &name.to_ascii_lowercase()).ok()?;
+
+    for _ in 0..=iterations
+    {
+        let mut ctx = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+        ctx.update(&x);
+        ctx.update(salt);
+        x = ctx.finish().as_ref().to_vec();
+    }
+
+    Some(x)
+}
+
+// EFFECTS: Encodes bytes in base32 with the extended hex alphabet and no
+//          padding, as NSEC3 owner names hold hashes (RFC 4648 section 7)
+pub fn base32hex_encode(bytes: &[u8]) -> String
+{
+    let mut s = String::new();
+
+    for chunk in bytes.chunks(5)
+    {
+        let mut buf = [0; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let n = buf.iter().fold(0u64, |n, b| n << 8 | u64::from(*b));
+
+        for i in 0..(chunk.len() * 8).div_ceil(5)
+        {
+            s.push(char::from(BASE32HEX[(n >> (35 - i * 5)) as usize & 0x1f]));
+        }
+    }
+
+    s
+}
+
+// EFFECTS: Decodes an unpadded base32hex label in either case, or returns
+//          None if it isn't one
+pub fn base32hex_decode(s: &str) -> Option<Vec<u8>>
+{
+    let mut bytes = Vec::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+
+    for c in s.bytes()
+    {
+        let v = BASE32HEX.iter().position(|&b| b == c.to_ascii_lowercase())?;
+        acc = acc << 5 | v as u32;
+        bits += 5;
+
+        if bits >= 8
+        {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    // Leftover bits are padding, and must be zero
+    if acc != 0
+    {
+        return None;
+    }
+
+    Some(bytes)
+}
@@ -1,30 +1,82 @@
 use super::*;
 
+use std::collections::BTreeMap;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+
 // ------------- Resource Record -------------
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum RData
 {
     A(u8,u8,u8,u8),
     AAAA(u16, u16, u16, u16, u16, u16, u16, u16),
     NS(String),
     CNAME(String),
-    PTR(String,)
+    SOA {
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        // TTL for negative answers from the zone (RFC 2308)
+        minimum: u32,
+    },
+    PTR(String,),
+    // The next owner name of a signed zone in canonical order, and the
+    // types present at this one, so that every name and type in between
+    // is proven not to exist (RFC 4034 section 4)
+    NSEC {
+        next: String,
+        types: Vec<u16>,
+    },
+    // The same over the hashes of owner names, the next of which is held
+    // unencoded (RFC 5155 section 3)
+    NSEC3 {
+        hash_alg: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed: Vec<u8>,
+        types: Vec<u16>,
+    },
 }
 
 impl RData
 {
+    pub fn rr_type(&self) -> Type
+    {
+        match self
+        {
+            RData::A(..) => Type::A,
+            RData::AAAA(..) => Type::AAAA,
+            RData::NS(_) => Type::NS,
+            RData::CNAME(_) => Type::CNAME,
+            RData::SOA { .. } => Type::SOA,
+            RData::PTR(_) => Type::PTR,
+            RData::NSEC { .. } => Type::NSEC,
+            RData::NSEC3 { .. } => Type::NSEC3,
+        }
+    }
+
+    // EFFECTS: Returns the address held by an A or AAAA record
+    pub fn to_ip(&self) -> Option<IpAddr>
+    {
+        match *self
+        {
+            RData::A(b1, b2, b3, b4) =>
+                Some(IpAddr::V4(Ipv4Addr::new(b1, b2, b3, b4))),
+            RData::AAAA(tb1, tb2, tb3, tb4, tb5, tb6, tb7, tb8) =>
+                Some(IpAddr::V6(Ipv6Addr::new(tb1, tb2, tb3, tb4, tb5, tb6, tb7, tb8))),
+            _ => None,
+        }
+    }
+
     fn to_bytes(&self) -> Vec<u8>
     {
         match self
         {
             RData::A(b1,b2,b3,b4) => {
-                let mut v = Vec::new();
-                v.push(*b1);
-                v.push(*b2);
-                v.push(*b3);
-                v.push(*b4);
-
-                v
+                vec![*b1, *b2, *b3, *b4]
             },
             RData::AAAA(tb1, tb2, tb3, tb4, tb5, tb6, tb7, tb8) => {
                 let mut v: Vec<u8> = Vec::new();
@@ -43,7 +95,7 @@ impl RData
             RData::NS(rname) => {
                 let mut v = Vec::new();
 
-                append_rname(&mut v, &rname)
+                append_rname(&mut v, rname)
                     .expect("Couldn't convert NS domain name to bytes (section too long)");
 
                 v
@@ -51,24 +103,105 @@ impl RData
             RData::CNAME(rname) => {
                 let mut v = Vec::new();
 
-                append_rname(&mut v, &rname)
+                append_rname(&mut v, rname)
                     .expect("Couldn't convert CName domain name to bytes (section too long)");
 
                 v
             }
+            RData::SOA { mname, rname, serial, refresh, retry, expire, minimum } => {
+                let mut v = Vec::new();
+
+                append_rname(&mut v, mname)
+                    .expect("Couldn't convert SOA MName to bytes (section too long)");
+                append_rname(&mut v, rname)
+                    .expect("Couldn't convert SOA RName to bytes (section too long)");
+                for n in [serial, refresh, retry, expire, minimum].iter()
+                {
+                    v.extend(&n.to_be_bytes());
+                }
+
+                v
+            }
             RData::PTR(rname) => {
                 let mut v = Vec::new();
 
-                append_rname(&mut v, &rname)
+                append_rname(&mut v, rname)
                     .expect("Couldn't convert PTR domain name to bytes (section too long)");
 
                 v
             }
+            RData::NSEC { next, types } => {
+                let mut v = Vec::new();
+
+                append_rname(&mut v, next)
+                    .expect("Couldn't convert NSEC next domain name to bytes (section too long)");
+                append_type_bitmap(&mut v, types);
+
+                v
+            }
+            RData::NSEC3 { hash_alg, flags, iterations, salt, next_hashed, types } => {
+                let mut v = vec![*hash_alg, *flags];
+
+                v.extend(&iterations.to_be_bytes());
+                v.push(salt.len() as u8);
+                v.extend(salt);
+                v.push(next_hashed.len() as u8);
+                v.extend(next_hashed);
+                append_type_bitmap(&mut v, types);
+
+                v
+            }
+        }
+    }
+}
+
+// EFFECTS: Extends bytes with types as an NSEC type bitmap: a window of up
+//          to 32 octets for each block of 256 types that has any, the bit
+//          of each type set (RFC 4034 section 4.1.2)
+fn append_type_bitmap(bytes: &mut Vec<u8>, types: &[u16])
+{
+    let mut windows: BTreeMap<u8, Vec<u8>> = BTreeMap::new();
+
+    for t in types
+    {
+        let [window, low] = t.to_be_bytes();
+        let bitmap = windows.entry(window).or_default();
+        let octet = usize::from(low / 8);
+
+        if bitmap.len() <= octet
+        {
+            bitmap.resize(octet + 1, 0);
         }
+        bitmap[octet] |= 0x80 >> (low % 8);
+    }
+
+    for (window, bitmap) in windows
+    {
+        bytes.push(window);
+        bytes.push(bitmap.len() as u8);
+        bytes.extend(bitmap);
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl From<IpAddr> for RData
+{
+    fn from(ip: IpAddr) -> Self
+    {
+        match ip
+        {
+            IpAddr::V4(v4) => {
+                let [b1, b2, b3, b4] = v4.octets();
+                RData::A(b1, b2, b3, b4)
+            },
+            IpAddr::V6(v6) => {
+                let [tb1, tb2, tb3, tb4, tb5, tb6, tb7, tb8] = v6.segments();
+                RData::AAAA(tb1, tb2, tb3, tb4, tb5, tb6, tb7, tb8)
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct ResourceRecord
 {
     pub name: String,
@@ -81,6 +214,24 @@ pub struct ResourceRecord
 
 impl ResourceRecord
 {
+    // EFFECTS: Builds an IN class record, deriving type and rd_len from rdata
+    pub fn new(name: &str, ttl: u32, rdata: RData) -> Self
+    {
+        let rd_len = rdata.to_bytes()
+                          .len()
+                          .try_into()
+                          .expect("ResourceRecord::new couldn't fit rdata length in a u16");
+
+        ResourceRecord {
+            name: name.to_string(),
+            rr_type: rdata.rr_type(),
+            rr_class: Class::IN,
+            ttl,
+            rd_len,
+            rdata,
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8>
     {
         let mut bytes = Vec::new();
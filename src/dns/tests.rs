@@ -273,6 +273,7 @@ fn message_all_lowval_to_bytes()
         answs: None,
         auths: None,
         adds: None,
+        edns: None,
     };
 
     let v: Vec<u8> = vec![0; 12];
@@ -304,6 +305,7 @@ fn message_all_highval_to_bytes()
         answs: None,
         auths: None,
         adds: None,
+        edns: None,
     };
 
     let v: Vec<u8> = vec![
@@ -350,6 +352,7 @@ fn question_simple_to_bytes()
         answs: None,
         auths: None,
         adds: None,
+        edns: None,
     };
 
     let mut v: Vec<u8> = vec![0; 12];
@@ -404,6 +407,7 @@ fn questions_to_bytes()
         answs: None,
         auths: None,
         adds: None,
+        edns: None,
     };
 
     let mut v: Vec<u8> = vec![0; 12];
@@ -430,3 +434,149 @@ fn questions_to_bytes()
 
     assert_eq!(v, m.to_bytes())
 }
+
+#[test]
+fn test_is_response_to()
+{
+    let q = Message::build_query(0x1234, vec![
+        Question {
+            qname: String::from("www.example.com"),
+            qtype: QType::A,
+            qclass: QClass::IN,
+        },
+    ]);
+
+    let mut r = q.clone();
+    r.header.qr = QR::Response;
+    assert!(r.is_response_to(&q));
+
+    let mut r2 = r.clone();
+    r2.quests.as_mut().unwrap()[0].qname = String::from("WWW.Example.com.");
+    assert!(r2.is_response_to(&q));
+
+    assert!(!q.is_response_to(&q));
+
+    let mut wrong_id = r.clone();
+    wrong_id.header.id = 0x1235;
+    assert!(!wrong_id.is_response_to(&q));
+
+    let mut wrong_name = r.clone();
+    wrong_name.quests.as_mut().unwrap()[0].qname = String::from("www.example.org");
+    assert!(!wrong_name.is_response_to(&q));
+
+    let mut wrong_type = r.clone();
+    wrong_type.quests.as_mut().unwrap()[0].qtype = QType::AAAA;
+    assert!(!wrong_type.is_response_to(&q));
+
+    let mut no_question = r;
+    no_question.quests = None;
+    assert!(!no_question.is_response_to(&q));
+}
+
+#[test]
+fn test_echoes_case()
+{
+    let q = Message::build_query(0x1234, vec![
+        Question {
+            qname: String::from("wWw.ExAmple.com"),
+            qtype: QType::A,
+            qclass: QClass::IN,
+        },
+    ]);
+
+    let mut r = q.clone();
+    r.header.qr = QR::Response;
+    assert!(r.echoes_case(&q));
+
+    r.quests.as_mut().unwrap()[0].qname = String::from("www.example.com");
+    assert!(r.is_response_to(&q));
+    assert!(!r.echoes_case(&q));
+}
+
+#[test]
+fn test_randomize_case()
+{
+    let name = "www.example-1.com.";
+
+    // 14 letters make an unchanged spelling on every try vanishingly unlikely
+    let spellings: Vec<String> = (0..4).map(|_| randomize_case(name)).collect();
+    assert!(spellings.iter().all(|s| s.len() == name.len() && name_eq(s, name)));
+    assert!(spellings.iter().any(|s| s != name));
+}
+
+#[test]
+fn test_reverse_name()
+{
+    assert_eq!("1.2.0.192.in-addr.arpa", reverse_name("192.0.2.1".parse().unwrap()));
+    assert_eq!("b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa",
+               reverse_name("4321:0:1:2:3:4:567:89ab".parse().unwrap()));
+}
+
+#[test]
+fn test_parse_reverse_name()
+{
+    let parse = |name| parse_reverse_name(name).map(|(ip, len)| (ip.to_string(), len));
+
+    assert_eq!(Some(("192.0.2.1".into(), 32)), parse("1.2.0.192.IN-ADDR.ARPA."));
+    assert_eq!(Some(("192.0.2.0".into(), 24)), parse("2.0.192.in-addr.arpa"));
+    assert_eq!(Some(("0.0.0.0".into(), 0)), parse("in-addr.arpa"));
+    assert_eq!(Some(("4321:0:1:2:3:4:567:89ab".into(), 128)),
+               parse("b.a.9.8.7.6.5.0.4.0.0.0.3.0.0.0.2.0.0.0.1.0.0.0.0.0.0.0.1.2.3.4.ip6.arpa"));
+    assert_eq!(Some(("2001:db8::".into(), 32)), parse("8.b.d.0.1.0.0.2.ip6.arpa"));
+    assert_eq!(Some(("2001:db0::".into(), 28)), parse("b.d.0.1.0.0.2.ip6.arpa"));
+
+    for bad in ["www.example.com", "xin-addr.arpa", "256.in-addr.arpa", "01.in-addr.arpa",
+                "1.1.1.1.1.in-addr.arpa", "0/26.2.0.192.in-addr.arpa", "ab.ip6.arpa", "g.ip6.arpa"]
+    {
+        assert_eq!(None, parse(bad), "{}", bad);
+    }
+}
+
+#[test]
+fn test_orders_names_canonically()
+{
+    // The example of RFC 4034 section 6.1, in order
+    let names = ["example", "a.example", "yljkjljk.a.example", "Z.a.example", "zABC.a.EXAMPLE",
+                 "z.example", "\u{1}.z.example", "*.z.example", "\u{80}.z.example"];
+
+    for pair in names.windows(2)
+    {
+        assert_eq!(std::cmp::Ordering::Less, nsec::canonical_cmp(pair[0], pair[1]), "{:?}", pair);
+    }
+    assert_eq!(std::cmp::Ordering::Equal, nsec::canonical_cmp("Z.a.example.", "z.A.example"));
+}
+
+#[test]
+fn test_covers_wraps_around_at_last_record()
+{
+    assert!(nsec::covers("b", "d", "c"));
+    assert!(!nsec::covers("b", "d", "b"));
+    assert!(!nsec::covers("b", "d", "d"));
+    assert!(nsec::covers("x", "a", "z"));
+    assert!(!nsec::covers("x", "a", "m"));
+}
+
+#[test]
+fn test_nsec3_hash()
+{
+    // From the example zone of RFC 5155 appendix A
+    let salt = [0xaa, 0xbb, 0xcc, 0xdd];
+    let hash = |name| nsec::base32hex_encode(&nsec::nsec3_hash(name, &salt, 12).unwrap());
+
+    assert_eq!("0p9mhaveqvm6t7vbl5lop2u3t2rp3tom", hash("example"));
+    assert_eq!("35mthgpgcu1qg68fab165klnsnk3dpvl", hash("A.Example."));
+    assert_eq!("r53bq7cc2uvmubfu5ocmm6pers9tk9en", hash("*.w.example"));
+}
+
+#[test]
+fn test_base32hex_round_trip()
+{
+    for bytes in [&[][..], &[0xff], &[1, 2, 3, 4, 5], &[0xde, 0xad, 0xbe, 0xef, 0, 1, 2]]
+    {
+        let s = nsec::base32hex_encode(bytes);
+        assert_eq!(Some(bytes.to_vec()), nsec::base32hex_decode(&s));
+        assert_eq!(Some(bytes.to_vec()), nsec::base32hex_decode(&s.to_ascii_uppercase()));
+    }
+    assert_eq!(None, nsec::base32hex_decode("w0"));
+    assert_eq!(None, nsec::base32hex_decode("01"));
+}
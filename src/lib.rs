@@ -0,0 +1,6 @@
+// DNS mnemonics (AAAA, CNAME, PTR, ...) are kept as spelled in RFC 1035
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod dns;
+pub mod parser;
+pub mod resolver;
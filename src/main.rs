@@ -1,20 +1,228 @@
-use clap::{ load_yaml };
+use clap::{ load_yaml, ArgMatches };
+use std::error::Error;
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::Arc;
 
-mod dns;
-mod parser;
-mod resolver;
+use rresolve::dns::QType;
+use rresolve::resolver::{
+    iterative::{ IterativeResolver, TraceOutcome, TraceStep },
+    config::{ parse_nameserver, ResolverConfig },
+    lookup::Lookup,
+    srtt::ServerStats,
+    transport::{
+        https::{ HttpsConfig, HttpsMethod, HttpsTransport, DOH_PORT },
+        tls::{ TlsConfig, TlsTransport, DOT_PORT },
+        Transport,
+    },
+    Resolver, DNS_PORT,
+};
 
-fn main() -> Result<(), Box<dyn std::error::Error>>
+// EFFECTS: Loads resolv.conf and applies any overrides given on the command line
+fn build_config(ms: &ArgMatches) -> Result<ResolverConfig, Box<dyn Error + Send + Sync>>
+{
+    let mut config = match ms.value_of("resolv-conf")
+    {
+        Some(path) => ResolverConfig::from_file(path)?,
+        None => ResolverConfig::system(),
+    };
+
+    if let Some(path) = ms.value_of("hosts-file")
+    {
+        config.hosts_file = Some(path.into());
+    }
+
+    if let Some(addrs) = ms.values_of("nameserver")
+    {
+        config.nameservers = addrs.map(|a| parse_nameserver(a).ok_or(format!("Invalid nameserver '{}'", a)))
+                                  .collect::<Result<_, _>>()?;
+    }
+
+    if let Some(domains) = ms.values_of("search")
+    {
+        config.search = domains.map(String::from).collect();
+    }
+
+    for opt in ["ndots", "timeout", "attempts"].iter()
+    {
+        if let Some(v) = ms.value_of(opt)
+        {
+            if !config.set_option(&format!("{}:{}", opt, v))
+            {
+                return Err(format!("Invalid value '{}' for --{}", v, opt).into());
+            }
+        }
+    }
+
+    config.randomize_case |= ms.is_present("randomize-case");
+
+    if let Some(family) = ms.value_of("family")
+    {
+        config.family = family.parse()?;
+    }
+
+    for opt in ["rotate", "edns0", "tcp"].iter()
+    {
+        if ms.is_present(opt)
+        {
+            config.set_option(opt);
+        }
+    }
+
+    Ok(config)
+}
+
+// EFFECTS: Builds a resolver for the config, querying over TLS or HTTPS if
+//          asked to
+fn build_resolver(ms: &ArgMatches) -> Result<Resolver, Box<dyn Error + Send + Sync>>
+{
+    let mut config = build_config(ms)?;
+
+    let tls = TlsConfig {
+        auth_name: ms.value_of("tls-name").map(String::from),
+        roots: ms.value_of("tls-ca").map(TlsConfig::read_roots).transpose()?.unwrap_or_default(),
+        spki_pins: ms.values_of("tls-pin").into_iter().flatten().map(String::from).collect(),
+    };
+
+    let (transport, port): (Arc<dyn Transport>, u16) = if let Some(template) = ms.value_of("https")
+    {
+        let method = if ms.is_present("https-get") { HttpsMethod::Get } else { HttpsMethod::Post };
+        let https = HttpsConfig { template: template.to_string(), method, tls };
+
+        (Arc::new(HttpsTransport::new(https)?), DOH_PORT)
+    } else if ms.is_present("tls") {
+        (Arc::new(TlsTransport::new(&tls)?), DOT_PORT)
+    } else {
+        return Ok(Resolver::new(config));
+    };
+
+    for ns in config.nameservers.iter_mut().filter(|ns| ns.port() == DNS_PORT)
+    {
+        ns.set_port(port);
+    }
+
+    Ok(Resolver::with_transport(config, transport))
+}
+
+fn print_lookup(lookup: &Lookup)
+{
+    for rr in lookup.chain.iter()
+    {
+        println!("Followed alias {:?}", rr);
+    }
+
+    if lookup.answers.is_empty()
+    {
+        println!("No records found ({:?})", lookup.rcode);
+    }
+
+    for rr in lookup.answers.iter()
+    {
+        println!("Found record {:?}", rr);
+    }
+}
+
+// EFFECTS: Prints each query of a traced lookup, indenting those made to
+//          find the address of a glueless nameserver
+fn print_trace(steps: &[TraceStep])
+{
+    for step in steps
+    {
+        let indent = "  ".repeat(step.depth);
+        let zone = if step.zone.is_empty() { "." } else { &step.zone };
+        println!("{}Asked {} for {} {:?} (zone {}) in {:?}", indent, step.server, step.qname, step.qtype, zone, step.rtt);
+
+        match &step.outcome
+        {
+            TraceOutcome::Answer(rcode, answers) => {
+                println!("{}  Answer ({:?})", indent, rcode);
+                for rr in answers
+                {
+                    println!("{}    {:?}", indent, rr);
+                }
+            },
+            TraceOutcome::Referral { zone, nameservers, glue } => {
+                println!("{}  Referral to {} served by {}", indent, zone, nameservers.join(", "));
+                for rr in glue
+                {
+                    println!("{}    Glue {:?}", indent, rr);
+                }
+            },
+            TraceOutcome::Lame(rcode) => println!("{}  Lame response ({:?})", indent, rcode),
+            TraceOutcome::Failed(e) => println!("{}  No response: {}", indent, e),
+        }
+    }
+}
+
+// EFFECTS: Prints the round-trip statistics of each server queried, if
+//          asked to
+fn print_server_stats(ms: &ArgMatches, stats: &[(SocketAddr, ServerStats)])
+{
+    if !ms.is_present("server-stats")
+    {
+        return;
+    }
+
+    for (server, st) in stats
+    {
+        println!("Server {} srtt {:?} ({} responses, {} failures)", server, st.srtt, st.responses, st.failures);
+    }
+}
+
+fn main() -> Result<(), Box<dyn Error + Send + Sync>>
 {
 
     let yaml = load_yaml!("clap.yml");
     let ms = clap::App::from_yaml(yaml).get_matches();
 
+    if let Some(addr) = ms.value_of("reverse")
+    {
+        let ip: IpAddr = addr.parse().map_err(|_| format!("Invalid address '{}'", addr))?;
+        println!("Performing reverse lookup for: {}", ip);
+
+        let r = build_resolver(&ms)?;
+        let names = r.reverse_lookup(ip);
+        print_server_stats(&ms, &r.server_stats());
+
+        for name in names?
+        {
+            println!("Found hostname {}", name);
+        }
+
+        return Ok(());
+    }
+
     let hostname = ms.value_of("lookup").expect("Error unwrapping a required value");
     println!("Performing lookup for: {}", hostname);
 
-    let addr = resolver::resolve(&hostname)?;
-    println!("Found record {}", addr);
+    if ms.is_present("iterative")
+    {
+        let mut r = IterativeResolver::default();
+        r.qname_minimisation = ms.is_present("qname-minimisation");
+        let lookup = if ms.is_present("trace")
+        {
+            let trace = r.trace(hostname, QType::A);
+            print_trace(&trace.steps);
+            trace.lookup
+        } else {
+            r.lookup(hostname, QType::A)
+        };
+        print_server_stats(&ms, &r.server_stats());
+        print_lookup(&lookup?);
+    } else if ms.is_present("family") {
+        let r = build_resolver(&ms)?;
+        let ips = r.lookup_ip(hostname);
+        print_server_stats(&ms, &r.server_stats());
+
+        for ip in ips?
+        {
+            println!("Found address {}", ip);
+        }
+    } else {
+        let r = build_resolver(&ms)?;
+        let lookup = r.search(hostname, QType::A);
+        print_server_stats(&ms, &r.server_stats());
+        print_lookup(&lookup?);
+    }
 
     Ok(())
 }
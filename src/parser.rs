@@ -1,6 +1,7 @@
 
 use crate::dns::{
     QType, QClass, Type, Class,
+    edns::{ Edns, OPT_TYPE },
     header::Header, header::HeaderRow2, header::QR, header::OpCode, header::RespCode,
     message::Message,
     question::Question,
@@ -8,13 +9,14 @@ use crate::dns::{
 };
 // use nom::lib::std::ops::Fn;
 use nom::{ IResult };
-use nom::combinator::{ map_res };
+use nom::bytes::complete::{ tag, take };
+use nom::combinator::{ map, map_res };
+use nom::error::{ Error, ErrorKind };
+use nom::multi::{ count };
 use nom::number::complete::{ be_u8, be_u16, be_u32 };
 use nom::{
-    dbg_dmp,
-    bits, do_parse, fold_many0, map, many_m_n,
-    map_res, named, take_str,
-    take_until, take_bits, tuple,
+    bits, do_parse, map, map_res, named, take_str,
+    take_bits, tuple,
 };
 
 use std::convert::TryFrom;
@@ -22,6 +24,9 @@ use std::convert::TryFrom;
 #[cfg(test)]
 mod tests;
 
+const PTR_MASK: u8 = 0b1100_0000;
+const MAX_PTR_JUMPS: usize = 64;
+
 // ----- Helpers -----
 #[inline]
 fn boolify(n: u8) -> bool
@@ -34,12 +39,10 @@ fn merge_str(mut acc: String, s: &str) -> String
     if !s.is_empty()
     {
         acc.push_str(s);
-        acc.push_str(".");
-        acc
-    } else
-    {
-        acc
+        acc.push('.');
     }
+
+    acc
 }
 
 named!(parse_rname_section<&str>,
@@ -50,29 +53,47 @@ named!(parse_rname_section<&str>,
     )
 );
 
-fn parse_rname(input: &[u8]) -> IResult<&[u8], String>
+// EFFECTS: Parses a domain name, following RFC 1035 (4.1.4) compression pointers
+//          into msg. Pointers are rejected when msg is empty.
+fn parse_rname<'a>(msg: &'a [u8]) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], String>
 {
-    named!(take_rname<&[u8]>,
-           take_until!("\0")
-    );
+    move |input: &'a [u8]| {
+        let mut s = String::new();
+        let mut cur = input;
+        let mut rest = None;
+        let mut jumps = 0;
 
-    named!(parse_rname_inner<String>,
-           fold_many0!(parse_rname_section, String::new(), merge_str)
-    );
+        loop
+        {
+            let (r, len) = be_u8(cur)?;
 
-    let (r, rname_input) = take_rname(input)?;
-    let (_, mut s) = parse_rname_inner(rname_input)?;
+            if len & PTR_MASK == PTR_MASK
+            {
+                let (r, lo) = be_u8(r)?;
+                let offset = (usize::from(len & !PTR_MASK) << 8) | usize::from(lo);
 
-    // advance 'r' to remove '0' left behind by take_until
-    let (r, _) = be_u8(r)?;
+                jumps += 1;
+                if offset >= msg.len() || jumps > MAX_PTR_JUMPS
+                {
+                    return Err(nom::Err::Error(Error::new(cur, ErrorKind::Verify)));
+                }
+
+                rest.get_or_insert(r);
+                cur = &msg[offset..];
+            } else if len == 0
+            {
+                rest.get_or_insert(r);
+                break;
+            } else {
+                let (r, section) = parse_rname_section(cur)?;
+                s = merge_str(s, section);
+                cur = r;
+            }
+        }
 
-    if !s.is_empty()
-    {
         s.pop();
-        Ok((r, s))
-    } else
-    {
-        Ok((r, s))
+
+        Ok((rest.unwrap_or(cur), s))
     }
 }
 
@@ -125,16 +146,16 @@ named!(parse_header<Header>,
 );
 
 // ----- Question -----
-named!(parse_question<Question>,
-       dbg_dmp!(
-       do_parse!(
-           qname: parse_rname >>
-           qtype: map_res!(be_u16, | b: u16 | QType::try_from(b)) >>
-           qclass: map_res!(be_u16, | b: u16 | QClass::try_from(b)) >>
-           (Question { qname, qclass, qtype })
-       )
-       )
-);
+fn parse_question<'a>(msg: &'a [u8]) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], Question>
+{
+    move |input: &'a [u8]| {
+        let (rest, qname) = parse_rname(msg)(input)?;
+        let (rest, qtype) = map_res(be_u16, QType::try_from)(rest)?;
+        let (rest, qclass) = map_res(be_u16, QClass::try_from)(rest)?;
+
+        Ok((rest, Question { qname, qclass, qtype }))
+    }
+}
 
 // ----- ResourceRecord -----
 named!(parse_rdata_a<RData>,
@@ -162,78 +183,185 @@ named!(parse_rdata_aaaa<RData>,
        )
 );
 
-named!(parse_rdata_ns<RData>,
-       do_parse!(
-           rname: parse_rname >>
-           (RData::NS(rname))
-       )
-);
-
-named!(parse_rdata_cname<RData>,
-       do_parse!(
-           rname: parse_rname >>
-           (RData::CNAME(rname))
-       )
-);
+fn parse_rdata_soa<'a>(msg: &'a [u8]) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], RData>
+{
+    move |input: &'a [u8]| {
+        let (rest, mname) = parse_rname(msg)(input)?;
+        let (rest, rname) = parse_rname(msg)(rest)?;
+        let (rest, serial) = be_u32(rest)?;
+        let (rest, refresh) = be_u32(rest)?;
+        let (rest, retry) = be_u32(rest)?;
+        let (rest, expire) = be_u32(rest)?;
+        let (rest, minimum) = be_u32(rest)?;
 
-named!(parse_rdata_ptr<RData>,
-       do_parse!(
-           rname: parse_rname >>
-           (RData::PTR(rname))
-       )
-);
+        Ok((rest, RData::SOA { mname, rname, serial, refresh, retry, expire, minimum }))
+    }
+}
 
-fn parse_rdata(t: Type)
-              -> impl Fn(&[u8]) -> IResult<&[u8], RData>
+// EFFECTS: Parses an NSEC type bitmap filling all of input into the types
+//          whose bits are set (RFC 4034 section 4.1.2)
+fn parse_type_bitmap(input: &[u8]) -> IResult<&[u8], Vec<u16>>
 {
-    match t
+    let mut types = Vec::new();
+    let mut rest = input;
+
+    while !rest.is_empty()
     {
-        Type::A =>
-            parse_rdata_a,
-        Type::AAAA =>
-            parse_rdata_aaaa,
-        Type::NS =>
-            parse_rdata_ns,
-        Type::CNAME =>
-            parse_rdata_cname,
-        Type::PTR =>
-            parse_rdata_ptr,
+        let (r, window) = be_u8(rest)?;
+        let (r, len) = be_u8(r)?;
+        if len == 0 || len > 32
+        {
+            return Err(nom::Err::Error(Error::new(rest, ErrorKind::Verify)));
+        }
+        let (r, bitmap) = take(len)(r)?;
+
+        for (i, octet) in bitmap.iter().enumerate()
+        {
+            for bit in 0..8
+            {
+                if octet & (0x80 >> bit) != 0
+                {
+                    types.push(u16::from(window) << 8 | (i * 8 + bit) as u16);
+                }
+            }
+        }
+        rest = r;
+    }
+
+    Ok((rest, types))
+}
+
+fn parse_rdata_nsec<'a>(msg: &'a [u8]) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], RData>
+{
+    move |input: &'a [u8]| {
+        let (rest, next) = parse_rname(msg)(input)?;
+        let (rest, types) = parse_type_bitmap(rest)?;
+
+        Ok((rest, RData::NSEC { next, types }))
+    }
+}
+
+fn parse_rdata_nsec3(input: &[u8]) -> IResult<&[u8], RData>
+{
+    let (rest, hash_alg) = be_u8(input)?;
+    let (rest, flags) = be_u8(rest)?;
+    let (rest, iterations) = be_u16(rest)?;
+    let (rest, salt_len) = be_u8(rest)?;
+    let (rest, salt) = take(salt_len)(rest)?;
+    let (rest, hash_len) = be_u8(rest)?;
+    let (rest, next_hashed) = take(hash_len)(rest)?;
+    let (rest, types) = parse_type_bitmap(rest)?;
+
+    Ok((rest, RData::NSEC3 {
+        hash_alg,
+        flags,
+        iterations,
+        salt: salt.to_vec(),
+        next_hashed: next_hashed.to_vec(),
+        types,
+    }))
+}
+
+// EFFECTS: Parses rdata of type t. Types whose rdata ends in a list run to
+//          the end of the rd_len octets given
+fn parse_rdata<'a>(t: Type, msg: &'a [u8], rd_len: u16)
+              -> impl Fn(&'a [u8]) -> IResult<&'a [u8], RData>
+{
+    move |input: &'a [u8]| {
+        match t
+        {
+            Type::A =>
+                parse_rdata_a(input),
+            Type::AAAA =>
+                parse_rdata_aaaa(input),
+            Type::NS =>
+                map(parse_rname(msg), RData::NS)(input),
+            Type::CNAME =>
+                map(parse_rname(msg), RData::CNAME)(input),
+            Type::SOA =>
+                parse_rdata_soa(msg)(input),
+            Type::PTR =>
+                map(parse_rname(msg), RData::PTR)(input),
+            Type::NSEC => {
+                let (rest, rdata) = take(rd_len)(input)?;
+                Ok((rest, parse_rdata_nsec(msg)(rdata)?.1))
+            },
+            Type::NSEC3 => {
+                let (rest, rdata) = take(rd_len)(input)?;
+                Ok((rest, parse_rdata_nsec3(rdata)?.1))
+            },
+        }
+    }
+}
+
+fn parse_rr<'a>(msg: &'a [u8]) -> impl Fn(&'a [u8]) -> IResult<&'a [u8], ResourceRecord>
+{
+    move |input: &'a [u8]| {
+        let (rest, name) = parse_rname(msg)(input)?;
+        let (rest, rr_type) = map_res(be_u16, Type::try_from)(rest)?;
+        let (rest, rr_class) = map_res(be_u16, Class::try_from)(rest)?;
+        let (rest, ttl) = be_u32(rest)?;
+        let (rest, rd_len) = be_u16(rest)?;
+        let (rest, rdata) = parse_rdata(rr_type, msg, rd_len)(rest)?;
+
+        Ok((rest, ResourceRecord { name, rr_type, rr_class, ttl, rd_len, rdata }))
     }
 }
 
-fn parse_rr(input: &[u8]) -> IResult<&[u8], ResourceRecord>
+// ----- EDNS -----
+fn parse_opt(input: &[u8]) -> IResult<&[u8], Edns>
 {
-    let (rest, name) = parse_rname(input)?;
-    let (rest, rr_type) = map_res(be_u16, Type::try_from)(rest)?;
-    let (rest, rr_class) = map_res(be_u16, Class::try_from)(rest)?;
-    let (rest, ttl) = be_u32(rest)?;
+    let (rest, _) = tag(&[0u8])(input)?;
+    let (rest, _) = tag(&OPT_TYPE.to_be_bytes()[..])(rest)?;
+    let (rest, payload_size) = be_u16(rest)?;
+    let (rest, ext_rcode) = be_u8(rest)?;
+    let (rest, version) = be_u8(rest)?;
+    let (rest, flags) = be_u16(rest)?;
     let (rest, rd_len) = be_u16(rest)?;
-    let (rest, rdata) = parse_rdata(rr_type)(rest)?;
+    let (rest, options) = take(rd_len)(rest)?;
 
-    Ok((rest, ResourceRecord { name, rr_type, rr_class, ttl, rd_len, rdata }))
+    Ok((rest, Edns::from_flags(payload_size, ext_rcode, version, flags, options)))
 }
 
 // ----- Message -----
-named!(pub parse_msg<Message>,
-       do_parse!(
-           header: parse_header >>
-           quests: many_m_n!(header.qd_count.into(),
-                             header.qd_count.into(),
-                             parse_question) >>
-           answs: many_m_n!(header.an_count.into(),
-                            header.an_count.into(),
-                            parse_rr) >>
-           auths: many_m_n!(header.ns_count.into(),
-                            header.ns_count.into(),
-                            parse_rr) >>
-           adds: many_m_n!(header.ar_count.into(),
-                           header.ar_count.into(),
-                           parse_rr) >>
-          (Message { header,
-                     quests: if quests.is_empty() { None } else { Some(quests) },
-                     answs:  if answs.is_empty() { None } else { Some(answs) },
-                     auths:  if auths.is_empty() { None } else { Some(auths) },
-                     adds:   if adds.is_empty() { None } else { Some(adds) },
-          })
-       )
-);
+#[inline]
+fn nonempty<T>(v: Vec<T>) -> Option<Vec<T>>
+{
+    if v.is_empty() { None } else { Some(v) }
+}
+
+pub fn parse_msg(input: &[u8]) -> IResult<&[u8], Message>
+{
+    let (rest, header) = parse_header(input)?;
+    let (rest, quests) = count(parse_question(input), header.qd_count.into())(rest)?;
+    let (rest, answs) = count(parse_rr(input), header.an_count.into())(rest)?;
+    let (rest, auths) = count(parse_rr(input), header.ns_count.into())(rest)?;
+
+    let mut rest = rest;
+    let mut adds = Vec::new();
+    let mut edns = None;
+
+    for _ in 0..header.ar_count
+    {
+        match parse_opt(rest)
+        {
+            Ok((r, opt)) if edns.is_none() => {
+                edns = Some(opt);
+                rest = r;
+            },
+            _ => {
+                let (r, rr) = parse_rr(input)(rest)?;
+                adds.push(rr);
+                rest = r;
+            },
+        }
+    }
+
+    Ok((rest, Message { header,
+                        quests: nonempty(quests),
+                        answs:  nonempty(answs),
+                        auths:  nonempty(auths),
+                        adds:   nonempty(adds),
+                        edns,
+    }))
+}
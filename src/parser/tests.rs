@@ -26,10 +26,9 @@ fn test_parse_rname_sections()
 #[test]
 fn test_parse_rname_mt()
 {
-    let mut v = Vec::new();
-    v.push(0);
+    let v = vec![0];
 
-    let (_, v_parsed) = parse_rname(v.as_slice()).unwrap();
+    let (_, v_parsed) = parse_rname(&v)(&v).unwrap();
     assert_eq!(String::from(""), v_parsed);
 }
 
@@ -47,11 +46,39 @@ fn test_parse_rname()
     v.extend("jp".as_bytes());
     v.push(0);
 
-    let (_, v_parsed) = parse_rname(v.as_slice()).unwrap();
+    let (_, v_parsed) = parse_rname(&v)(&v).unwrap();
     assert_eq!(String::from("big.badwolf.co.jp"), v_parsed);
 }
 
 
+#[test]
+fn test_parse_rname_compressed()
+{
+    let mut v = Vec::new();
+    v.push(7);
+    v.extend("example".as_bytes());
+    v.push(3);
+    v.extend("com".as_bytes());
+    v.push(0);
+    // www -> pointer to offset 0
+    v.push(3);
+    v.extend("www".as_bytes());
+    v.extend(&[0xC0, 0x00]);
+    v.push(0xFF);
+
+    let (r, v_parsed) = parse_rname(&v)(&v[13..]).unwrap();
+    assert_eq!(String::from("www.example.com"), v_parsed);
+    assert_eq!(&[0xFF], r);
+}
+
+#[test]
+fn test_parse_rname_pointer_loop()
+{
+    let v = vec![0xC0, 0x00];
+
+    assert!(parse_rname(&v)(&v).is_err());
+}
+
 #[test]
 fn test_pares_r2_bytes_lowvals()
 {
@@ -137,7 +164,8 @@ fn test_parse_question_basic()
         qclass: QClass::IN,
     };
 
-    let (_, parsed_q) = parse_question(&q.to_bytes()).unwrap();
+    let b = q.to_bytes();
+    let (_, parsed_q) = parse_question(&b)(&b).unwrap();
 
     assert_eq!(q, parsed_q);
 }
@@ -154,11 +182,31 @@ fn test_parse_rr_basic()
         rdata: RData::NS(String::from("turnips.dns.com")),
     };
 
-    let (_, parsed_rr) = parse_rr(&rr.to_bytes()).unwrap();
+    let b = rr.to_bytes();
+    let (_, parsed_rr) = parse_rr(&b)(&b).unwrap();
 
     assert_eq!(rr, parsed_rr);
 }
 
+#[test]
+fn test_parse_rr_soa()
+{
+    let rr = ResourceRecord::new("example.com", 3600, RData::SOA {
+        mname: String::from("ns1.example.com"),
+        rname: String::from("hostmaster.example.com"),
+        serial: 2024010101,
+        refresh: 7200,
+        retry: 900,
+        expire: 1209600,
+        minimum: 60,
+    });
+
+    let b = rr.to_bytes();
+    let (rest, parsed_rr) = parse_rr(&b)(&b).unwrap();
+
+    assert_eq!(rr, parsed_rr);
+    assert!(rest.is_empty());
+}
 
 #[test]
 fn test_parse_msg_only_qs()
@@ -212,6 +260,7 @@ fn test_parse_msg_only_qs()
         answs: None,
         auths: None,
         adds: None,
+        edns: None,
     };
 
     let m_bytes = &m.to_bytes();
@@ -255,6 +304,7 @@ fn test_parse_msg_rr_a()
         answs: Some(rrs),
         auths: None,
         adds: None,
+        edns: None,
     };
 
     let m_bytes = &m.to_bytes();
@@ -306,6 +356,7 @@ fn test_parse_msg_rr_aaaas()
         answs: Some(rrs),
         auths: None,
         adds: None,
+        edns: None,
     };
 
     let m_bytes = &m.to_bytes();
@@ -349,6 +400,7 @@ fn test_parse_msg_rr_ns()
         answs: None,
         auths: Some(rrs),
         adds: None,
+        edns: None,
     };
 
     let m_bytes = &m.to_bytes();
@@ -392,6 +444,7 @@ fn test_parse_msg_rr_cname()
         answs: None,
         auths: None,
         adds: Some(rrs),
+        edns: None,
     };
 
     let m_bytes = &m.to_bytes();
@@ -495,6 +548,7 @@ fn test_parse_msg_q_and_rr_many()
         answs: Some(rrs),
         auths: None,
         adds: None,
+        edns: None,
     };
 
     let m_bytes = &m.to_bytes();
@@ -502,3 +556,75 @@ fn test_parse_msg_q_and_rr_many()
 
     assert_eq!(m, parsed_m);
 }
+
+#[test]
+fn test_parse_msg_with_opt()
+{
+    let mut m = Message::build_query(0xBEAD, vec![
+        Question {
+            qname: String::from("www.example.com"),
+            qtype: QType::A,
+            qclass: QClass::IN,
+        },
+    ]);
+    let mut edns = Edns::new(1232);
+    edns.dnssec_ok = true;
+    edns.options = vec![0, 10, 0, 2, 0xAB, 0xCD];
+    m.set_edns(edns);
+
+    let m_bytes = &m.to_bytes();
+    let (r, parsed_m) = parse_msg(m_bytes).unwrap();
+
+    assert!(r.is_empty());
+    assert_eq!(1, parsed_m.header.ar_count);
+    assert_eq!(None, parsed_m.adds);
+    assert_eq!(m, parsed_m);
+    assert_eq!(1232, parsed_m.udp_payload_size());
+}
+
+#[test]
+fn test_parse_msg_rejects_second_opt()
+{
+    let mut m = Message::build_query(0xBEAD, vec![]);
+    m.set_edns(Edns::new(1232));
+    m.header.ar_count = 2;
+
+    let mut m_bytes = m.to_bytes();
+    m_bytes.extend(Edns::new(4096).to_bytes());
+
+    assert!(parse_msg(&m_bytes).is_err());
+}
+
+#[test]
+fn test_parse_nsec_and_nsec3_records()
+{
+    let nsec = ResourceRecord::new("alfa.example.com", 3600, RData::NSEC {
+        next: "host.example.com".into(),
+        types: vec![1, 15, 46, 47, 1234],
+    });
+    let nsec3 = ResourceRecord::new("0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example", 3600, RData::NSEC3 {
+        hash_alg: 1,
+        flags: 1,
+        iterations: 12,
+        salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+        next_hashed: vec![0x05, 0x13, 0x14, 0x8b, 0x4b, 0x3a, 0x58, 0x5b, 0x7b, 0x2a,
+                          0xb2, 0xde, 0x03, 0x09, 0x93, 0xf5, 0x8d, 0x61, 0xe2, 0x10],
+        types: vec![2, 6, 15, 46, 48, 51],
+    });
+
+    // The type bitmap of RFC 4034 section 4.3
+    let bytes = nsec.to_bytes();
+    let bitmap = [&[0x00, 0x06, 0x40, 0x01, 0x00, 0x00, 0x00, 0x03, 0x04, 0x1b][..], &[0; 26], &[0x20]].concat();
+    assert!(bytes.ends_with(&bitmap));
+
+    let mut m = Message::build_query(0xBEAD, vec![]);
+    m.header.qr = QR::Response;
+    m.header.ns_count = 2;
+    m.auths = Some(vec![nsec, nsec3]);
+
+    let m_bytes = m.to_bytes();
+    let (r, parsed_m) = parse_msg(&m_bytes).unwrap();
+
+    assert!(r.is_empty());
+    assert_eq!(m.auths, parsed_m.auths);
+}
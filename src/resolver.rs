@@ -1,47 +1,839 @@
-use rand;
+use rand::Rng;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::error::Error;
-use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket };
+use std::io::{ self, Read, Write };
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
 
-use crate::dns::{ self, message::Message, question::Question };
+use crate::dns::{
+    self, QType, QClass, Type,
+    edns::Edns,
+    header::RespCode,
+    message::Message,
+    question::Question,
+    resourcerecord::{ RData, ResourceRecord },
+};
 use crate::parser::parse_msg;
 
-const DNS_SRVR1: IpAddr = IpAddr::V4(Ipv4Addr::new(192,168,1,253));
-// const DNS_SRVR1: IpAddr = IpAddr::V4(Ipv4Addr::new(84,200,69,80));
-// const DNS_SRVR2: IpAddr = IpAddr::V4(Ipv4Addr::new(84,200,70,40));
-const DNS_PORT: u16 = 53;
-const RESP_BUFF_SIZE: usize = 128;
+use cache::{ Cache, Cached };
+use config::{ FamilyPreference, ResolverConfig, MAX_ATTEMPTS };
+use hosts::Hosts;
+use lookup::Lookup;
+use srtt::{ ServerStats, Srtt };
+use transport::{ CaseMismatch, TcpTransport, Transport, UdpTransport };
 
-pub fn resolve(hostname: &str) -> Result<IpAddr, Box<dyn Error>>
+pub mod asyncresolver;
+pub mod cache;
+pub mod config;
+pub mod hosts;
+pub mod iterative;
+pub mod limits;
+pub mod lookup;
+pub mod roothints;
+pub mod srtt;
+pub mod transport;
+
+#[cfg(test)]
+mod standin;
+#[cfg(test)]
+mod tests;
+
+pub const DNS_PORT: u16 = 53;
+const MIN_SRC_PORT: u16 = 1024;
+const PORT_TRIES: usize = 16;
+// How long a server found not to preserve case is sent names as given,
+// before it is tried in random case again
+const CASE_BLIND_FOR: Duration = Duration::from_secs(30 * 60);
+// Payload size advertised with EDNS, chosen to avoid IP fragmentation
+// (https://www.dnsflagday.net/2020/)
+pub const EDNS_PAYLOAD_SIZE: u16 = 1232;
+
+// EFFECTS: Looks up hostname using the upstream servers from the system resolv.conf
+pub fn resolve(hostname: &str) -> Result<IpAddr, Box<dyn Error + Send + Sync>>
 {
-    let qs = vec![
-        Question {
-            qname: hostname.to_string(),
-            qtype: dns::QType::A,
-            qclass: dns::QClass::IN,
-        },
-        Question {
-            qname: hostname.to_string(),
-            qtype: dns::QType::AAAA,
-            qclass: dns::QClass::IN,
+    Resolver::new(ResolverConfig::system()).resolve(hostname)
+}
+
+// ------------- Stub Resolver -------------
+// Answers from the hosts file or its cache when it can, otherwise sends
+// queries to the upstream recursive nameservers of its config over its
+// transport, fastest first by srtt unless config.rotate is set. Clones
+// share one cache, transport and srtt
+#[derive(Clone, Debug)]
+pub struct Resolver
+{
+    pub config: ResolverConfig,
+    pub hosts: Hosts,
+    pub cache: Arc<Cache>,
+    pub transport: Arc<dyn Transport>,
+    pub srtt: Arc<Srtt>,
+    next_server: Arc<AtomicUsize>,
+    rejected: Arc<AtomicU64>,
+    // Whether transport is the UDP or TCP one new chose from the config,
+    // which AsyncResolver swaps for sockets of its own
+    builtin_transport: bool,
+    // Servers found not to echo query names in the case they were sent
+    // and when each was found so
+    case_blind: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+}
+
+impl Resolver
+{
+    // EFFECTS: Creates a resolver, loading the configured hosts file. Like the
+    //          system resolver, an unreadable hosts file is treated as empty.
+    //          Queries go over UDP, or TCP only if config.tcp is set
+    pub fn new(config: ResolverConfig) -> Self
+    {
+        let rejected = Arc::new(AtomicU64::new(0));
+        let transport: Arc<dyn Transport> = if config.tcp
+        {
+            Arc::new(TcpTransport)
+        } else {
+            Arc::new(UdpTransport { rejected: rejected.clone() })
+        };
+
+        Resolver { rejected, builtin_transport: true, ..Resolver::with_transport(config, transport) }
+    }
+
+    // EFFECTS: Creates a resolver as new does, sending its queries over
+    //          transport instead
+    pub fn with_transport(config: ResolverConfig, transport: Arc<dyn Transport>) -> Self
+    {
+        let hosts = config.hosts_file
+                          .as_ref()
+                          .and_then(|path| Hosts::from_file(path).ok())
+                          .unwrap_or_default();
+
+        let cache = Arc::new(Cache::new(config.cache.clone()));
+
+        Resolver {
+            config,
+            hosts,
+            cache,
+            transport,
+            srtt: Arc::new(Srtt::default()),
+            next_server: Arc::new(AtomicUsize::new(0)),
+            rejected: Arc::new(AtomicU64::new(0)),
+            builtin_transport: false,
+            case_blind: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // EFFECTS: Returns how many datagrams the transport of new (or the
+    //          sockets of an AsyncResolver on this one) discarded for not
+    //          matching an outstanding query
+    pub fn rejected_responses(&self) -> u64
+    {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    // EFFECTS: Returns the round-trip statistics of each nameserver queried,
+    //          fastest first
+    pub fn server_stats(&self) -> Vec<(SocketAddr, ServerStats)>
+    {
+        self.srtt.stats()
+    }
+
+    // EFFECTS: Returns the nameservers found not to preserve the case of
+    //          query names, which config.randomize_case doesn't apply to
+    //          for now
+    pub fn case_blind_servers(&self) -> Vec<SocketAddr>
+    {
+        self.case_blind
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, since)| since.elapsed() < CASE_BLIND_FOR)
+            .map(|(server, _)| *server)
+            .collect()
+    }
+
+    // EFFECTS: Returns the first address lookup_ip finds for hostname
+    pub fn resolve(&self, hostname: &str) -> Result<IpAddr, Box<dyn Error + Send + Sync>>
+    {
+        Ok(self.lookup_ip(hostname)?[0])
+    }
+
+    // EFFECTS: Returns the addresses of hostname in the families and order
+    //          config.family asks for, trying the names the search list
+    //          makes of it until one has addresses. A and AAAA records are
+    //          looked up with separate queries, sent concurrently when both
+    //          are wanted
+    pub fn lookup_ip(&self, hostname: &str) -> Result<Vec<IpAddr>, Box<dyn Error + Send + Sync>>
+    {
+        if let Some(ips) = self.ips_from_hosts(hostname)
+        {
+            return Ok(ips);
+        }
+
+        for name in self.config.search_names(hostname)
+        {
+            let (v4, v6) = match self.config.family
+            {
+                FamilyPreference::Ipv4Only => (Some(self.lookup(&name, QType::A)), None),
+                FamilyPreference::Ipv6Only => (None, Some(self.lookup(&name, QType::AAAA))),
+                _ => thread::scope(|s| {
+                    let v6 = s.spawn(|| self.lookup(&name, QType::AAAA));
+                    let v4 = self.lookup(&name, QType::A);
+
+                    (Some(v4), Some(v6.join().unwrap_or_else(|e| std::panic::resume_unwind(e))))
+                }),
+            };
+
+            if let Some(ips) = next_search_name(hostname, self.config.family, v4, v6)?
+            {
+                return Ok(ips);
+            }
+        }
+
+        Err(format!("No address found for {}", hostname).into())
+    }
+
+    // EFFECTS: Looks up qname/qtype under each name the search list makes
+    //          of it, returning the first lookup with answers. Like glibc,
+    //          failing that it returns the first that found the name without
+    //          records of qtype, or else the last. A lookup that gets no
+    //          response ends the search
+    pub fn search(&self, qname: &str, qtype: QType) -> Result<Lookup, Box<dyn Error + Send + Sync>>
+    {
+        let mut outcome: Option<Lookup> = None;
+
+        for name in self.config.search_names(qname)
+        {
+            let lookup = self.lookup(&name, qtype)?;
+            if !lookup.answers.is_empty()
+            {
+                return Ok(lookup);
+            }
+
+            match &outcome
+            {
+                Some(o) if o.rcode == RespCode::Ok => {},
+                _ => outcome = Some(lookup),
+            }
+        }
+
+        Ok(outcome.expect("The search list always holds the name itself"))
+    }
+
+    // EFFECTS: Returns the hostnames the PTR records under the reverse name
+    //          of ip point to, failing if there are none
+    pub fn reverse_lookup(&self, ip: IpAddr) -> Result<Vec<String>, Box<dyn Error + Send + Sync>>
+    {
+        hostnames(ip, self.lookup(&dns::reverse_name(ip), QType::PTR)?)
+    }
+
+    // EFFECTS: Looks up qname/qtype in the hosts file, then the cache, then
+    //          upstream, querying again for each alias left unresolved. If
+    //          no nameserver responds, answers from stale cache entries
+    //          where the cache config allows. Cache entries found due for a
+    //          prefetch are refreshed in the background
+    pub fn lookup(&self, qname: &str, qtype: QType) -> Result<Lookup, Box<dyn Error + Send + Sync>>
+    {
+        let mut lookup = Lookup::new(qname, qtype);
+        if self.answer_from_hosts(&mut lookup)
+        {
+            return Ok(lookup);
+        }
+
+        while !self.answer_from_cache(&mut lookup)?
+        {
+            let resp = match self.query(lookup.canonical_name(), qtype)
+            {
+                Ok(resp) => resp,
+                Err(e) => return self.answer_from_stale(lookup, e),
+            };
+
+            if self.absorb(&mut lookup, &resp)?
+            {
+                break;
+            }
+        }
+        self.prefetch();
+
+        Ok(lookup)
+    }
+
+    // EFFECTS: Refreshes each cache entry found due for it, on a thread of
+    //          its own, so that its lookups keep being answered from the
+    //          cache rather than waiting on a nameserver once it expires
+    fn prefetch(&self)
+    {
+        for (name, rr_type) in self.cache.take_prefetches()
+        {
+            let r = self.clone();
+            thread::spawn(move || r.refresh(&name, rr_type.into()));
+        }
+    }
+
+    // EFFECTS: Queries qname/qtype upstream and caches what comes back as a
+    //          lookup would. A failed refresh leaves the entry to expire
+    fn refresh(&self, qname: &str, qtype: QType)
+    {
+        if let Ok(resp) = self.query(qname, qtype)
+        {
+            let _ = self.absorb(&mut Lookup::new(qname, qtype), &resp);
+        }
+    }
+
+    // EFFECTS: Returns the addresses the hosts file lists for hostname in
+    //          the families config.family asks for, if there are any. Like
+    //          glibc, the nameservers aren't asked about a name found there
+    fn ips_from_hosts(&self, hostname: &str) -> Option<Vec<IpAddr>>
+    {
+        let (v4, v6) = self.hosts
+                           .lookup(hostname)
+                           .unwrap_or_default()
+                           .iter()
+                           .partition(|ip| ip.is_ipv4());
+        let ips = order_ips(self.config.family, v4, v6);
+
+        if ips.is_empty() { None } else { Some(ips) }
+    }
+
+    // EFFECTS: Takes the addresses the hosts file lists for the name of
+    //          lookup, if any match its type, or for a PTR lookup of the
+    //          reverse name of an address the names listed for it. Returns
+    //          true if there were some
+    fn answer_from_hosts(&self, lookup: &mut Lookup) -> bool
+    {
+        let qtype = lookup.qtype;
+        let mut from_hosts: Vec<ResourceRecord> = self.hosts.lookup(&lookup.qname)
+                                                            .unwrap_or_default()
+                                                            .iter()
+                                                            .map(|ip| ResourceRecord::new(&lookup.qname, 0, (*ip).into()))
+                                                            .filter(|rr| qtype.matches(rr.rr_type))
+                                                            .collect();
+
+        // Only a full address has names; a shorter prefix is a network
+        let full = match dns::parse_reverse_name(&lookup.qname)
+        {
+            Some((ip @ IpAddr::V4(_), 32)) | Some((ip @ IpAddr::V6(_), 128)) => Some(ip),
+            _ => None,
+        };
+
+        if let (true, Some(ip)) = (qtype.matches(Type::PTR), full)
+        {
+            from_hosts.extend(self.hosts
+                                  .reverse(ip)
+                                  .unwrap_or_default()
+                                  .iter()
+                                  .map(|name| ResourceRecord::new(&lookup.qname, 0, RData::PTR(name.clone()))));
+        }
+
+        lookup.answers = from_hosts;
+
+        !lookup.answers.is_empty()
+    }
+
+    // EFFECTS: Follows cached aliases from the canonical name of lookup and
+    //          takes the cached answer at the end of them. Returns false if
+    //          the chain leaves the cache before reaching an answer
+    fn answer_from_cache(&self, lookup: &mut Lookup) -> Result<bool, Box<dyn Error + Send + Sync>>
+    {
+        self.follow_cache(lookup, false)
+    }
+
+    // EFFECTS: Finishes lookup from the cache, taking expired entries still
+    //          within the stale window, and tags it stale. Fails with err,
+    //          the reason the nameservers couldn't answer, if the cache
+    //          holds nothing for the rest of the chain
+    fn answer_from_stale(&self, mut lookup: Lookup, err: Box<dyn Error + Send + Sync>)
+                         -> Result<Lookup, Box<dyn Error + Send + Sync>>
+    {
+        if self.follow_cache(&mut lookup, true)?
+        {
+            lookup.stale = true;
+            return Ok(lookup);
+        }
+
+        Err(err)
+    }
+
+    // EFFECTS: Follows cached aliases as answer_from_cache does, taking
+    //          stale entries too if stale is set
+    fn follow_cache(&self, lookup: &mut Lookup, stale: bool) -> Result<bool, Box<dyn Error + Send + Sync>>
+    {
+        loop
+        {
+            let cached = if stale
+            {
+                self.cache.get_stale(lookup.canonical_name(), lookup.qtype.into())
+            } else {
+                self.cache.get(lookup.canonical_name(), lookup.qtype.into())
+            };
+
+            match cached
+            {
+                Some(Cached::Records(rrs)) if rrs[0].rr_type == Type::CNAME && lookup.qtype != QType::CNAME => {
+                    lookup.push_alias(&rrs[0], self.config.max_cname_depth)?;
+                },
+                Some(Cached::Records(rrs)) => {
+                    lookup.answers = rrs;
+                    return Ok(true);
+                },
+                Some(Cached::NxDomain(soa)) => {
+                    lookup.rcode = RespCode::NameError;
+                    lookup.soa = Some(soa);
+                    return Ok(true);
+                },
+                Some(Cached::NoData(soa)) => {
+                    lookup.soa = Some(soa);
+                    return Ok(true);
+                },
+                None => return Ok(false),
+            }
+        }
+    }
+
+    // EFFECTS: Caches the answers in resp, then folds them into lookup. Once
+    //          the lookup is finished without answers, caches it as negative.
+    //          Returns true if the lookup is finished
+    fn absorb(&self, lookup: &mut Lookup, resp: &Message) -> Result<bool, Box<dyn Error + Send + Sync>>
+    {
+        self.cache.insert(resp.answs.as_deref().unwrap_or_default());
+
+        if !lookup.absorb(resp, self.config.max_cname_depth)?
+        {
+            return Ok(false);
+        }
+
+        if let (true, Some(soa)) = (lookup.answers.is_empty(), &lookup.soa)
+        {
+            self.cache.insert_negative(lookup.canonical_name(), lookup.qtype.into(), lookup.rcode, soa);
+            // Nothing validates the proofs, so they never answer for other names
+            self.cache.insert_denial(soa, resp.auths.as_deref().unwrap_or_default(), false);
+        }
+
+        Ok(true)
+    }
+
+    // EFFECTS: Builds a recursive query for qname/qtype with a random ID,
+    //          advertising EDNS if configured
+    fn build_query(&self, qname: &str, qtype: QType) -> Message
+    {
+        let qs = vec![
+            Question {
+                qname: qname.to_string(),
+                qtype,
+                qclass: QClass::IN,
+            },
+        ];
+
+        let id: u16 = rand::random();
+        let mut m = Message::build_query(id, qs);
+        m.header.rec_desired = true;
+        if self.config.edns0
+        {
+            m.set_edns(Edns::new(EDNS_PAYLOAD_SIZE));
+        }
+
+        m
+    }
+
+    // EFFECTS: Returns the nameservers in the order a query should try them.
+    //          With rotate set, each query starts at the server after the one
+    //          the previous query started at. Otherwise srtt orders them
+    fn server_order(&self) -> Vec<SocketAddr>
+    {
+        let servers = &self.config.nameservers;
+        if !self.config.rotate
+        {
+            return self.srtt.order(servers);
+        }
+
+        let start = self.next_server.fetch_add(1, Ordering::Relaxed) % servers.len();
+
+        servers[start..].iter().chain(&servers[..start]).cloned().collect()
+    }
+
+    // EFFECTS: Returns how long to wait on each server in the given round.
+    //          As in glibc, the first round waits config.timeout and later
+    //          rounds double it, split across the servers. The doubling
+    //          stops at the last round resolv.conf could ask for, however
+    //          many rounds config.attempts sets
+    fn attempt_timeout(&self, attempt: u8) -> Duration
+    {
+        let doublings = attempt.min(MAX_ATTEMPTS - 1);
+        let mut timeout = self.config.timeout.saturating_mul(1 << doublings);
+        if attempt > 0
+        {
+            timeout /= self.config.nameservers.len() as u32;
+        }
+
+        timeout
+    }
+
+    // EFFECTS: Sends the query to each nameserver in turn, in server_order,
+    //          for up to config.attempts rounds. Each response updates the
+    //          smoothed RTT of its server, and each failure counts as a
+    //          timeout against it
+    fn query(&self, qname: &str, qtype: QType) -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        if self.config.nameservers.is_empty()
+        {
+            return Err("No nameservers configured".into());
+        }
+
+        let m = self.build_query(qname, qtype);
+        let servers = self.server_order();
+        let mut last_err: Box<dyn Error + Send + Sync> = "No attempts configured".into();
+
+        for attempt in 0..self.config.attempts
+        {
+            let timeout = self.attempt_timeout(attempt);
+
+            for &server in servers.iter()
+            {
+                let sent = Instant::now();
+                match self.ask(&m, server, timeout).and_then(|resp| answered(resp, server))
+                {
+                    Ok(resp) => {
+                        self.srtt.record_response(server, sent.elapsed());
+                        return Ok(resp);
+                    },
+                    Err(e) => {
+                        self.srtt.record_failure(server, timeout);
+                        last_err = e;
+                    },
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    // EFFECTS: Returns true if queries to server should have their names in
+    //          random case
+    fn randomizes_case(&self, server: SocketAddr) -> bool
+    {
+        let blind = self.case_blind
+                        .lock()
+                        .unwrap()
+                        .get(&server)
+                        .is_some_and(|since| since.elapsed() < CASE_BLIND_FOR);
+
+        self.config.randomize_case && !blind
+    }
+
+    // EFFECTS: Takes resp, a response from server to sent that can't have
+    //          been forged, as proof of whether server preserves case.
+    //          One that doesn't is sent names as given for CASE_BLIND_FOR
+    fn note_case(&self, server: SocketAddr, sent: &Message, resp: &Message)
+    {
+        if !resp.echoes_case(sent)
+        {
+            self.case_blind.lock().unwrap().insert(server, Instant::now());
+        }
+    }
+
+    // EFFECTS: Sends query to server over the transport. When randomizing
+    //          case, only a response in the case sent will do. If none
+    //          comes, the query is sent again over TCP, whose response is
+    //          taken whatever its case; in the wrong case, it shows that
+    //          server doesn't preserve case
+    fn ask(&self, query: &Message, server: SocketAddr, timeout: Duration)
+           -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        if !self.randomizes_case(server)
+        {
+            return self.transport.exchange(query, server, timeout);
+        }
+
+        let sent = with_random_case(query);
+        let resp = match self.transport.exchange_exact(&sent, server, timeout)
+        {
+            Ok(resp) => resp,
+            Err(e) => {
+                let mismatch = e.downcast::<CaseMismatch>()?;
+                let resp = match mismatch.response
+                {
+                    Some(resp) => resp,
+                    None => exchange_tcp(&sent, server, timeout)?,
+                };
+                self.note_case(server, &sent, &resp);
+                resp
+            },
+        };
+
+        Ok(restore_case(resp, query))
+    }
+}
+
+// EFFECTS: Returns resp from server if it answers the query, with records,
+//          none or NXDOMAIN. SERVFAIL, REFUSED and the like are made errors
+//          instead, so that the next server is tried as glibc does
+fn answered(resp: Message, server: SocketAddr) -> Result<Message, Box<dyn Error + Send + Sync>>
+{
+    match resp.header.rcode
+    {
+        RespCode::Ok | RespCode::NameError => Ok(resp),
+        rcode => Err(format!("{} answered with {:?}", server, rcode).into()),
+    }
+}
+
+// EFFECTS: Returns resp, a response to query from server, if it spells the
+//          names of the question as query does, or else a CaseMismatch
+//          carrying it
+fn check_case(resp: Message, query: &Message, server: SocketAddr) -> Result<Message, Box<dyn Error + Send + Sync>>
+{
+    if resp.echoes_case(query)
+    {
+        Ok(resp)
+    } else {
+        Err(CaseMismatch { server, response: Some(resp) }.into())
+    }
+}
+
+// EFFECTS: Returns a copy of query with a fresh ID and its names in random
+//          case
+fn with_random_case(query: &Message) -> Message
+{
+    let mut sent = query.clone();
+    sent.header.id = rand::random();
+    for q in sent.quests.iter_mut().flatten()
+    {
+        q.qname = dns::randomize_case(&q.qname);
+    }
+
+    sent
+}
+
+// EFFECTS: Gives resp, a response to query sent in random case, the ID and
+//          question of query, and respells records owned by a name of the
+//          question as query did
+fn restore_case(mut resp: Message, query: &Message) -> Message
+{
+    let names: Vec<&str> = query.quests.iter().flatten().map(|q| q.qname.as_str()).collect();
+    let records = resp.answs.iter_mut().chain(resp.auths.iter_mut()).chain(resp.adds.iter_mut()).flatten();
+
+    for rr in records
+    {
+        if let Some(name) = names.iter().find(|n| dns::name_eq(n, &rr.name))
+        {
+            rr.name = name.to_string();
+        }
+    }
+
+    resp.header.id = query.header.id;
+    resp.quests = query.quests.clone();
+
+    resp
+}
+
+// EFFECTS: Merges the outcomes of the A and AAAA lookups (None if not made)
+//          for hostname as family asks. A failed lookup only fails the merge
+//          when no addresses were found at all
+fn merge_ips(hostname: &str, family: FamilyPreference,
+             v4: Option<Result<Lookup, Box<dyn Error + Send + Sync>>>,
+             v6: Option<Result<Lookup, Box<dyn Error + Send + Sync>>>)
+             -> Result<Vec<IpAddr>, Box<dyn Error + Send + Sync>>
+{
+    let mut failure = None;
+    let mut ips = |outcome| match outcome
+    {
+        Some(Ok(lookup)) => Lookup::ips(&lookup),
+        Some(Err(e)) => {
+            failure.get_or_insert(e);
+            Vec::new()
         },
-    ];
+        None => Vec::new(),
+    };
+    let merged = order_ips(family, ips(v4), ips(v6));
 
+    if merged.is_empty()
+    {
+        return Err(failure.unwrap_or_else(|| format!("No address found for {}", hostname).into()));
+    }
 
-    let id: u16 = rand::random();
-    let m = Message::build_query(id, qs);
-    let m = m.to_bytes();
+    Ok(merged)
+}
 
-    let sock = UdpSocket::bind(":::9001")?;
-    sock.send_to(m.as_slice(), (DNS_SRVR1, DNS_PORT))?;
+// EFFECTS: Merges the outcomes of the lookups for one name the search list
+//          made of hostname as merge_ips does. Returns None if they found no
+//          addresses but did get answers, so the next name should be tried
+fn next_search_name(hostname: &str, family: FamilyPreference,
+                    v4: Option<Result<Lookup, Box<dyn Error + Send + Sync>>>,
+                    v6: Option<Result<Lookup, Box<dyn Error + Send + Sync>>>)
+                    -> Result<Option<Vec<IpAddr>>, Box<dyn Error + Send + Sync>>
+{
+    let answered = [&v4, &v6].iter().all(|o| !matches!(o, Some(Err(_))));
 
-    let mut dns_resp = [0; RESP_BUFF_SIZE];
+    match merge_ips(hostname, family, v4, v6)
+    {
+        Ok(ips) => Ok(Some(ips)),
+        Err(_) if answered => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// EFFECTS: Orders the IPv4 and IPv6 addresses of a host as family asks
+fn order_ips(family: FamilyPreference, v4: Vec<IpAddr>, v6: Vec<IpAddr>) -> Vec<IpAddr>
+{
+    match family
+    {
+        FamilyPreference::Ipv4Only => v4,
+        FamilyPreference::Ipv6Only => v6,
+        FamilyPreference::PreferIpv4 if !v4.is_empty() => v4,
+        FamilyPreference::PreferIpv6 if !v6.is_empty() => v6,
+        _ => [v4, v6].concat(),
+    }
+}
+
+// EFFECTS: Returns the hostnames in a PTR lookup for ip, failing if there
+//          are none
+fn hostnames(ip: IpAddr, lookup: Lookup) -> Result<Vec<String>, Box<dyn Error + Send + Sync>>
+{
+    let names = lookup.hostnames();
+    if names.is_empty()
+    {
+        return Err(format!("No hostname found for {} ({:?})", ip, lookup.rcode).into());
+    }
+
+    Ok(names)
+}
+
+// EFFECTS: Binds a UDP socket for talking to server on a randomly chosen
+//          unprivileged port, leaving the choice to the OS if none is free
+fn bind_random_port(server: SocketAddr) -> io::Result<UdpSocket>
+{
+    let ip: IpAddr = match server
+    {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let mut rng = rand::thread_rng();
+
+    for _ in 0..PORT_TRIES
+    {
+        if let Ok(sock) = UdpSocket::bind((ip, rng.gen_range(MIN_SRC_PORT..=u16::MAX)))
+        {
+            return Ok(sock);
+        }
+    }
+
+    UdpSocket::bind((ip, 0))
+}
+
+// EFFECTS: Sends query to server over UDP from a random port and waits
+//          up to timeout for its response, into a buffer as large as the
+//          EDNS payload size of query. Datagrams from any other address,
+//          that don't parse, or that don't match the ID and question of
+//          query are counted in rejected and otherwise ignored. If exact,
+//          so are those spelling the question in another case; should only
+//          such come, the exchange fails with a CaseMismatch. A truncated
+//          response is retried over TCP
+fn exchange(query: &Message, server: SocketAddr, timeout: Duration, rejected: &AtomicU64, exact: bool)
+            -> Result<Message, Box<dyn Error + Send + Sync>>
+{
+    let sock = bind_random_port(server)?;
+    sock.send_to(query.to_bytes().as_slice(), server)?;
 
-    let (len, _addr) = sock.recv_from(&mut dns_resp)?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0; query.udp_payload_size()];
+    let mut wrong_case = false;
+
+    loop
+    {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0)
+        {
+            if wrong_case
+            {
+                return Err(CaseMismatch { server, response: None }.into());
+            }
+
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                      format!("No response from {}", server)).into());
+        }
+
+        sock.set_read_timeout(Some(remaining))?;
+        let (len, addr) = match sock.recv_from(&mut buf)
+        {
+            Ok(received) => received,
+            Err(e) if wrong_case && matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        match parse_msg(&buf[..len])
+        {
+            Ok((_, resp)) if addr == server && resp.is_response_to(query) => {
+                if exact && !resp.echoes_case(query)
+                {
+                    rejected.fetch_add(1, Ordering::Relaxed);
+                    wrong_case = true;
+                    continue;
+                }
+
+                if resp.header.trunc_resp
+                {
+                    let resp = exchange_tcp(query, server, timeout)?;
+                    return if exact { check_case(resp, query, server) } else { Ok(resp) };
+                }
+
+                return Ok(resp);
+            },
+            _ => {
+                rejected.fetch_add(1, Ordering::Relaxed);
+            },
+        }
+    }
+}
+
+// EFFECTS: Sends query to server over a new TCP connection and reads back
+//          its response. Each step may take up to timeout
+fn exchange_tcp(query: &Message, server: SocketAddr, timeout: Duration)
+                -> Result<Message, Box<dyn Error + Send + Sync>>
+{
+    let mut stream = TcpStream::connect_timeout(&server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    exchange_stream(&mut stream, query, server)
+}
+
+// EFFECTS: Sends query to server over stream with the two byte length prefix
+//          of RFC 1035 (4.2.2) and reads back its response
+fn exchange_stream<S: Read + Write>(stream: &mut S, query: &Message, server: SocketAddr)
+                                    -> Result<Message, Box<dyn Error + Send + Sync>>
+{
+    stream.write_all(&tcp_frame(query)?)?;
+    stream.flush()?;
+
+    let mut len = [0; 2];
+    stream.read_exact(&mut len)?;
+    let mut buf = vec![0; u16::from_be_bytes(len).into()];
+    stream.read_exact(&mut buf)?;
+
+    tcp_response(&buf, query, server)
+}
+
+// EFFECTS: Returns query prefixed with its length, as sent over TCP
+fn tcp_frame(query: &Message) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>>
+{
+    let bytes = query.to_bytes();
+    let len = u16::try_from(bytes.len())?;
+    let mut framed = len.to_be_bytes().to_vec();
+    framed.extend(bytes);
+
+    Ok(framed)
+}
+
+// EFFECTS: Parses the message read from server over TCP, failing unless it
+//          is a response to query
+fn tcp_response(buf: &[u8], query: &Message, server: SocketAddr) -> Result<Message, Box<dyn Error + Send + Sync>>
+{
+    let (_, resp) = parse_msg(buf)
+        .map_err(|e| format!("Malformed response from {}: {:?}", server, e))?;
 
-    if let Ok(resp) = parse_msg(&dns_resp[..len])
+    if !resp.is_response_to(query)
     {
-        println!("Got resp:\n{:?}", resp);
+        return Err(format!("Response from {} over TCP doesn't match the query", server).into());
     }
 
-    Ok(IpAddr::V6(Ipv6Addr::new(0,0,0,0,0,0,0,1)))
+    Ok(resp)
 }
@@ -0,0 +1,517 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ Duration, Instant };
+
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+use tokio::net::{ TcpStream, UdpSocket };
+use tokio::sync::{ oneshot, OnceCell };
+use tokio::task::{ self, JoinHandle };
+use tokio::time;
+
+use crate::dns::{ self, QType, header::RespCode, message::Message };
+use crate::parser::parse_msg;
+
+use super::{
+    config::{ FamilyPreference, ResolverConfig },
+    lookup::Lookup,
+    transport::CaseMismatch,
+    answered, check_case, hostnames, next_search_name, restore_case, tcp_frame, tcp_response, with_random_case, Resolver,
+};
+
+#[cfg(test)]
+mod tests;
+
+// Each family gets a few long lived sockets, so that a burst of lookups
+// doesn't need a socket (and a source port) each
+const SOCKETS_PER_FAMILY: usize = 4;
+const MAX_UDP_SIZE: usize = 65_535;
+
+// Queries awaiting a response, keyed on the server asked and the query ID
+type Pending = Arc<Mutex<HashMap<(SocketAddr, u16), Awaiting>>>;
+
+// A query awaiting its response. An exact one takes only a response
+// spelling its question in the same case, noting whether any other came
+#[derive(Debug)]
+struct Awaiting
+{
+    query: Message,
+    exact: bool,
+    wrong_case: bool,
+    tx: oneshot::Sender<Message>,
+}
+
+// ------------- Async Resolver -------------
+// A stub resolver for tokio, answering like Resolver (and sharing its
+// config, hosts file and cache) but without blocking. Any number of lookups
+// may run at once over a small set of shared UDP sockets, and dropping a
+// lookup cancels its outstanding query. A resolver made with another
+// transport (DoT, DoH or a mock) keeps it: each exchange runs on tokio's
+// blocking pool, where a dropped lookup's query runs on to its timeout
+#[derive(Clone, Debug)]
+pub struct AsyncResolver
+{
+    resolver: Resolver,
+    sockets: Arc<Sockets>,
+}
+
+impl From<Resolver> for AsyncResolver
+{
+    fn from(resolver: Resolver) -> Self
+    {
+        AsyncResolver { resolver, sockets: Arc::new(Sockets::default()) }
+    }
+}
+
+impl AsyncResolver
+{
+    pub fn new(config: ResolverConfig) -> Self
+    {
+        Resolver::new(config).into()
+    }
+
+    // EFFECTS: Returns the blocking resolver sharing this one's config and cache
+    pub fn blocking(&self) -> &Resolver
+    {
+        &self.resolver
+    }
+
+    // EFFECTS: Returns how many queries are waiting on a UDP response
+    pub fn outstanding_queries(&self) -> usize
+    {
+        [&self.sockets.v4, &self.sockets.v6].iter()
+                                            .filter_map(|family| family.get())
+                                            .flatten()
+                                            .map(|s| s.pending.lock().unwrap().len())
+                                            .sum()
+    }
+
+    // EFFECTS: Returns the first address lookup_ip finds for hostname
+    pub async fn resolve(&self, hostname: &str) -> Result<IpAddr, Box<dyn Error + Send + Sync>>
+    {
+        Ok(self.lookup_ip(hostname).await?[0])
+    }
+
+    // EFFECTS: Looks up the addresses of hostname as Resolver::lookup_ip does
+    pub async fn lookup_ip(&self, hostname: &str) -> Result<Vec<IpAddr>, Box<dyn Error + Send + Sync>>
+    {
+        if let Some(ips) = self.resolver.ips_from_hosts(hostname)
+        {
+            return Ok(ips);
+        }
+
+        let family = self.resolver.config.family;
+        for name in self.resolver.config.search_names(hostname)
+        {
+            let (v4, v6) = match family
+            {
+                FamilyPreference::Ipv4Only => (Some(self.lookup(&name, QType::A).await), None),
+                FamilyPreference::Ipv6Only => (None, Some(self.lookup(&name, QType::AAAA).await)),
+                _ => {
+                    let (v4, v6) = tokio::join!(self.lookup(&name, QType::A), self.lookup(&name, QType::AAAA));
+                    (Some(v4), Some(v6))
+                },
+            };
+
+            if let Some(ips) = next_search_name(hostname, family, v4, v6)?
+            {
+                return Ok(ips);
+            }
+        }
+
+        Err(format!("No address found for {}", hostname).into())
+    }
+
+    // EFFECTS: Looks up qname/qtype under the names the search list makes
+    //          of it as Resolver::search does
+    pub async fn search(&self, qname: &str, qtype: QType) -> Result<Lookup, Box<dyn Error + Send + Sync>>
+    {
+        let mut outcome: Option<Lookup> = None;
+
+        for name in self.resolver.config.search_names(qname)
+        {
+            let lookup = self.lookup(&name, qtype).await?;
+            if !lookup.answers.is_empty()
+            {
+                return Ok(lookup);
+            }
+
+            match &outcome
+            {
+                Some(o) if o.rcode == RespCode::Ok => {},
+                _ => outcome = Some(lookup),
+            }
+        }
+
+        Ok(outcome.expect("The search list always holds the name itself"))
+    }
+
+    // EFFECTS: Looks up the hostnames of ip as Resolver::reverse_lookup does
+    pub async fn reverse_lookup(&self, ip: IpAddr) -> Result<Vec<String>, Box<dyn Error + Send + Sync>>
+    {
+        hostnames(ip, self.lookup(&dns::reverse_name(ip), QType::PTR).await?)
+    }
+
+    // EFFECTS: Looks up qname/qtype as Resolver::lookup does
+    pub async fn lookup(&self, qname: &str, qtype: QType) -> Result<Lookup, Box<dyn Error + Send + Sync>>
+    {
+        let mut lookup = Lookup::new(qname, qtype);
+        if self.resolver.answer_from_hosts(&mut lookup)
+        {
+            return Ok(lookup);
+        }
+
+        while !self.resolver.answer_from_cache(&mut lookup)?
+        {
+            let resp = match self.query(lookup.canonical_name(), qtype).await
+            {
+                Ok(resp) => resp,
+                Err(e) => return self.resolver.answer_from_stale(lookup, e),
+            };
+
+            if self.resolver.absorb(&mut lookup, &resp)?
+            {
+                break;
+            }
+        }
+        self.prefetch();
+
+        Ok(lookup)
+    }
+
+    // EFFECTS: Refreshes each cache entry found due for it as
+    //          Resolver::prefetch does, on a task of its own
+    fn prefetch(&self)
+    {
+        for (name, rr_type) in self.resolver.cache.take_prefetches()
+        {
+            let r = self.clone();
+            tokio::spawn(async move { r.refresh(&name, rr_type.into()).await });
+        }
+    }
+
+    // EFFECTS: Queries qname/qtype upstream and caches what comes back
+    async fn refresh(&self, qname: &str, qtype: QType)
+    {
+        if let Ok(resp) = self.query(qname, qtype).await
+        {
+            let _ = self.resolver.absorb(&mut Lookup::new(qname, qtype), &resp);
+        }
+    }
+
+    // EFFECTS: Sends the query to the nameservers in the order and with the
+    //          timeouts Resolver::query uses, updating the same srtt
+    async fn query(&self, qname: &str, qtype: QType) -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        let config = &self.resolver.config;
+        if config.nameservers.is_empty()
+        {
+            return Err("No nameservers configured".into());
+        }
+
+        let m = self.resolver.build_query(qname, qtype);
+        let servers = self.resolver.server_order();
+        let mut last_err: Box<dyn Error + Send + Sync> = "No attempts configured".into();
+
+        for attempt in 0..config.attempts
+        {
+            let timeout = self.resolver.attempt_timeout(attempt);
+
+            for &server in servers.iter()
+            {
+                let sent = Instant::now();
+                match self.ask(&m, server, timeout).await.and_then(|resp| answered(resp, server))
+                {
+                    Ok(resp) => {
+                        self.resolver.srtt.record_response(server, sent.elapsed());
+                        return Ok(resp);
+                    },
+                    Err(e) => {
+                        self.resolver.srtt.record_failure(server, timeout);
+                        last_err = e;
+                    },
+                }
+            }
+        }
+
+        Err(last_err)
+    }
+
+    // EFFECTS: Sends query to server, randomizing its case as Resolver::ask
+    //          does
+    async fn ask(&self, query: &Message, server: SocketAddr, timeout: Duration)
+                 -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        if !self.resolver.randomizes_case(server)
+        {
+            return self.send(query, server, timeout, false).await;
+        }
+
+        let sent = with_random_case(query);
+        let resp = match self.send(&sent, server, timeout, true).await
+        {
+            Ok(resp) => resp,
+            Err(e) => {
+                let mismatch = e.downcast::<CaseMismatch>()?;
+                let resp = match mismatch.response
+                {
+                    Some(resp) => resp,
+                    None => exchange_tcp(&sent, server, timeout).await?,
+                };
+                self.resolver.note_case(server, &sent, &resp);
+                resp
+            },
+        };
+
+        Ok(restore_case(resp, query))
+    }
+
+    // EFFECTS: Sends query to server over the transport of the resolver if
+    //          it was given one, else over TCP if config.tcp is set, or else
+    //          over the shared sockets. If exact, only a response in the
+    //          case of query will do, as Transport::exchange_exact has it
+    async fn send(&self, query: &Message, server: SocketAddr, timeout: Duration, exact: bool)
+                  -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        if !self.resolver.builtin_transport
+        {
+            let transport = self.resolver.transport.clone();
+            let query = query.clone();
+
+            task::spawn_blocking(move || {
+                if exact
+                {
+                    transport.exchange_exact(&query, server, timeout)
+                } else {
+                    transport.exchange(&query, server, timeout)
+                }
+            }).await?
+        } else if self.resolver.config.tcp {
+            let resp = exchange_tcp(query, server, timeout).await?;
+            if exact { check_case(resp, query, server) } else { Ok(resp) }
+        } else {
+            self.exchange(query, server, timeout, exact).await
+        }
+    }
+
+    // EFFECTS: Sends query to server from one of the shared sockets and
+    //          waits up to timeout for the response. The query ID is changed
+    //          if another outstanding query to server already uses it. If
+    //          exact, responses in another case are passed over, and the
+    //          exchange fails with a CaseMismatch should only such come. A
+    //          truncated response is retried over TCP
+    async fn exchange(&self, query: &Message, server: SocketAddr, timeout: Duration, exact: bool)
+                      -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        let socks = self.sockets.for_server(server, &self.resolver.rejected).await?;
+        let sock = &socks[rand::thread_rng().gen_range(0..socks.len())];
+
+        let (query, mut waiter) = sock.register(query, server, exact);
+        sock.sock.send_to(&query.to_bytes(), server).await?;
+
+        let resp = match time::timeout(timeout, &mut waiter.rx).await
+        {
+            Ok(Ok(resp)) => resp,
+            Ok(Err(_)) => return Err(format!("Stopped listening for {}", server).into()),
+            Err(_) if waiter.saw_wrong_case() => return Err(CaseMismatch { server, response: None }.into()),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                                format!("No response from {}", server)).into()),
+        };
+
+        if resp.header.trunc_resp
+        {
+            let resp = exchange_tcp(&query, server, timeout).await?;
+            return if exact { check_case(resp, &query, server) } else { Ok(resp) };
+        }
+
+        Ok(resp)
+    }
+}
+
+// EFFECTS: Sends query to server over TCP as the blocking exchange_tcp does
+async fn exchange_tcp(query: &Message, server: SocketAddr, timeout: Duration)
+                      -> Result<Message, Box<dyn Error + Send + Sync>>
+{
+    let exchange = async {
+        let mut stream = TcpStream::connect(server).await?;
+        stream.write_all(&tcp_frame(query)?).await?;
+
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).await?;
+        let mut buf = vec![0; u16::from_be_bytes(len).into()];
+        stream.read_exact(&mut buf).await?;
+
+        tcp_response(&buf, query, server)
+    };
+
+    time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("No response from {} over TCP", server)))?
+}
+
+// ------------- Shared Sockets -------------
+// UDP sockets bound on first use, one set per address family
+#[derive(Debug, Default)]
+struct Sockets
+{
+    v4: OnceCell<Vec<SharedSocket>>,
+    v6: OnceCell<Vec<SharedSocket>>,
+}
+
+impl Sockets
+{
+    async fn for_server(&self, server: SocketAddr, rejected: &Arc<AtomicU64>) -> io::Result<&[SharedSocket]>
+    {
+        let (family, ip): (_, IpAddr) = match server
+        {
+            SocketAddr::V4(_) => (&self.v4, Ipv4Addr::UNSPECIFIED.into()),
+            SocketAddr::V6(_) => (&self.v6, Ipv6Addr::UNSPECIFIED.into()),
+        };
+
+        let socks = family.get_or_try_init(|| async {
+            (0..SOCKETS_PER_FAMILY).map(|_| SharedSocket::bind(ip, rejected.clone()))
+                                   .collect::<io::Result<Vec<_>>>()
+        }).await?;
+
+        Ok(socks)
+    }
+}
+
+// A socket on a random port with a task handing each response to the query
+// awaiting it
+#[derive(Debug)]
+struct SharedSocket
+{
+    sock: Arc<UdpSocket>,
+    pending: Pending,
+    reader: JoinHandle<()>,
+}
+
+// Removes its query from the pending set when dropped, whether it was
+// answered, timed out or cancelled
+#[derive(Debug)]
+struct Waiter
+{
+    rx: oneshot::Receiver<Message>,
+    pending: Pending,
+    key: (SocketAddr, u16),
+}
+
+impl Drop for Waiter
+{
+    fn drop(&mut self)
+    {
+        // Once answered, the key may already belong to a newer query
+        self.rx.close();
+
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(&self.key).is_some_and(|awaiting| awaiting.tx.is_closed())
+        {
+            pending.remove(&self.key);
+        }
+    }
+}
+
+impl Waiter
+{
+    // EFFECTS: Returns whether a response in the wrong case has come for
+    //          the query, while it is still awaiting one
+    fn saw_wrong_case(&self) -> bool
+    {
+        self.pending.lock().unwrap().get(&self.key).is_some_and(|awaiting| awaiting.wrong_case)
+    }
+}
+
+impl SharedSocket
+{
+    fn bind(ip: IpAddr, rejected: Arc<AtomicU64>) -> io::Result<Self>
+    {
+        let std_sock = super::bind_random_port(SocketAddr::new(ip, 0))?;
+        std_sock.set_nonblocking(true)?;
+
+        let sock = Arc::new(UdpSocket::from_std(std_sock)?);
+        let pending = Pending::default();
+        let reader = tokio::spawn(read_responses(sock.clone(), pending.clone(), rejected));
+
+        Ok(SharedSocket { sock, pending, reader })
+    }
+
+    // EFFECTS: Records query as awaiting a response from server, under an ID
+    //          no other query to server is using, and in its exact case if
+    //          exact. Returns the query as sent
+    fn register(&self, query: &Message, server: SocketAddr, exact: bool) -> (Message, Waiter)
+    {
+        let mut query = query.clone();
+        let mut pending = self.pending.lock().unwrap();
+
+        while pending.contains_key(&(server, query.header.id))
+        {
+            query.header.id = rand::random();
+        }
+
+        let key = (server, query.header.id);
+        let (tx, rx) = oneshot::channel();
+        pending.insert(key, Awaiting { query: query.clone(), exact, wrong_case: false, tx });
+
+        (query, Waiter { rx, pending: self.pending.clone(), key })
+    }
+}
+
+impl Drop for SharedSocket
+{
+    fn drop(&mut self)
+    {
+        self.reader.abort();
+    }
+}
+
+// EFFECTS: Hands each datagram arriving on sock to the pending query it
+//          answers. Datagrams that don't parse, answer no pending query or
+//          answer an exact one in the wrong case are counted in rejected
+//          and otherwise ignored
+async fn read_responses(sock: Arc<UdpSocket>, pending: Pending, rejected: Arc<AtomicU64>)
+{
+    let mut buf = vec![0; MAX_UDP_SIZE];
+
+    loop
+    {
+        let (len, src) = match sock.recv_from(&mut buf).await
+        {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+
+        let waiter = parse_msg(&buf[..len]).ok().and_then(|(_, resp)| {
+            let mut pending = pending.lock().unwrap();
+            let key = (src, resp.header.id);
+
+            match pending.get_mut(&key)
+            {
+                Some(awaiting) if awaiting.exact && resp.is_response_to(&awaiting.query)
+                                  && !resp.echoes_case(&awaiting.query) => {
+                    awaiting.wrong_case = true;
+                    None
+                },
+                Some(awaiting) if resp.is_response_to(&awaiting.query) => {
+                    pending.remove(&key).map(|awaiting| (awaiting.tx, resp))
+                },
+                _ => None,
+            }
+        });
+
+        match waiter
+        {
+            Some((tx, resp)) => {
+                // The lookup may have been cancelled meanwhile
+                let _ = tx.send(resp);
+            },
+            None => {
+                rejected.fetch_add(1, Ordering::Relaxed);
+            },
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use super::*;
+
+use std::collections::HashSet;
+use std::net::TcpListener;
+use std::thread;
+
+use crate::dns::resourcerecord::{ RData, ResourceRecord };
+use crate::resolver::cache::CacheConfig;
+use crate::resolver::standin::{ bind_loopback, response, StandIn, Zone };
+use crate::resolver::transport::mock::MockTransport;
+use crate::resolver::DNS_PORT;
+
+fn lo(host: u8, port: u16) -> SocketAddr
+{
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, host)), port)
+}
+
+fn zone() -> Zone
+{
+    (1..=10).fold(Zone::new("example.com"), |z, i| {
+        z.with(&format!("host{}.example.com", i), RData::A(10, 0, 0, i))
+    })
+}
+
+// EFFECTS: A stub config using only the given upstreams, with no hosts file
+//          and no cache so that every lookup reaches them, resolving IPv4
+//          addresses only so that each resolve sends one query
+fn upstreams(nameservers: Vec<SocketAddr>) -> ResolverConfig
+{
+    ResolverConfig {
+        hosts_file: None,
+        nameservers,
+        timeout: Duration::from_millis(500),
+        attempts: 1,
+        cache: CacheConfig { max_entries: 0, ..Default::default() },
+        family: FamilyPreference::Ipv4Only,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn test_runs_lookups_concurrently_over_shared_sockets()
+{
+    let (port, mut socks) = bind_loopback(&[40]);
+    let sock = socks.remove(0);
+    let z = zone();
+
+    // Holds back every answer until all ten queries are outstanding, then
+    // answers them last to first
+    let server = thread::spawn(move || {
+        let mut buf = [0; 512];
+        let mut queries = Vec::new();
+
+        while queries.len() < 10
+        {
+            let (len, src) = sock.recv_from(&mut buf).unwrap();
+            queries.push((parse_msg(&buf[..len]).unwrap().1, src));
+        }
+
+        for (q, src) in queries.iter().rev()
+        {
+            sock.send_to(&z.answer(q).to_bytes(), src).unwrap();
+        }
+
+        queries.iter().map(|(_, src)| *src).collect::<HashSet<_>>()
+    });
+
+    let r = AsyncResolver::new(upstreams(vec![lo(40, port)]));
+    let lookups: Vec<_> = (1..=10u8).map(|i| {
+        let r = r.clone();
+        tokio::spawn(async move { (i, r.resolve(&format!("host{}.example.com", i)).await) })
+    }).collect();
+
+    for l in lookups
+    {
+        let (i, ip) = l.await.unwrap();
+        assert_eq!(IpAddr::V4(Ipv4Addr::new(10, 0, 0, i)), ip.unwrap());
+    }
+
+    assert!(server.join().unwrap().len() <= SOCKETS_PER_FAMILY);
+    assert_eq!(0, r.outstanding_queries());
+}
+
+#[tokio::test]
+async fn test_dropping_a_lookup_cancels_its_query()
+{
+    let (port, mut socks) = bind_loopback(&[41]);
+    let server = StandIn::spawn(socks.remove(0), |_| None);
+
+    let r = AsyncResolver::new(upstreams(vec![lo(41, port)]));
+    let lookup = time::timeout(Duration::from_millis(100), r.lookup("host1.example.com", QType::A));
+
+    assert!(lookup.await.is_err());
+    assert_eq!(1, server.query_count());
+    assert_eq!(0, r.outstanding_queries());
+}
+
+#[tokio::test]
+async fn test_times_out_and_fails_over()
+{
+    let (port, mut socks) = bind_loopback(&[42, 43]);
+    let silent = StandIn::spawn(socks.remove(0), |_| None);
+    let answering = StandIn::serve(socks.remove(0), zone());
+
+    let mut config = upstreams(vec![lo(42, port), lo(43, port)]);
+    config.timeout = Duration::from_millis(100);
+    let r = AsyncResolver::new(config);
+
+    assert_eq!(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), r.resolve("host1.example.com").await.unwrap());
+    assert_eq!(1, silent.query_count());
+    assert_eq!(1, answering.query_count());
+}
+
+#[tokio::test]
+async fn test_fails_over_on_server_failure()
+{
+    let (port, mut socks) = bind_loopback(&[86, 87]);
+    let failing = StandIn::spawn(socks.remove(0), |q| Some(response(q, RespCode::ServFail, false, vec![], vec![], vec![])));
+    let answering = StandIn::serve(socks.remove(0), zone());
+
+    let r = AsyncResolver::new(upstreams(vec![lo(86, port), lo(87, port)]));
+
+    assert_eq!(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), r.resolve("host1.example.com").await.unwrap());
+    assert_eq!((1, 1), (failing.query_count(), answering.query_count()));
+}
+
+#[tokio::test]
+async fn test_shares_cache_with_blocking_resolver()
+{
+    let (port, mut socks) = bind_loopback(&[44]);
+    let server = StandIn::serve(socks.remove(0), zone());
+
+    let mut config = upstreams(vec![lo(44, port)]);
+    config.cache = CacheConfig::default();
+    let blocking = Resolver::new(config);
+    let r = AsyncResolver::from(blocking.clone());
+
+    let l = r.lookup("host2.example.com", QType::A).await.unwrap();
+    thread::spawn(move || blocking.lookup("host2.example.com", QType::A).unwrap()).join().unwrap();
+
+    assert_eq!(vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))], l.ips());
+    assert_eq!(1, server.query_count());
+    assert_eq!(1, r.blocking().cache.stats().hits);
+}
+
+#[tokio::test]
+async fn test_retries_truncated_response_over_tcp()
+{
+    let (port, mut socks) = bind_loopback(&[45]);
+    let listener = TcpListener::bind(lo(45, port)).unwrap();
+    let udp = StandIn::spawn(socks.remove(0), {
+        let z = zone();
+        move |q| {
+            let mut m = z.answer(q);
+            m.header.trunc_resp = true;
+            m.header.an_count = 0;
+            m.answs = None;
+            Some(m)
+        }
+    });
+    let tcp = StandIn::serve_tcp(listener, zone());
+
+    let r = AsyncResolver::new(upstreams(vec![lo(45, port)]));
+
+    assert_eq!(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)), r.resolve("host3.example.com").await.unwrap());
+    assert_eq!(1, udp.query_count());
+    assert_eq!(1, tcp.query_count());
+}
+
+#[tokio::test]
+async fn test_looks_up_both_families_concurrently()
+{
+    let (port, mut socks) = bind_loopback(&[46]);
+    let z = zone().with("host1.example.com", RData::AAAA(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+    let server = StandIn::serve(socks.remove(0), z);
+
+    let mut config = upstreams(vec![lo(46, port)]);
+    config.family = FamilyPreference::PreferIpv6;
+    let r = AsyncResolver::new(config);
+
+    assert_eq!(vec!["2001:db8::1".parse::<IpAddr>().unwrap()], r.lookup_ip("host1.example.com").await.unwrap());
+    assert_eq!(2, server.query_count());
+}
+
+#[tokio::test]
+async fn test_randomizes_case_like_blocking_resolver()
+{
+    let (port, mut socks) = bind_loopback(&[65]);
+    let listener = TcpListener::bind(lo(65, port)).unwrap();
+    // Answers with the question lowercased, over UDP and TCP alike
+    let lowercased = |z: Zone| move |q: &Message| {
+        let mut resp = z.answer(q);
+        for q in resp.quests.iter_mut().flatten()
+        {
+            q.qname = q.qname.to_ascii_lowercase();
+        }
+        Some(resp)
+    };
+    let udp = StandIn::spawn(socks.remove(0), lowercased(zone()));
+    let tcp = StandIn::spawn_tcp(listener, lowercased(zone()));
+
+    let mut config = upstreams(vec![lo(65, port)]);
+    config.randomize_case = true;
+    let r = AsyncResolver::new(config);
+
+    let l = r.lookup("host3.example.com", QType::A).await.unwrap();
+
+    assert_eq!("host3.example.com", l.answers[0].name);
+    assert_eq!(vec![lo(65, port)], r.blocking().case_blind_servers());
+    // One spelling in random case, confirmed lost over TCP
+    assert_eq!((1, 1), (udp.query_count(), tcp.query_count()));
+}
+
+#[tokio::test]
+async fn test_sends_over_the_transport_of_the_resolver()
+{
+    let mock = Arc::new(MockTransport::new().with_answers("www.example.com", QType::A, vec![
+        ResourceRecord::new("www.example.com", 300, RData::A(10, 0, 0, 1)),
+    ]));
+    let server = lo(1, DNS_PORT);
+    let r = AsyncResolver::from(Resolver::with_transport(upstreams(vec![server]), mock.clone()));
+
+    assert_eq!(vec![IpAddr::from([10, 0, 0, 1])], r.lookup_ip("www.example.com").await.unwrap());
+    assert!(r.lookup_ip("ftp.example.com").await.is_err());
+
+    let asked: Vec<_> = mock.queries().into_iter().map(|(s, q)| (s, q.qname)).collect();
+    assert_eq!(vec![(server, "www.example.com".to_string()), (server, "ftp.example.com".to_string())], asked);
+    assert_eq!(0, r.outstanding_queries());
+}
@@ -0,0 +1,893 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::mem;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+use crate::dns::{
+    self, Class, Type,
+    header::{ QR, RespCode },
+    message::Message,
+    nsec::{ self, NSEC3_OPT_OUT, NSEC3_SHA1 },
+    resourcerecord::{ RData, ResourceRecord },
+};
+
+#[cfg(test)]
+mod tests;
+
+const DEFAULT_MAX_ENTRIES: usize = 10_000;
+const DEFAULT_MAX_BYTES: usize = 16 * 1024 * 1024;
+// One day, as in most recursive resolvers (e.g. unbound's cache-max-ttl)
+const DEFAULT_MAX_TTL: u32 = 86_400;
+// The upper end of the one to three hours suggested by RFC 2308 (section 5)
+const DEFAULT_MAX_NEGATIVE_TTL: u32 = 10_800;
+// The TTL RFC 8767 (section 4) recommends for stale answers
+const DEFAULT_STALE_ANSWER_TTL: u32 = 30;
+// Hits an entry needs before it is worth refreshing early
+const DEFAULT_PREFETCH_MIN_HITS: u64 = 3;
+// NSEC3 iterations beyond which a proof is treated as no proof at all, as
+// RFC 9276 (section 3.2) lets validators do
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+// The DNAME type, which like a delegation takes the names beneath its owner
+// out of the zone
+const DNAME: u16 = 39;
+
+// Cache entries are keyed on the lowercased owner name, type and class. A
+// type of None covers every type, as an NXDOMAIN answer does
+type Key = (String, Option<Type>, Class);
+
+// ------------- Cache Config -------------
+// Bounds on what the cache holds. A max_entries of zero disables caching.
+// Expired entries are kept for max_stale seconds more, to answer with a TTL
+// of stale_answer_ttl when no nameserver can be reached (RFC 8767). A
+// max_stale of zero, the default, serves nothing stale.
+// An entry hit at least prefetch_min_hits times is due to be refreshed once
+// it is hit within the last prefetch_percent of its TTL. A prefetch_percent
+// of zero, the default, prefetches nothing.
+// With aggressive_nsec, validated NSEC and NSEC3 proofs held for a zone
+// answer NXDOMAIN and NODATA for the other names and types they cover
+// (RFC 8198). Nothing can be validated yet, so nothing is synthesized
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheConfig
+{
+    pub max_entries: usize,
+    pub max_bytes: usize,
+    pub max_ttl: u32,
+    pub max_negative_ttl: u32,
+    pub max_stale: u32,
+    pub stale_answer_ttl: u32,
+    pub prefetch_percent: u8,
+    pub prefetch_min_hits: u64,
+    pub aggressive_nsec: bool,
+}
+
+impl Default for CacheConfig
+{
+    fn default() -> Self
+    {
+        CacheConfig {
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_ttl: DEFAULT_MAX_TTL,
+            max_negative_ttl: DEFAULT_MAX_NEGATIVE_TTL,
+            max_stale: 0,
+            stale_answer_ttl: DEFAULT_STALE_ANSWER_TTL,
+            prefetch_percent: 0,
+            prefetch_min_hits: DEFAULT_PREFETCH_MIN_HITS,
+            aggressive_nsec: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats
+{
+    pub hits: u64,
+    // Hits answered with NXDOMAIN or NODATA, also counted in hits
+    pub negative_hits: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    pub evictions: u64,
+    pub expirations: u64,
+    // Expired entries served because no nameserver could be reached, also
+    // counted in hits
+    pub stale_hits: u64,
+    // Entries found due for a refresh before they expire
+    pub prefetches: u64,
+    // Negative answers synthesized from NSEC or NSEC3 proofs, also counted
+    // in hits and negative_hits
+    pub synthesized: u64,
+}
+
+// How far cached data can be trusted, lowest first, after the ranking of
+// RFC 2181 section 5.4.1. Data is never replaced by data ranked lower
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Credibility
+{
+    // The additional section of any response, or the authority section of
+    // a non-authoritative one, such as referrals and their glue
+    Additional,
+    // The answer section of a non-authoritative response, as a recursive
+    // upstream gives
+    Answer,
+    // The authority section of an authoritative response
+    AuthAuthority,
+    // The answer section of an authoritative response
+    AuthAnswer,
+}
+
+// ------------- Cached -------------
+// What the cache knows about a name and type
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cached
+{
+    // The RRset asked for, or the CNAME held for the name instead
+    Records(Vec<ResourceRecord>),
+    // The name doesn't exist, as the SOA of its zone vouches (RFC 2308)
+    NxDomain(ResourceRecord),
+    // The name exists but has no records of the type
+    NoData(ResourceRecord),
+}
+
+impl Cached
+{
+    // EFFECTS: Returns a copy with every record's TTL set to ttl
+    fn with_ttl(&self, ttl: u32) -> Cached
+    {
+        let set = |rr: &ResourceRecord| ResourceRecord { ttl, ..rr.clone() };
+
+        match self
+        {
+            Cached::Records(rrs) => Cached::Records(rrs.iter().map(set).collect()),
+            Cached::NxDomain(soa) => Cached::NxDomain(set(soa)),
+            Cached::NoData(soa) => Cached::NoData(set(soa)),
+        }
+    }
+
+    // EFFECTS: Builds a response to query equivalent to the one this was
+    //          cached from: the records as answers, or the rcode of a
+    //          negative answer with its SOA in the authority section
+    pub fn to_response(&self, query: &Message) -> Message
+    {
+        let (rcode, answs, auths) = match self
+        {
+            Cached::Records(rrs) => (RespCode::Ok, rrs.clone(), vec![]),
+            Cached::NxDomain(soa) => (RespCode::NameError, vec![], vec![soa.clone()]),
+            Cached::NoData(soa) => (RespCode::Ok, vec![], vec![soa.clone()]),
+        };
+
+        let mut m = query.clone();
+        m.header.qr = QR::Response;
+        m.header.auth_answ = false;
+        m.header.rec_avail = true;
+        m.header.rcode = rcode;
+        m.header.an_count = answs.len() as u16;
+        m.header.ns_count = auths.len() as u16;
+        m.answs = if answs.is_empty() { None } else { Some(answs) };
+        m.auths = if auths.is_empty() { None } else { Some(auths) };
+
+        m
+    }
+}
+
+#[derive(Debug)]
+struct Entry
+{
+    answer: Cached,
+    expires: Instant,
+    size: usize,
+    credibility: Credibility,
+    // The TTL the entry was stored with
+    ttl: u32,
+    hits: u64,
+    // Whether a refresh has been handed out already
+    prefetching: bool,
+    // Position in the LRU order
+    used: u64,
+}
+
+// ------------- Denial -------------
+// A proof of nonexistence: an NSEC or NSEC3 record held along with the SOA
+// of its zone, which the answers it gives carry
+#[derive(Debug)]
+struct Denial
+{
+    rr: ResourceRecord,
+    soa: ResourceRecord,
+    expires: Instant,
+    // Answers are synthesized only from validated proofs
+    validated: bool,
+}
+
+impl Denial
+{
+    fn usable(&self, now: Instant) -> bool
+    {
+        self.validated && self.expires > now
+    }
+
+    fn has(&self, t: u16) -> bool
+    {
+        match &self.rr.rdata
+        {
+            RData::NSEC { types, .. } | RData::NSEC3 { types, .. } => types.contains(&t),
+            _ => false,
+        }
+    }
+
+    // EFFECTS: Returns true if names beneath the owner are delegated or
+    //          redirected away, so that the zone's proofs say nothing of them
+    fn cuts_off(&self) -> bool
+    {
+        (self.has(Type::NS as u16) && !self.has(Type::SOA as u16)) || self.has(DNAME)
+    }
+
+    // EFFECTS: Returns true if the owner, proven to exist, has no records of
+    //          rr_type nor a CNAME in their stead
+    fn denies_type(&self, rr_type: Type) -> bool
+    {
+        !self.has(rr_type as u16) && !self.has(Type::CNAME as u16) && !self.cuts_off()
+    }
+}
+
+// The proofs held for one zone: NSEC records keyed on their owners in
+// canonical order, NSEC3 records on the hashes their owners spell
+#[derive(Debug, Default)]
+struct Denials
+{
+    nsec: BTreeMap<Vec<String>, Denial>,
+    nsec3: BTreeMap<Vec<u8>, Denial>,
+}
+
+impl Denials
+{
+    fn len(&self) -> usize
+    {
+        self.nsec.len() + self.nsec3.len()
+    }
+
+    // EFFECTS: Holds denial, a proof for zone, under its owner unless a
+    //          usable proof is held there and denial isn't validated.
+    //          Returns how many more proofs are held
+    fn insert(&mut self, zone: &str, denial: Denial, now: Instant) -> usize
+    {
+        let keeps = |held: Option<&Denial>| held.is_some_and(|h| h.usable(now)) && !denial.validated;
+
+        match &denial.rr.rdata
+        {
+            RData::NSEC { .. } => {
+                let k = nsec::canonical_key(&denial.rr.name);
+                if keeps(self.nsec.get(&k))
+                {
+                    return 0;
+                }
+                usize::from(self.nsec.insert(k, denial).is_none())
+            },
+            RData::NSEC3 { .. } => {
+                // The owner is the hash in base32hex, directly beneath the zone
+                let name = dns::trim_root(&denial.rr.name);
+                let (label, parent) = name.split_once('.').unwrap_or((name, ""));
+                let k = match nsec::base32hex_decode(label)
+                {
+                    Some(k) if dns::name_eq(parent, zone) => k,
+                    _ => return 0,
+                };
+                if keeps(self.nsec3.get(&k))
+                {
+                    return 0;
+                }
+                usize::from(self.nsec3.insert(k, denial).is_none())
+            },
+            _ => 0,
+        }
+    }
+
+    // EFFECTS: Synthesizes the answer the usable proofs held give for
+    //          name/rr_type, a name in zone: NODATA if name exists without
+    //          the type, NXDOMAIN if neither it nor a wildcard that would
+    //          stand in for it exists. None if they prove neither
+    fn deny(&self, zone: &str, name: &str, rr_type: Type, now: Instant) -> Option<Cached>
+    {
+        self.deny_by_nsec(name, rr_type, now).or_else(|| self.deny_by_nsec3(zone, name, rr_type, now))
+    }
+
+    // EFFECTS: Denies as deny does from NSEC proofs (RFC 4035 section 5.4)
+    fn deny_by_nsec(&self, name: &str, rr_type: Type, now: Instant) -> Option<Cached>
+    {
+        if let Some(d) = self.nsec.get(&nsec::canonical_key(name))
+        {
+            return (d.usable(now) && d.denies_type(rr_type)).then(|| negative(Cached::NoData, &[d], now));
+        }
+
+        let d = self.nsec_covering(name, now)?;
+        let next = match &d.rr.rdata
+        {
+            RData::NSEC { next, .. } => next,
+            _ => return None,
+        };
+        if dns::in_zone(name, &d.rr.name) && d.cuts_off()
+        {
+            return None;
+        }
+
+        // A name with names beneath it exists, though it holds no records
+        if dns::in_zone(next, name)
+        {
+            return Some(negative(Cached::NoData, &[d], now));
+        }
+
+        // The closest encloser, the nearest ancestor of name that exists,
+        // is the longer of those it shares with either end of the span
+        let encloser = [common_ancestor(name, &d.rr.name), common_ancestor(name, next)]
+            .iter()
+            .max_by_key(|labels| labels.len())
+            .map(|labels| labels.join("."))
+            .unwrap_or_default();
+        let w = self.nsec_covering(&wildcard(&encloser), now)?;
+
+        Some(negative(Cached::NxDomain, &[d, w], now))
+    }
+
+    // EFFECTS: Denies as deny does from NSEC3 proofs, by way of a closest
+    //          encloser proof for NXDOMAIN (RFC 5155 sections 8.3 to 8.5)
+    fn deny_by_nsec3(&self, zone: &str, name: &str, rr_type: Type, now: Instant) -> Option<Cached>
+    {
+        // Every NSEC3 record of a zone hashes alike
+        let (salt, iterations) = self.nsec3.values().find(|d| d.usable(now)).and_then(|d| match &d.rr.rdata {
+            RData::NSEC3 { hash_alg, iterations, salt, .. }
+                if *hash_alg == NSEC3_SHA1 && *iterations <= MAX_NSEC3_ITERATIONS => Some((salt.clone(), *iterations)),
+            _ => None,
+        })?;
+        let hash = |n: &str| nsec::nsec3_hash(n, &salt, iterations);
+        let matching = |n: &str| hash(n).and_then(|h| self.nsec3.get(&h)).filter(|d| d.usable(now));
+
+        if let Some(d) = matching(name)
+        {
+            return d.denies_type(rr_type).then(|| negative(Cached::NoData, &[d], now));
+        }
+
+        let mut next_closer = name;
+        let mut encloser = parent(name)?;
+        let found = loop
+        {
+            if let Some(d) = matching(encloser)
+            {
+                break d;
+            }
+            if dns::name_eq(encloser, zone)
+            {
+                return None;
+            }
+            next_closer = encloser;
+            encloser = parent(encloser)?;
+        };
+        if found.cuts_off()
+        {
+            return None;
+        }
+
+        let nc = self.nsec3_covering(&hash(next_closer)?, now)?;
+        if matches!(nc.rr.rdata, RData::NSEC3 { flags, .. } if flags & NSEC3_OPT_OUT != 0)
+        {
+            return None;
+        }
+        let w = self.nsec3_covering(&hash(&wildcard(encloser))?, now)?;
+
+        Some(negative(Cached::NxDomain, &[found, nc, w], now))
+    }
+
+    // EFFECTS: Returns the usable NSEC record whose span name falls in
+    fn nsec_covering(&self, name: &str, now: Instant) -> Option<&Denial>
+    {
+        let k = nsec::canonical_key(name);
+        let (owner, d) = self.nsec.range(..k.clone()).next_back().or_else(|| self.nsec.iter().next_back())?;
+
+        match &d.rr.rdata
+        {
+            RData::NSEC { next, .. } if d.usable(now) && nsec::covers(owner, &nsec::canonical_key(next), &k) => Some(d),
+            _ => None,
+        }
+    }
+
+    // EFFECTS: Returns the usable NSEC3 record whose span hash falls in
+    fn nsec3_covering(&self, hash: &[u8], now: Instant) -> Option<&Denial>
+    {
+        let (owner, d) = self.nsec3.range(..hash.to_vec()).next_back().or_else(|| self.nsec3.iter().next_back())?;
+
+        match &d.rr.rdata
+        {
+            RData::NSEC3 { next_hashed, .. } if d.usable(now) && nsec::covers(&owner[..], next_hashed, hash) => Some(d),
+            _ => None,
+        }
+    }
+}
+
+// EFFECTS: Builds the negative answer proofs give, carrying the SOA of
+//          their zone with a TTL no longer than any of them has left
+fn negative(answer: fn(ResourceRecord) -> Cached, proofs: &[&Denial], now: Instant) -> Cached
+{
+    let ttl = proofs.iter().map(|d| remaining_ttl(d.expires, now)).min().unwrap_or(0);
+
+    answer(ResourceRecord { ttl, ..proofs[0].soa.clone() })
+}
+
+// EFFECTS: Returns the labels of the nearest common ancestor of a and b,
+//          leftmost first
+fn common_ancestor(a: &str, b: &str) -> Vec<String>
+{
+    let (a, b) = (nsec::canonical_key(a), nsec::canonical_key(b));
+    let shared = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+
+    a[..shared].iter().rev().cloned().collect()
+}
+
+// EFFECTS: Returns the name one label up from name, or None for the root
+fn parent(name: &str) -> Option<&str>
+{
+    let name = dns::trim_root(name);
+    if name.is_empty()
+    {
+        return None;
+    }
+
+    Some(name.split_once('.').map_or("", |(_, rest)| rest))
+}
+
+// EFFECTS: Returns the wildcard name directly beneath name
+fn wildcard(name: &str) -> String
+{
+    let name = dns::trim_root(name);
+    if name.is_empty() { "*".to_string() } else { format!("*.{}", name) }
+}
+
+#[derive(Debug, Default)]
+struct Inner
+{
+    entries: HashMap<Key, Entry>,
+    // Least recently used first
+    lru: BTreeMap<u64, Key>,
+    clock: u64,
+    bytes: usize,
+    stats: CacheStats,
+    // Names and types due to be refreshed, not yet taken
+    prefetches: Vec<(String, Type)>,
+    // Proofs of nonexistence by zone, and how many there are in all
+    denials: HashMap<String, Denials>,
+    denial_count: usize,
+}
+
+// ------------- Cache -------------
+// Answers keyed on (name, type, class), each living as long as its lowest
+// TTL and evicted least recently used first. Positive answers are stored as
+// RRsets, negative ones as the SOA record that came with them
+#[derive(Debug)]
+pub struct Cache
+{
+    config: CacheConfig,
+    inner: Mutex<Inner>,
+}
+
+fn key(name: &str, rr_type: Option<Type>, rr_class: Class) -> Key
+{
+    (dns::trim_root(name).to_ascii_lowercase(), rr_type, rr_class)
+}
+
+// EFFECTS: Approximates the memory held by rr
+fn rr_size(rr: &ResourceRecord) -> usize
+{
+    mem::size_of::<ResourceRecord>() + rr.name.len() + usize::from(rr.rd_len)
+}
+
+impl Cache
+{
+    pub fn new(config: CacheConfig) -> Self
+    {
+        Cache { config, inner: Mutex::new(Inner::default()) }
+    }
+
+    pub fn stats(&self) -> CacheStats
+    {
+        self.inner.lock().unwrap().stats
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    // EFFECTS: Returns what is cached for name/rr_type in class IN: the
+    //          RRset or NODATA answer for the type, else the CNAME held for
+    //          name, else an NXDOMAIN answer for name. TTLs are lowered by
+    //          the time spent in the cache
+    pub fn get(&self, name: &str, rr_type: Type) -> Option<Cached>
+    {
+        self.get_at(name, rr_type, Instant::now())
+    }
+
+    // EFFECTS: Returns what get would, also taking entries that expired at
+    //          most config.max_stale seconds ago. Those are given a TTL of
+    //          config.stale_answer_ttl
+    pub fn get_stale(&self, name: &str, rr_type: Type) -> Option<Cached>
+    {
+        self.get_stale_at(name, rr_type, Instant::now())
+    }
+
+    // EFFECTS: Returns the names and types whose entries were found due
+    //          for a refresh since the last call, for the caller to query
+    //          again and insert
+    pub fn take_prefetches(&self) -> Vec<(String, Type)>
+    {
+        mem::take(&mut self.inner.lock().unwrap().prefetches)
+    }
+
+    // EFFECTS: Caches records, grouped into RRsets, as answers from a
+    //          recursive upstream, replacing any RRset held under the same
+    //          key unless it is more credible
+    pub fn insert(&self, records: &[ResourceRecord])
+    {
+        self.insert_at(records, Instant::now())
+    }
+
+    // EFFECTS: Caches records, grouped into RRsets, as credible as given. An
+    //          RRset held under the same key is replaced unless it is more
+    //          credible and hasn't expired
+    pub fn insert_ranked(&self, records: &[ResourceRecord], credibility: Credibility)
+    {
+        self.insert_ranked_at(records, credibility, Instant::now())
+    }
+
+    // EFFECTS: Caches an NXDOMAIN (rcode NameError) or NODATA (rcode Ok)
+    //          answer for name/rr_type, for the lower of the TTL and minimum
+    //          field of soa (RFC 2308 section 5)
+    pub fn insert_negative(&self, name: &str, rr_type: Type, rcode: RespCode, soa: &ResourceRecord)
+    {
+        self.insert_negative_at(name, rr_type, rcode, soa, Instant::now())
+    }
+
+    // EFFECTS: Caches the NSEC and NSEC3 records among proofs that lie in
+    //          the zone of soa, for as long as a negative answer from it
+    //          would be. Only proofs inserted as validated ever answer for
+    //          other names (RFC 8198 section 5); a validated proof held
+    //          isn't replaced by one that isn't
+    pub fn insert_denial(&self, soa: &ResourceRecord, proofs: &[ResourceRecord], validated: bool)
+    {
+        self.insert_denial_at(soa, proofs, validated, Instant::now())
+    }
+
+    fn get_at(&self, name: &str, rr_type: Type, now: Instant) -> Option<Cached>
+    {
+        self.find(name, rr_type, now, false)
+    }
+
+    fn get_stale_at(&self, name: &str, rr_type: Type, now: Instant) -> Option<Cached>
+    {
+        self.find(name, rr_type, now, true)
+    }
+
+    // EFFECTS: Looks up name/rr_type as get does, taking stale entries too
+    //          if stale is set. Entries past the stale window are dropped
+    fn find(&self, name: &str, rr_type: Type, now: Instant, stale: bool) -> Option<Cached>
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let window = Duration::from_secs(self.config.max_stale.into());
+        let mut found = None;
+
+        for t in [Some(rr_type), Some(Type::CNAME), None].iter()
+        {
+            let k = key(name, *t, Class::IN);
+
+            match inner.entries.get(&k).map(|e| e.expires)
+            {
+                Some(expires) if expires > now || (stale && expires + window > now) => {
+                    found = Some((k, expires));
+                    break;
+                },
+                Some(expires) if expires + window > now => {},
+                Some(_) => {
+                    inner.remove(&k);
+                    inner.stats.expirations += 1;
+                },
+                None => {},
+            }
+        }
+
+        let (k, expires) = match found
+        {
+            Some(f) => f,
+            None => {
+                let synthesized = if stale || !self.config.aggressive_nsec
+                {
+                    None
+                } else {
+                    inner.synthesize(name, rr_type, now)
+                };
+
+                match synthesized
+                {
+                    Some(answer) => {
+                        inner.stats.hits += 1;
+                        inner.stats.negative_hits += 1;
+                        inner.stats.synthesized += 1;
+                        return Some(answer);
+                    },
+                    None => {
+                        inner.stats.misses += 1;
+                        return None;
+                    },
+                }
+            },
+        };
+
+        inner.touch(&k);
+        if !stale
+        {
+            inner.hit(&self.config, &k, name, rr_type, remaining_ttl(expires, now));
+        }
+
+        let ttl = if expires > now
+        {
+            remaining_ttl(expires, now)
+        } else {
+            inner.stats.stale_hits += 1;
+            self.config.stale_answer_ttl
+        };
+        let answer = inner.entries[&k].answer.with_ttl(ttl);
+
+        inner.stats.hits += 1;
+        if !matches!(answer, Cached::Records(_))
+        {
+            inner.stats.negative_hits += 1;
+        }
+
+        Some(answer)
+    }
+
+    fn insert_at(&self, records: &[ResourceRecord], now: Instant)
+    {
+        self.insert_ranked_at(records, Credibility::Answer, now)
+    }
+
+    fn insert_ranked_at(&self, records: &[ResourceRecord], credibility: Credibility, now: Instant)
+    {
+        if self.config.max_entries == 0
+        {
+            return;
+        }
+
+        let mut rrsets: HashMap<Key, Vec<ResourceRecord>> = HashMap::new();
+        for rr in records
+        {
+            rrsets.entry(key(&rr.name, Some(rr.rr_type), rr.rr_class))
+                  .or_default()
+                  .push(rr.clone());
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+
+        for (k, rrset) in rrsets
+        {
+            // The RRset lives only as long as its shortest lived member
+            let ttl = rrset.iter()
+                           .map(|rr| rr.ttl)
+                           .min()
+                           .unwrap_or(0)
+                           .min(self.config.max_ttl);
+            let size = rrset.iter().map(rr_size).sum::<usize>();
+
+            inner.store(&self.config, k, Cached::Records(rrset), ttl, size, credibility, now);
+        }
+    }
+
+    fn insert_negative_at(&self, name: &str, rr_type: Type, rcode: RespCode, soa: &ResourceRecord, now: Instant)
+    {
+        let minimum = match soa.rdata
+        {
+            RData::SOA { minimum, .. } => minimum,
+            _ => return,
+        };
+
+        let (k, answer) = match rcode
+        {
+            RespCode::NameError => (key(name, None, Class::IN), Cached::NxDomain(soa.clone())),
+            RespCode::Ok => (key(name, Some(rr_type), Class::IN), Cached::NoData(soa.clone())),
+            _ => return,
+        };
+
+        if self.config.max_entries == 0
+        {
+            return;
+        }
+
+        let ttl = soa.ttl.min(minimum).min(self.config.max_negative_ttl);
+
+        self.inner.lock().unwrap().store(&self.config, k, answer, ttl, rr_size(soa), Credibility::Answer, now);
+    }
+
+    fn insert_denial_at(&self, soa: &ResourceRecord, proofs: &[ResourceRecord], validated: bool, now: Instant)
+    {
+        let minimum = match soa.rdata
+        {
+            RData::SOA { minimum, .. } => minimum,
+            _ => return,
+        };
+
+        if self.config.max_entries == 0
+        {
+            return;
+        }
+
+        let zone = dns::trim_root(&soa.name).to_ascii_lowercase();
+        let max_ttl = soa.ttl.min(minimum).min(self.config.max_negative_ttl);
+        let mut inner = self.inner.lock().unwrap();
+
+        for rr in proofs.iter().filter(|rr| matches!(rr.rr_type, Type::NSEC | Type::NSEC3))
+        {
+            let ttl = rr.ttl.min(max_ttl);
+            if ttl == 0 || !dns::in_zone(&rr.name, &zone)
+            {
+                continue;
+            }
+
+            let denial = Denial {
+                rr: rr.clone(),
+                soa: soa.clone(),
+                expires: now + Duration::from_secs(ttl.into()),
+                validated,
+            };
+            inner.store_denial(&self.config, &zone, denial, now);
+        }
+    }
+}
+
+// EFFECTS: Returns the whole seconds left before expires, rounding up so
+//          that an unexpired record never reports a TTL of zero
+fn remaining_ttl(expires: Instant, now: Instant) -> u32
+{
+    let left = expires.saturating_duration_since(now);
+    let secs = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+
+    secs.min(u32::MAX.into()) as u32
+}
+
+impl Inner
+{
+    // EFFECTS: Holds answer under k for ttl seconds from now, replacing what
+    //          was there unless that is unexpired and more credible, then
+    //          evicts until back within the bounds of config
+    #[allow(clippy::too_many_arguments)]
+    fn store(&mut self, config: &CacheConfig, k: Key, answer: Cached, ttl: u32, size: usize,
+             credibility: Credibility, now: Instant)
+    {
+        if ttl == 0 || size > config.max_bytes
+        {
+            return;
+        }
+
+        if self.entries.get(&k).is_some_and(|e| e.expires > now && e.credibility > credibility)
+        {
+            return;
+        }
+
+        self.remove(&k);
+        self.clock += 1;
+        let used = self.clock;
+
+        self.bytes += size;
+        self.lru.insert(used, k.clone());
+        self.entries.insert(k, Entry {
+            answer,
+            expires: now + Duration::from_secs(ttl.into()),
+            size,
+            credibility,
+            ttl,
+            hits: 0,
+            prefetching: false,
+            used,
+        });
+        self.stats.inserts += 1;
+
+        while self.entries.len() > config.max_entries || self.bytes > config.max_bytes
+        {
+            self.evict_lru();
+        }
+    }
+
+    // EFFECTS: Holds denial, a proof for zone, while fewer than
+    //          config.max_entries proofs are held, dropping expired ones
+    //          to make room
+    fn store_denial(&mut self, config: &CacheConfig, zone: &str, denial: Denial, now: Instant)
+    {
+        if self.denial_count >= config.max_entries
+        {
+            for denials in self.denials.values_mut()
+            {
+                denials.nsec.retain(|_, d| d.expires > now);
+                denials.nsec3.retain(|_, d| d.expires > now);
+            }
+            self.denials.retain(|_, denials| denials.len() > 0);
+            self.denial_count = self.denials.values().map(Denials::len).sum();
+
+            if self.denial_count >= config.max_entries
+            {
+                return;
+            }
+        }
+
+        self.denial_count += self.denials.entry(zone.to_string()).or_default().insert(zone, denial, now);
+    }
+
+    // EFFECTS: Returns the negative answer for name/rr_type that the
+    //          proofs of the nearest enclosing zone holding any give
+    fn synthesize(&self, name: &str, rr_type: Type, now: Instant) -> Option<Cached>
+    {
+        let name = dns::trim_root(name).to_ascii_lowercase();
+        let mut zone = name.as_str();
+
+        loop
+        {
+            if let Some(denials) = self.denials.get(zone)
+            {
+                return denials.deny(zone, &name, rr_type, now);
+            }
+            zone = parent(zone)?;
+        }
+    }
+
+    // EFFECTS: Counts a hit on the entry under k, found by a lookup of
+    //          name/rr_type with left seconds to live, and queues that
+    //          lookup for a refresh if the entry has become due
+    fn hit(&mut self, config: &CacheConfig, k: &Key, name: &str, rr_type: Type, left: u32)
+    {
+        let e = match self.entries.get_mut(k)
+        {
+            Some(e) => e,
+            None => return,
+        };
+        e.hits += 1;
+
+        let late = u64::from(left) * 100 <= u64::from(e.ttl) * u64::from(config.prefetch_percent);
+        if config.prefetch_percent > 0 && late && e.hits >= config.prefetch_min_hits && !e.prefetching
+        {
+            e.prefetching = true;
+            self.prefetches.push((name.to_string(), rr_type));
+            self.stats.prefetches += 1;
+        }
+    }
+
+    fn remove(&mut self, k: &Key)
+    {
+        if let Some(e) = self.entries.remove(k)
+        {
+            self.lru.remove(&e.used);
+            self.bytes -= e.size;
+        }
+    }
+
+    fn touch(&mut self, k: &Key)
+    {
+        self.clock += 1;
+        let now = self.clock;
+
+        if let Some(e) = self.entries.get_mut(k)
+        {
+            self.lru.remove(&e.used);
+            e.used = now;
+            self.lru.insert(now, k.clone());
+        }
+    }
+
+    fn evict_lru(&mut self)
+    {
+        let oldest = self.lru.iter().next().map(|(_, k)| k.clone());
+
+        if let Some(k) = oldest
+        {
+            self.remove(&k);
+            self.stats.evictions += 1;
+        }
+    }
+}
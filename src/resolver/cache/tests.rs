@@ -0,0 +1,470 @@
+use super::*;
+
+use crate::dns::{ QClass, QType, question::Question };
+
+fn a(name: &str, ttl: u32, last: u8) -> ResourceRecord
+{
+    ResourceRecord::new(name, ttl, RData::A(10, 0, 0, last))
+}
+
+fn soa(ttl: u32, minimum: u32) -> ResourceRecord
+{
+    ResourceRecord::new("example.com", ttl, RData::SOA {
+        mname: "ns.example.com".into(),
+        rname: "hostmaster.example.com".into(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum,
+    })
+}
+
+fn ttls(answer: &Cached) -> Vec<u32>
+{
+    match answer
+    {
+        Cached::Records(rrs) => rrs.iter().map(|rr| rr.ttl).collect(),
+        Cached::NxDomain(soa) | Cached::NoData(soa) => vec![soa.ttl],
+    }
+}
+
+#[test]
+fn test_ttl_counts_down()
+{
+    let cache = Cache::new(CacheConfig::default());
+    let t0 = Instant::now();
+    cache.insert_at(&[a("www.example.com", 300, 1)], t0);
+
+    let rrs = cache.get_at("www.example.com", Type::A, t0 + Duration::from_secs(100)).unwrap();
+    assert_eq!(vec![200], ttls(&rrs));
+
+    // Part of a second left still counts as a whole one
+    let rrs = cache.get_at("www.example.com", Type::A, t0 + Duration::from_millis(299_500)).unwrap();
+    assert_eq!(vec![1], ttls(&rrs));
+}
+
+#[test]
+fn test_expires_at_ttl()
+{
+    let cache = Cache::new(CacheConfig::default());
+    let t0 = Instant::now();
+    cache.insert_at(&[a("www.example.com", 60, 1)], t0);
+
+    assert!(cache.get_at("www.example.com", Type::A, t0 + Duration::from_secs(60)).is_none());
+    assert!(cache.is_empty());
+    assert_eq!(1, cache.stats().expirations);
+}
+
+#[test]
+fn test_rrset_lives_as_long_as_lowest_ttl()
+{
+    let cache = Cache::new(CacheConfig::default());
+    let t0 = Instant::now();
+    cache.insert_at(&[a("www.example.com", 300, 1), a("www.example.com", 30, 2)], t0);
+
+    let rrs = cache.get_at("www.example.com", Type::A, t0).unwrap();
+    assert_eq!(vec![30, 30], ttls(&rrs));
+    assert_eq!(1, cache.len());
+}
+
+#[test]
+fn test_keys_ignore_case_and_root_dot()
+{
+    let cache = Cache::new(CacheConfig::default());
+    cache.insert(&[a("WWW.Example.com.", 300, 1)]);
+
+    assert!(cache.get("www.example.com", Type::A).is_some());
+    assert!(cache.get("www.example.com", Type::AAAA).is_none());
+}
+
+#[test]
+fn test_returns_cname_when_type_not_cached()
+{
+    let cache = Cache::new(CacheConfig::default());
+    cache.insert(&[ResourceRecord::new("web.example.com", 300, RData::CNAME("www.example.com".into()))]);
+
+    match cache.get("web.example.com", Type::A)
+    {
+        Some(Cached::Records(rrs)) => assert_eq!(Type::CNAME, rrs[0].rr_type),
+        other => panic!("Expected the CNAME, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_caps_ttl_and_skips_zero()
+{
+    let cache = Cache::new(CacheConfig { max_ttl: 60, ..Default::default() });
+    let t0 = Instant::now();
+    cache.insert_at(&[a("www.example.com", 86_400, 1), a("ftp.example.com", 0, 2)], t0);
+
+    assert_eq!(vec![60], ttls(&cache.get_at("www.example.com", Type::A, t0).unwrap()));
+    assert!(cache.get_at("ftp.example.com", Type::A, t0).is_none());
+}
+
+#[test]
+fn test_evicts_least_recently_used()
+{
+    let cache = Cache::new(CacheConfig { max_entries: 2, ..Default::default() });
+    cache.insert(&[a("one.example.com", 300, 1)]);
+    cache.insert(&[a("two.example.com", 300, 2)]);
+    // Makes "two" the least recently used
+    cache.get("one.example.com", Type::A);
+    cache.insert(&[a("three.example.com", 300, 3)]);
+
+    assert!(cache.get("one.example.com", Type::A).is_some());
+    assert!(cache.get("two.example.com", Type::A).is_none());
+    assert!(cache.get("three.example.com", Type::A).is_some());
+    assert_eq!(1, cache.stats().evictions);
+}
+
+#[test]
+fn test_evicts_to_stay_within_bytes()
+{
+    let one = rr_size(&a("one.example.com", 300, 1));
+    let cache = Cache::new(CacheConfig { max_bytes: one * 2, ..Default::default() });
+    cache.insert(&[a("one.example.com", 300, 1)]);
+    cache.insert(&[a("two.example.com", 300, 2)]);
+    cache.insert(&[a("six.example.com", 300, 6)]);
+
+    assert_eq!(2, cache.len());
+    assert!(cache.get("one.example.com", Type::A).is_none());
+}
+
+#[test]
+fn test_zero_entries_disables_caching()
+{
+    let cache = Cache::new(CacheConfig { max_entries: 0, ..Default::default() });
+    cache.insert(&[a("www.example.com", 300, 1)]);
+
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_counts_hits_and_misses()
+{
+    let cache = Cache::new(CacheConfig::default());
+    cache.get("www.example.com", Type::A);
+    cache.insert(&[a("www.example.com", 300, 1), a("www.example.com", 300, 2)]);
+    cache.get("www.example.com", Type::A);
+    cache.get("www.example.com", Type::A);
+
+    assert_eq!(CacheStats { hits: 2, negative_hits: 0, misses: 1, inserts: 1, evictions: 0, expirations: 0, stale_hits: 0,
+                            prefetches: 0, synthesized: 0 },
+               cache.stats());
+}
+
+#[test]
+fn test_negative_ttl_is_lower_of_soa_ttl_and_minimum()
+{
+    let cache = Cache::new(CacheConfig::default());
+    let t0 = Instant::now();
+    cache.insert_negative_at("nope.example.com", Type::A, RespCode::NameError, &soa(3600, 60), t0);
+    cache.insert_negative_at("www.example.com", Type::PTR, RespCode::Ok, &soa(30, 60), t0);
+
+    let nx = cache.get_at("nope.example.com", Type::A, t0 + Duration::from_secs(10)).unwrap();
+    assert!(matches!(nx, Cached::NxDomain(_)));
+    assert_eq!(vec![50], ttls(&nx));
+
+    let nodata = cache.get_at("www.example.com", Type::PTR, t0).unwrap();
+    assert!(matches!(nodata, Cached::NoData(_)));
+    assert_eq!(vec![30], ttls(&nodata));
+
+    assert!(cache.get_at("nope.example.com", Type::A, t0 + Duration::from_secs(60)).is_none());
+}
+
+#[test]
+fn test_nxdomain_covers_every_type_and_nodata_one()
+{
+    let cache = Cache::new(CacheConfig::default());
+    cache.insert_negative("nope.example.com", Type::A, RespCode::NameError, &soa(300, 300));
+    cache.insert_negative("www.example.com", Type::AAAA, RespCode::Ok, &soa(300, 300));
+
+    assert!(matches!(cache.get("nope.example.com", Type::AAAA), Some(Cached::NxDomain(_))));
+    assert!(matches!(cache.get("www.example.com", Type::AAAA), Some(Cached::NoData(_))));
+    assert!(cache.get("www.example.com", Type::A).is_none());
+    assert_eq!(2, cache.stats().negative_hits);
+}
+
+#[test]
+fn test_negative_needs_soa_and_a_negative_rcode()
+{
+    let cache = Cache::new(CacheConfig::default());
+    cache.insert_negative("nope.example.com", Type::A, RespCode::NameError, &a("example.com", 300, 1));
+    cache.insert_negative("nope.example.com", Type::A, RespCode::ServFail, &soa(300, 300));
+
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_negative_answer_rebuilds_response_with_soa()
+{
+    let cache = Cache::new(CacheConfig::default());
+    let t0 = Instant::now();
+    cache.insert_negative_at("nope.example.com", Type::A, RespCode::NameError, &soa(300, 60), t0);
+
+    let query = Message::build_query(7, vec![Question {
+        qname: "nope.example.com".into(),
+        qtype: QType::A,
+        qclass: QClass::IN,
+    }]);
+    let resp = cache.get_at("nope.example.com", Type::A, t0 + Duration::from_secs(20))
+                    .unwrap()
+                    .to_response(&query);
+
+    assert!(resp.is_response_to(&query));
+    assert_eq!(RespCode::NameError, resp.header.rcode);
+    assert_eq!(None, resp.answs);
+    assert_eq!(Some(vec![soa(40, 60)]), resp.auths);
+    assert_eq!(1, resp.header.ns_count);
+}
+
+#[test]
+fn test_less_credible_data_does_not_replace_more()
+{
+    let cache = Cache::new(CacheConfig::default());
+    let t0 = Instant::now();
+    let held = |t| match cache.get_at("ns1.example.com", Type::A, t) {
+        Some(Cached::Records(rrs)) => rrs[0].rdata.clone(),
+        other => panic!("{:?}", other),
+    };
+
+    cache.insert_ranked_at(&[a("ns1.example.com", 60, 1)], Credibility::AuthAnswer, t0);
+    // Glue from a referral, then a recursive upstream's answer
+    cache.insert_ranked_at(&[a("ns1.example.com", 300, 2)], Credibility::Additional, t0);
+    cache.insert_ranked_at(&[a("ns1.example.com", 300, 3)], Credibility::Answer, t0);
+    assert_eq!(RData::A(10, 0, 0, 1), held(t0));
+
+    // As credible replaces
+    cache.insert_ranked_at(&[a("ns1.example.com", 60, 4)], Credibility::AuthAnswer, t0);
+    assert_eq!(RData::A(10, 0, 0, 4), held(t0));
+
+    // Once expired, anything may take its place
+    let t1 = t0 + Duration::from_secs(61);
+    cache.insert_ranked_at(&[a("ns1.example.com", 300, 5)], Credibility::Additional, t1);
+    assert_eq!(RData::A(10, 0, 0, 5), held(t1));
+}
+
+#[test]
+fn test_keeps_expired_entries_for_stale_window()
+{
+    let cache = Cache::new(CacheConfig { max_stale: 600, ..Default::default() });
+    let t0 = Instant::now();
+    cache.insert_at(&[a("www.example.com", 60, 1)], t0);
+    cache.insert_negative_at("nope.example.com", Type::A, RespCode::NameError, &soa(60, 60), t0);
+
+    // Expired for fresh lookups, but kept
+    let expired = t0 + Duration::from_secs(120);
+    assert!(cache.get_at("www.example.com", Type::A, expired).is_none());
+    assert_eq!(2, cache.len());
+
+    let stale = cache.get_stale_at("www.example.com", Type::A, expired).unwrap();
+    assert_eq!(vec![DEFAULT_STALE_ANSWER_TTL], ttls(&stale));
+    assert!(matches!(cache.get_stale_at("nope.example.com", Type::AAAA, expired), Some(Cached::NxDomain(_))));
+    assert_eq!(2, cache.stats().stale_hits);
+
+    // Unexpired entries keep their own TTL
+    let fresh = cache.get_stale_at("www.example.com", Type::A, t0 + Duration::from_secs(10)).unwrap();
+    assert_eq!(vec![50], ttls(&fresh));
+    assert_eq!(2, cache.stats().stale_hits);
+
+    // Past the window they are gone
+    assert!(cache.get_stale_at("www.example.com", Type::A, t0 + Duration::from_secs(660)).is_none());
+    assert_eq!(1, cache.len());
+    assert_eq!(1, cache.stats().expirations);
+}
+
+#[test]
+fn test_serves_nothing_stale_by_default()
+{
+    let cache = Cache::new(CacheConfig::default());
+    let t0 = Instant::now();
+    cache.insert_at(&[a("www.example.com", 60, 1)], t0);
+
+    assert!(cache.get_stale_at("www.example.com", Type::A, t0 + Duration::from_secs(61)).is_none());
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn test_hot_entries_become_due_late_in_their_ttl()
+{
+    let cache = Cache::new(CacheConfig { prefetch_percent: 10, prefetch_min_hits: 2, ..Default::default() });
+    let t0 = Instant::now();
+    cache.insert_at(&[a("www.example.com", 100, 1)], t0);
+    cache.insert_at(&[a("ftp.example.com", 100, 1)], t0);
+
+    // Hit often enough, but too early
+    cache.get_at("www.example.com", Type::A, t0);
+    cache.get_at("www.example.com", Type::A, t0 + Duration::from_secs(80));
+    assert!(cache.take_prefetches().is_empty());
+
+    // Within the last 10 seconds, handed out once only
+    cache.get_at("www.example.com", Type::A, t0 + Duration::from_secs(91));
+    cache.get_at("www.example.com", Type::A, t0 + Duration::from_secs(92));
+    assert_eq!(vec![("www.example.com".to_string(), Type::A)], cache.take_prefetches());
+
+    // Late, but not hit often enough
+    cache.get_at("ftp.example.com", Type::A, t0 + Duration::from_secs(95));
+    assert!(cache.take_prefetches().is_empty());
+    assert_eq!(1, cache.stats().prefetches);
+
+    // A refreshed entry starts counting again
+    cache.insert_at(&[a("www.example.com", 100, 1)], t0 + Duration::from_secs(93));
+    cache.get_at("www.example.com", Type::A, t0 + Duration::from_secs(94));
+    assert!(cache.take_prefetches().is_empty());
+}
+
+#[test]
+fn test_prefetches_nothing_by_default()
+{
+    let cache = Cache::new(CacheConfig::default());
+    let t0 = Instant::now();
+    cache.insert_at(&[a("www.example.com", 100, 1)], t0);
+
+    for _ in 0..10
+    {
+        cache.get_at("www.example.com", Type::A, t0 + Duration::from_secs(99));
+    }
+
+    assert!(cache.take_prefetches().is_empty());
+    assert_eq!(0, cache.stats().prefetches);
+}
+
+// example.com holds a and c, a wildcard beneath w, a delegation to sub and
+// x.y, which makes y an empty non-terminal
+const SIGNED_ZONE: [(&str, &[Type]); 6] = [
+    ("example.com", &[Type::SOA, Type::NS]),
+    ("a.example.com", &[Type::A]),
+    ("c.example.com", &[Type::A]),
+    ("sub.example.com", &[Type::NS]),
+    ("*.w.example.com", &[Type::A]),
+    ("x.y.example.com", &[Type::A]),
+];
+
+fn bitmap(types: &[Type]) -> Vec<u16>
+{
+    types.iter().map(|t| *t as u16).collect()
+}
+
+// EFFECTS: Builds the NSEC chain of SIGNED_ZONE, each record leading to
+//          the next owner in canonical order and the last back to the apex
+fn nsec_chain() -> Vec<ResourceRecord>
+{
+    let mut names = SIGNED_ZONE.to_vec();
+    names.sort_by(|a, b| nsec::canonical_cmp(a.0, b.0));
+
+    (0..names.len()).map(|i| {
+        let (owner, types) = names[i];
+        let next = names[(i + 1) % names.len()].0;
+        ResourceRecord::new(owner, 300, RData::NSEC { next: next.into(), types: bitmap(types) })
+    }).collect()
+}
+
+// EFFECTS: Builds the NSEC3 chain of SIGNED_ZONE, empty non-terminals
+//          included, with flags set on each record
+fn nsec3_chain(flags: u8) -> Vec<ResourceRecord>
+{
+    let salt = vec![0xaa, 0xbb];
+    let mut hashes: Vec<(Vec<u8>, &[Type])> = SIGNED_ZONE.iter()
+        .cloned()
+        .chain(vec![("w.example.com", &[][..]), ("y.example.com", &[][..])])
+        .map(|(name, types)| (nsec::nsec3_hash(name, &salt, 5).unwrap(), types))
+        .collect();
+    hashes.sort_by(|a, b| a.0.cmp(&b.0));
+
+    (0..hashes.len()).map(|i| {
+        let (hash, types) = &hashes[i];
+        let owner = format!("{}.example.com", nsec::base32hex_encode(hash));
+        ResourceRecord::new(&owner, 300, RData::NSEC3 {
+            hash_alg: NSEC3_SHA1,
+            flags,
+            iterations: 5,
+            salt: salt.clone(),
+            next_hashed: hashes[(i + 1) % hashes.len()].0.clone(),
+            types: bitmap(types),
+        })
+    }).collect()
+}
+
+// EFFECTS: Returns the rcode of a negative answer, or None for no answer or
+//          records
+fn denial(answer: Option<Cached>) -> Option<RespCode>
+{
+    match answer
+    {
+        Some(Cached::NxDomain(_)) => Some(RespCode::NameError),
+        Some(Cached::NoData(_)) => Some(RespCode::Ok),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_synthesizes_negative_answers_from_validated_nsec()
+{
+    let cache = Cache::new(CacheConfig::default());
+    let t0 = Instant::now();
+    cache.insert_denial_at(&soa(3600, 600), &nsec_chain(), true, t0);
+    let get = |name, t| denial(cache.get_at(name, t, t0 + Duration::from_secs(10)));
+
+    assert_eq!(Some(RespCode::NameError), get("b.example.com", Type::A));
+    assert_eq!(Some(RespCode::NameError), get("zzz.example.com", Type::AAAA));
+    assert_eq!(Some(RespCode::NameError), get("deep.b.example.com", Type::A));
+    assert_eq!(Some(RespCode::Ok), get("a.example.com", Type::AAAA));
+    assert_eq!(Some(RespCode::Ok), get("y.example.com", Type::A));
+
+    // Names that exist, may match the wildcard or lie in the child zone
+    assert_eq!(None, get("a.example.com", Type::A));
+    assert_eq!(None, get("q.w.example.com", Type::A));
+    assert_eq!(None, get("host.sub.example.com", Type::A));
+    assert_eq!(None, get("www.example.net", Type::A));
+
+    let answer = cache.get_at("b.example.com", Type::A, t0 + Duration::from_secs(10)).unwrap();
+    assert_eq!(vec![290], ttls(&answer));
+    assert_eq!(6, cache.stats().synthesized);
+    assert_eq!(None, denial(cache.get_at("b.example.com", Type::A, t0 + Duration::from_secs(300))));
+}
+
+#[test]
+fn test_synthesizes_only_from_validated_proofs()
+{
+    let cache = Cache::new(CacheConfig::default());
+    let t0 = Instant::now();
+    let get = || denial(cache.get_at("b.example.com", Type::A, t0));
+
+    cache.insert_denial_at(&soa(3600, 600), &nsec_chain(), false, t0);
+    assert_eq!(None, get());
+
+    cache.insert_denial_at(&soa(3600, 600), &nsec_chain(), true, t0);
+    assert_eq!(Some(RespCode::NameError), get());
+
+    // An unvalidated copy doesn't displace the validated proof
+    cache.insert_denial_at(&soa(3600, 600), &nsec_chain(), false, t0);
+    assert_eq!(Some(RespCode::NameError), get());
+
+    let cache = Cache::new(CacheConfig { aggressive_nsec: false, ..Default::default() });
+    cache.insert_denial_at(&soa(3600, 600), &nsec_chain(), true, t0);
+    assert_eq!(None, denial(cache.get_at("b.example.com", Type::A, t0)));
+}
+
+#[test]
+fn test_synthesizes_negative_answers_from_validated_nsec3()
+{
+    let cache = Cache::new(CacheConfig::default());
+    let t0 = Instant::now();
+    cache.insert_denial_at(&soa(3600, 600), &nsec3_chain(0), true, t0);
+    let get = |name, t| denial(cache.get_at(name, t, t0));
+
+    assert_eq!(Some(RespCode::NameError), get("b.example.com", Type::A));
+    assert_eq!(Some(RespCode::NameError), get("p.q.b.example.com", Type::A));
+    assert_eq!(Some(RespCode::Ok), get("a.example.com", Type::AAAA));
+    assert_eq!(Some(RespCode::Ok), get("y.example.com", Type::A));
+    assert_eq!(None, get("a.example.com", Type::A));
+    assert_eq!(None, get("q.w.example.com", Type::A));
+    assert_eq!(None, get("host.sub.example.com", Type::A));
+
+    // Opt-out spans may hide unsigned delegations, so prove nothing
+    let cache = Cache::new(CacheConfig::default());
+    cache.insert_denial_at(&soa(3600, 600), &nsec3_chain(NSEC3_OPT_OUT), true, t0);
+    assert_eq!(None, denial(cache.get_at("b.example.com", Type::A, t0)));
+    assert_eq!(Some(RespCode::Ok), denial(cache.get_at("a.example.com", Type::AAAA, t0)));
+}
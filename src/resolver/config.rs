@@ -0,0 +1,245 @@
+use std::error::Error;
+use std::fs;
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
+use std::path::{ Path, PathBuf };
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::dns;
+
+use super::{ cache::CacheConfig, hosts::HOSTS_FILE, DNS_PORT };
+
+#[cfg(test)]
+mod tests;
+
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+// Defaults and caps follow glibc's resolver (resolv/resolv.h)
+const MAXNS: usize = 3;
+const MAXDNSRCH: usize = 6;
+const MAX_NDOTS: u8 = 15;
+const MAX_TIMEOUT: u64 = 30;
+pub const MAX_ATTEMPTS: u8 = 5;
+const DEFAULT_NDOTS: u8 = 1;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_ATTEMPTS: u8 = 2;
+pub const DEFAULT_CNAME_DEPTH: usize = 8;
+
+// ------------- Family Preference -------------
+// Which address families a lookup by hostname asks for. Both and the
+// preferences query A and AAAA records concurrently; a preference answers
+// with the other family only when the preferred one has no addresses
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FamilyPreference
+{
+    Ipv4Only,
+    Ipv6Only,
+    // Every address, IPv4 first
+    #[default]
+    Both,
+    PreferIpv4,
+    PreferIpv6,
+}
+
+impl FromStr for FamilyPreference
+{
+    type Err = String;
+
+    // EFFECTS: Parses "ipv4", "ipv6", "both", "prefer-ipv4" or "prefer-ipv6"
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        match s
+        {
+            "ipv4" => Ok(FamilyPreference::Ipv4Only),
+            "ipv6" => Ok(FamilyPreference::Ipv6Only),
+            "both" => Ok(FamilyPreference::Both),
+            "prefer-ipv4" => Ok(FamilyPreference::PreferIpv4),
+            "prefer-ipv6" => Ok(FamilyPreference::PreferIpv6),
+            _ => Err(format!("Unknown address family preference '{}'", s)),
+        }
+    }
+}
+
+// ------------- Resolver Config -------------
+// Upstream servers and lookup options, as found in resolv.conf(5), plus the
+// hosts(5) file consulted before any of them (None to skip it), the
+// number of CNAMEs a lookup may follow, the bounds of the answer cache,
+// the address families wanted when resolving a hostname and whether query
+// names are sent in random case
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResolverConfig
+{
+    pub hosts_file: Option<PathBuf>,
+    pub nameservers: Vec<SocketAddr>,
+    pub search: Vec<String>,
+    pub ndots: u8,
+    pub timeout: Duration,
+    pub attempts: u8,
+    pub rotate: bool,
+    pub edns0: bool,
+    // Query over TCP only ("use-vc")
+    pub tcp: bool,
+    pub max_cname_depth: usize,
+    pub cache: CacheConfig,
+    pub family: FamilyPreference,
+    // Randomize the case of query names and require responses to echo it
+    // ("0x20"), as extra entropy against forged responses
+    pub randomize_case: bool,
+}
+
+impl Default for ResolverConfig
+{
+    // Matches glibc when resolv.conf is missing: ask a server on localhost
+    fn default() -> Self
+    {
+        ResolverConfig {
+            hosts_file: Some(PathBuf::from(HOSTS_FILE)),
+            nameservers: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DNS_PORT)],
+            search: Vec::new(),
+            ndots: DEFAULT_NDOTS,
+            timeout: DEFAULT_TIMEOUT,
+            attempts: DEFAULT_ATTEMPTS,
+            rotate: false,
+            edns0: false,
+            tcp: false,
+            max_cname_depth: DEFAULT_CNAME_DEPTH,
+            cache: CacheConfig::default(),
+            family: FamilyPreference::default(),
+            randomize_case: false,
+        }
+    }
+}
+
+impl ResolverConfig
+{
+    // EFFECTS: Reads the system resolv.conf, falling back to the defaults when
+    //          it cannot be read
+    pub fn system() -> Self
+    {
+        ResolverConfig::from_file(RESOLV_CONF).unwrap_or_default()
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error + Send + Sync>>
+    {
+        let contents = fs::read_to_string(path)?;
+
+        Ok(ResolverConfig::parse(&contents))
+    }
+
+    // EFFECTS: Parses resolv.conf contents. Like glibc, unknown keywords and
+    //          malformed values are ignored, "search" and "domain" replace
+    //          each other (the last one wins) and numeric options are capped
+    pub fn parse(contents: &str) -> Self
+    {
+        let mut conf = ResolverConfig { nameservers: Vec::new(), ..Default::default() };
+
+        for line in contents.lines()
+        {
+            let line = match line.find(['#', ';'])
+            {
+                Some(i) => &line[..i],
+                None => line,
+            };
+            let mut words = line.split_whitespace();
+
+            match words.next()
+            {
+                Some("nameserver") => {
+                    if let Some(ns) = words.next().and_then(parse_nameserver)
+                    {
+                        if conf.nameservers.len() < MAXNS
+                        {
+                            conf.nameservers.push(ns);
+                        }
+                    }
+                },
+                Some("domain") => {
+                    if let Some(d) = words.next()
+                    {
+                        conf.search = vec![d.to_string()];
+                    }
+                },
+                Some("search") => {
+                    conf.search = words.take(MAXDNSRCH).map(String::from).collect();
+                },
+                Some("options") => {
+                    for opt in words
+                    {
+                        conf.set_option(opt);
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        if conf.nameservers.is_empty()
+        {
+            conf.nameservers = ResolverConfig::default().nameservers;
+        }
+
+        conf
+    }
+
+    // EFFECTS: Applies a single resolv.conf option such as "ndots:2" or
+    //          "rotate" ("tcp" is accepted as an alias for "use-vc").
+    //          Returns false if the option was not recognised
+    pub fn set_option(&mut self, opt: &str) -> bool
+    {
+        let (name, value) = match opt.find(':')
+        {
+            Some(i) => (&opt[..i], Some(&opt[i + 1..])),
+            None => (opt, None),
+        };
+        let num = value.and_then(|v| v.parse::<u64>().ok());
+
+        match (name, num)
+        {
+            ("ndots", Some(n)) => self.ndots = n.min(MAX_NDOTS.into()) as u8,
+            ("timeout", Some(n)) => self.timeout = Duration::from_secs(n.clamp(1, MAX_TIMEOUT)),
+            ("attempts", Some(n)) => self.attempts = n.clamp(1, MAX_ATTEMPTS.into()) as u8,
+            ("rotate", None) => self.rotate = true,
+            ("edns0", None) => self.edns0 = true,
+            ("use-vc", None) | ("tcp", None) => self.tcp = true,
+            _ => return false,
+        }
+
+        true
+    }
+
+    // EFFECTS: Returns the names to query for name, in order, as glibc's
+    //          res_nsearch tries them. A name ending in a dot is fully
+    //          qualified and tried alone. One with at least ndots dots is
+    //          tried as given before each search domain is appended to it,
+    //          and one with fewer after
+    pub fn search_names(&self, name: &str) -> Vec<String>
+    {
+        if name.ends_with('.')
+        {
+            return vec![dns::trim_root(name).to_string()];
+        }
+
+        let mut names: Vec<String> = self.search
+                                         .iter()
+                                         .map(|domain| format!("{}.{}", name, dns::trim_root(domain)))
+                                         .collect();
+
+        if name.matches('.').count() >= usize::from(self.ndots)
+        {
+            names.insert(0, name.to_string());
+        } else {
+            names.push(name.to_string());
+        }
+
+        names
+    }
+}
+
+// EFFECTS: Parses "1.2.3.4", "::1", "1.2.3.4:5353" or "[::1]:5353", using
+//          the DNS port when none is given
+pub fn parse_nameserver(s: &str) -> Option<SocketAddr>
+{
+    s.parse::<IpAddr>()
+     .map(|ip| SocketAddr::new(ip, DNS_PORT))
+     .or_else(|_| s.parse::<SocketAddr>())
+     .ok()
+}
@@ -0,0 +1,125 @@
+use super::*;
+
+use std::net::Ipv6Addr;
+
+fn ns(ip: IpAddr) -> SocketAddr
+{
+    SocketAddr::new(ip, DNS_PORT)
+}
+
+#[test]
+fn test_parse_full_resolv_conf()
+{
+    let conf = ResolverConfig::parse("\
+# generated by NetworkManager
+search corp.example.com example.com
+nameserver 10.0.0.2
+nameserver 2001:db8::53 ; secondary
+options ndots:2 timeout:3 attempts:4 rotate edns0 use-vc
+");
+
+    assert_eq!(ResolverConfig {
+        hosts_file: Some(PathBuf::from(HOSTS_FILE)),
+        nameservers: vec![
+            ns(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))),
+            ns(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x53))),
+        ],
+        search: vec!["corp.example.com".to_string(), "example.com".to_string()],
+        ndots: 2,
+        timeout: Duration::from_secs(3),
+        attempts: 4,
+        rotate: true,
+        edns0: true,
+        tcp: true,
+        max_cname_depth: DEFAULT_CNAME_DEPTH,
+        cache: CacheConfig::default(),
+        family: FamilyPreference::Both,
+        randomize_case: false,
+    }, conf);
+}
+
+#[test]
+fn test_parse_empty_uses_defaults()
+{
+    assert_eq!(ResolverConfig::default(), ResolverConfig::parse(""));
+}
+
+#[test]
+fn test_last_of_domain_and_search_wins()
+{
+    let conf = ResolverConfig::parse("search a.example b.example\ndomain c.example\n");
+    assert_eq!(vec!["c.example".to_string()], conf.search);
+
+    let conf = ResolverConfig::parse("domain c.example\nsearch a.example b.example\n");
+    assert_eq!(vec!["a.example".to_string(), "b.example".to_string()], conf.search);
+}
+
+#[test]
+fn test_caps_and_ignores_malformed_values()
+{
+    let conf = ResolverConfig::parse("\
+nameserver 10.0.0.1
+nameserver not-an-address
+nameserver 10.0.0.2
+nameserver 10.0.0.3
+nameserver 10.0.0.4
+options ndots:99 timeout:600 attempts:0 ndots:bogus frobnicate
+sortlist 130.155.160.0/255.255.240.0
+");
+
+    assert_eq!(MAXNS, conf.nameservers.len());
+    assert_eq!(ns(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3))), conf.nameservers[2]);
+    assert_eq!(MAX_NDOTS, conf.ndots);
+    assert_eq!(Duration::from_secs(MAX_TIMEOUT), conf.timeout);
+    assert_eq!(1, conf.attempts);
+}
+
+#[test]
+fn test_set_option_reports_unknown()
+{
+    let mut conf = ResolverConfig::default();
+
+    assert!(conf.set_option("rotate"));
+    assert!(conf.set_option("ndots:3"));
+    assert!(!conf.set_option("rotate:1"));
+    assert!(!conf.set_option("inet6"));
+    assert!(conf.rotate);
+    assert_eq!(3, conf.ndots);
+}
+
+#[test]
+fn test_parse_nameserver_forms()
+{
+    assert_eq!(Some(ns(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)))), parse_nameserver("1.1.1.1"));
+    assert_eq!(Some(ns(IpAddr::V6(Ipv6Addr::LOCALHOST))), parse_nameserver("::1"));
+    assert_eq!(Some("127.0.0.1:5353".parse().unwrap()), parse_nameserver("127.0.0.1:5353"));
+    assert_eq!(Some("[::1]:5353".parse().unwrap()), parse_nameserver("[::1]:5353"));
+    assert_eq!(None, parse_nameserver("localhost"));
+}
+
+#[test]
+fn test_parse_family_preference()
+{
+    assert_eq!(Ok(FamilyPreference::Ipv4Only), "ipv4".parse());
+    assert_eq!(Ok(FamilyPreference::Ipv6Only), "ipv6".parse());
+    assert_eq!(Ok(FamilyPreference::Both), "both".parse());
+    assert_eq!(Ok(FamilyPreference::PreferIpv4), "prefer-ipv4".parse());
+    assert_eq!(Ok(FamilyPreference::PreferIpv6), "prefer-ipv6".parse());
+    assert!("ipv5".parse::<FamilyPreference>().is_err());
+}
+
+#[test]
+fn test_search_names_follow_ndots()
+{
+    let conf = ResolverConfig::parse("search corp.example.com example.com.\noptions ndots:2\n");
+
+    // Fewer dots than ndots: the search domains come first
+    assert_eq!(vec!["db01.corp.example.com", "db01.example.com", "db01"], conf.search_names("db01"));
+    assert_eq!(vec!["db01.eu.corp.example.com", "db01.eu.example.com", "db01.eu"], conf.search_names("db01.eu"));
+    // As many or more: the name as given comes first
+    assert_eq!(vec!["www.example.org", "www.example.org.corp.example.com", "www.example.org.example.com"],
+               conf.search_names("www.example.org"));
+    // A trailing dot marks the name as fully qualified
+    assert_eq!(vec!["db01"], conf.search_names("db01."));
+    assert_eq!(vec!["db01"], ResolverConfig::parse("options ndots:2\n").search_names("db01"));
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::dns;
+
+#[cfg(test)]
+mod tests;
+
+pub const HOSTS_FILE: &str = "/etc/hosts";
+
+// ------------- Hosts -------------
+// Static name to address mappings from a hosts(5) file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Hosts
+{
+    // Keyed on the lowercased name without a trailing dot
+    by_name: HashMap<String, Vec<IpAddr>>,
+    by_addr: HashMap<IpAddr, Vec<String>>,
+}
+
+impl Hosts
+{
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error + Send + Sync>>
+    {
+        let contents = fs::read_to_string(path)?;
+
+        Ok(Hosts::parse(&contents))
+    }
+
+    // EFFECTS: Parses "<address> <canonical name> [aliases...]" lines. Comments
+    //          start with '#' and lines with an invalid address are skipped
+    pub fn parse(contents: &str) -> Self
+    {
+        let mut hosts = Hosts::default();
+
+        for line in contents.lines()
+        {
+            let line = match line.find('#')
+            {
+                Some(i) => &line[..i],
+                None => line,
+            };
+            let mut words = line.split_whitespace();
+
+            let ip = match words.next().and_then(|w| w.parse::<IpAddr>().ok())
+            {
+                Some(ip) => ip,
+                None => continue,
+            };
+
+            for name in words
+            {
+                let name = dns::trim_root(name);
+                let key = name.to_ascii_lowercase();
+
+                let addrs = hosts.by_name.entry(key).or_default();
+                if !addrs.contains(&ip)
+                {
+                    addrs.push(ip);
+                }
+
+                let names = hosts.by_addr.entry(ip).or_default();
+                if !names.iter().any(|n| dns::name_eq(n, name))
+                {
+                    names.push(name.to_string());
+                }
+            }
+        }
+
+        hosts
+    }
+
+    // EFFECTS: Returns the addresses listed for name, in file order
+    pub fn lookup(&self, name: &str) -> Option<&[IpAddr]>
+    {
+        self.by_name
+            .get(&dns::trim_root(name).to_ascii_lowercase())
+            .map(Vec::as_slice)
+    }
+
+    // EFFECTS: Returns the names listed for ip; the first is the canonical name
+    pub fn reverse(&self, ip: IpAddr) -> Option<&[String]>
+    {
+        self.by_addr
+            .get(&ip)
+            .map(Vec::as_slice)
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.by_name.is_empty()
+    }
+}
@@ -0,0 +1,74 @@
+use super::*;
+
+use std::net::{ Ipv4Addr, Ipv6Addr };
+
+const HOSTS: &str = "\
+127.0.0.1   localhost
+::1         localhost ip6-localhost ip6-loopback
+# a comment line
+10.0.0.5    db01.corp.example.com db01   # trailing comment
+10.0.0.6    DB01.corp.example.com.
+2001:db8::5 db01.corp.example.com
+not-an-ip   bogus.example.com
+10.0.0.7
+";
+
+fn v4(a: u8, b: u8, c: u8, d: u8) -> IpAddr
+{
+    IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+}
+
+#[test]
+fn test_lookup_v4_and_v6()
+{
+    let hosts = Hosts::parse(HOSTS);
+
+    assert_eq!(Some(&[v4(127, 0, 0, 1), IpAddr::V6(Ipv6Addr::LOCALHOST)][..]),
+               hosts.lookup("localhost"));
+    assert_eq!(Some(&[IpAddr::V6(Ipv6Addr::LOCALHOST)][..]),
+               hosts.lookup("ip6-loopback"));
+}
+
+#[test]
+fn test_lookup_aliases_ignore_case_and_root_dot()
+{
+    let hosts = Hosts::parse(HOSTS);
+    let expected = [
+        v4(10, 0, 0, 5),
+        v4(10, 0, 0, 6),
+        IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 5)),
+    ];
+
+    assert_eq!(Some(&expected[..]), hosts.lookup("db01.corp.example.com"));
+    assert_eq!(Some(&expected[..]), hosts.lookup("Db01.Corp.Example.Com."));
+    assert_eq!(Some(&[v4(10, 0, 0, 5)][..]), hosts.lookup("db01"));
+}
+
+#[test]
+fn test_skips_invalid_lines()
+{
+    let hosts = Hosts::parse(HOSTS);
+
+    assert_eq!(None, hosts.lookup("bogus.example.com"));
+    assert_eq!(None, hosts.reverse(v4(10, 0, 0, 7)));
+    assert_eq!(None, hosts.lookup("a"));
+}
+
+#[test]
+fn test_reverse_returns_canonical_name_first()
+{
+    let hosts = Hosts::parse(HOSTS);
+
+    assert_eq!(Some(&["db01.corp.example.com".to_string(), "db01".to_string()][..]),
+               hosts.reverse(v4(10, 0, 0, 5)));
+    assert_eq!(Some(&["DB01.corp.example.com".to_string()][..]),
+               hosts.reverse(v4(10, 0, 0, 6)));
+    assert_eq!(Some(&["localhost".to_string(), "ip6-localhost".to_string(), "ip6-loopback".to_string()][..]),
+               hosts.reverse(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+}
+
+#[test]
+fn test_from_missing_file_is_an_error()
+{
+    assert!(Hosts::from_file("/nonexistent/rresolve/hosts").is_err());
+}
@@ -0,0 +1,585 @@
+use std::cell::Cell;
+use std::error::Error;
+use std::net::{ IpAddr, SocketAddr };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ Duration, Instant };
+
+use crate::dns::{
+    self, QType, QClass, Type,
+    header::RespCode,
+    message::Message,
+    question::Question,
+    resourcerecord::{ RData, ResourceRecord },
+};
+
+use super::{
+    cache::{ Cache, CacheConfig, Cached, Credibility },
+    config::DEFAULT_CNAME_DEPTH,
+    limits::{ Limit, LimitExceeded },
+    lookup::Lookup,
+    roothints::ROOT_SERVERS,
+    srtt::{ ServerStats, Srtt },
+    transport::{ Transport, UdpTransport },
+    DNS_PORT,
+};
+
+#[cfg(test)]
+mod tests;
+
+// Bounds on the work of one lookup, against zones crafted to make a
+// resolver send floods of queries (NXNSAttack and the like)
+const MAX_QUERIES: usize = 100;
+const MAX_REFERRALS: usize = 16;
+const MAX_GLUELESS_NAMES: usize = 8;
+const MAX_GLUELESS_DEPTH: usize = 4;
+const LOOKUP_DEADLINE: Duration = Duration::from_secs(30);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// MAX_MINIMISE_COUNT of RFC 9156 section 2.3
+const MAX_MINIMISE_QUERIES: usize = 10;
+
+// Outcome of asking one server during iterative resolution
+#[derive(Debug, PartialEq)]
+enum Step
+{
+    // An answer, NXDOMAIN or an authoritative empty response
+    Done,
+    // A delegation to a child zone and the names of its nameservers
+    Referral(String, Vec<String>),
+    // Neither an answer nor a usable referral
+    Lame,
+}
+
+// One query sent during iterative resolution, as recorded by a trace
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceStep
+{
+    // How many glueless nameserver lookups deep the query was, 0 for the
+    // name being resolved
+    pub depth: usize,
+    // The zone the server was asked as a nameserver for, "" for the root
+    pub zone: String,
+    pub server: SocketAddr,
+    pub qname: String,
+    pub qtype: QType,
+    // Time until the response arrived or the exchange failed
+    pub rtt: Duration,
+    pub outcome: TraceOutcome,
+}
+
+// What came back for a traced query
+#[derive(Clone, Debug, PartialEq)]
+pub enum TraceOutcome
+{
+    // An answer, NXDOMAIN or an authoritative empty response, and the
+    // records of its answer section
+    Answer(RespCode, Vec<ResourceRecord>),
+    // A delegation to zone, served by nameservers, with the glue records
+    // for them that the referral carried
+    Referral { zone: String, nameservers: Vec<String>, glue: Vec<ResourceRecord> },
+    // A response that was neither an answer nor a usable referral
+    Lame(RespCode),
+    // No response: a timeout or a transport error
+    Failed(String),
+}
+
+// The queries a lookup sent, in order, and how the lookup ended
+#[derive(Debug)]
+pub struct Trace
+{
+    pub steps: Vec<TraceStep>,
+    pub lookup: Result<Lookup, Box<dyn Error + Send + Sync>>,
+}
+
+// The work one lookup has done so far, held against the limits of the
+// resolver doing it
+struct Budget
+{
+    qname: String,
+    queries: Cell<usize>,
+    glueless_names: Cell<usize>,
+    deadline: Instant,
+}
+
+impl Budget
+{
+    // EFFECTS: Returns the error of a lookup stopped by limit
+    fn exceeded(&self, limit: Limit) -> Box<dyn Error + Send + Sync>
+    {
+        LimitExceeded { limit, qname: self.qname.clone() }.into()
+    }
+}
+
+// ------------- Iterative Resolver -------------
+// Resolves names by starting at the root servers and following NS referrals
+// until an authoritative server answers, asking the fastest of each zone's
+// servers first. Queries go over UDP unless another transport is set.
+//
+// With qname_minimisation (RFC 9156) each server is asked for an A record
+// of only one label more than the zone it serves, until the full name is
+// reached or max_minimise_queries such queries have been sent. A server
+// that answers those with NXDOMAIN or not at all is asked for the full name
+// instead, as the relaxed mode of section 2.3 allows.
+//
+// Each lookup is bounded in the queries it sends, the referrals it follows
+// for one name, the CNAMEs it follows, the nameservers it resolves for want
+// of glue (and how deeply such lookups nest) and its time overall. Going
+// past any of them fails the lookup with a LimitExceeded
+#[derive(Clone, Debug)]
+pub struct IterativeResolver
+{
+    pub roots: Vec<IpAddr>,
+    pub port: u16,
+    pub timeout: Duration,
+    pub max_queries: usize,
+    pub max_referrals: usize,
+    pub max_cname_depth: usize,
+    pub max_glueless_names: usize,
+    pub max_glueless_depth: usize,
+    pub lookup_deadline: Duration,
+    pub qname_minimisation: bool,
+    pub max_minimise_queries: usize,
+    pub transport: Arc<dyn Transport>,
+    pub srtt: Arc<Srtt>,
+    pub cache: Arc<Cache>,
+    rejected: Arc<AtomicU64>,
+    // Where queries are recorded while a trace is running
+    trace: Option<Arc<Mutex<Vec<TraceStep>>>>,
+}
+
+impl Default for IterativeResolver
+{
+    fn default() -> Self
+    {
+        IterativeResolver::with_roots(ROOT_SERVERS.to_vec(), DNS_PORT)
+    }
+}
+
+impl IterativeResolver
+{
+    // EFFECTS: Creates a resolver which starts from the given root hints. All
+    //          servers (including those learned from glue) are queried on port
+    pub fn with_roots(roots: Vec<IpAddr>, port: u16) -> Self
+    {
+        let rejected = Arc::new(AtomicU64::new(0));
+
+        IterativeResolver {
+            roots,
+            port,
+            timeout: QUERY_TIMEOUT,
+            max_queries: MAX_QUERIES,
+            max_referrals: MAX_REFERRALS,
+            max_cname_depth: DEFAULT_CNAME_DEPTH,
+            max_glueless_names: MAX_GLUELESS_NAMES,
+            max_glueless_depth: MAX_GLUELESS_DEPTH,
+            lookup_deadline: LOOKUP_DEADLINE,
+            qname_minimisation: false,
+            max_minimise_queries: MAX_MINIMISE_QUERIES,
+            transport: Arc::new(UdpTransport { rejected: rejected.clone() }),
+            srtt: Arc::new(Srtt::default()),
+            cache: Arc::new(Cache::new(CacheConfig::default())),
+            rejected,
+            trace: None,
+        }
+    }
+
+    // EFFECTS: Returns how many datagrams were discarded for not matching an
+    //          outstanding query
+    pub fn rejected_responses(&self) -> u64
+    {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    // EFFECTS: Returns the round-trip statistics of each server queried,
+    //          fastest first
+    pub fn server_stats(&self) -> Vec<(SocketAddr, ServerStats)>
+    {
+        self.srtt.stats()
+    }
+
+    // EFFECTS: Resolves qname/qtype from the root down and returns the final
+    //          response from the authoritative server
+    pub fn resolve(&self, qname: &str, qtype: QType) -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        self.resolve_at_depth(qname, qtype, 0, &self.budget(qname))
+    }
+
+    // EFFECTS: Resolves qname/qtype, starting again from the root for each
+    //          alias whose target the authoritative answer does not cover
+    pub fn lookup(&self, qname: &str, qtype: QType) -> Result<Lookup, Box<dyn Error + Send + Sync>>
+    {
+        let mut lookup = Lookup::new(qname, qtype);
+        let budget = self.budget(qname);
+
+        loop
+        {
+            let resp = self.resolve_at_depth(lookup.canonical_name(), qtype, 0, &budget)?;
+
+            if lookup.absorb(&resp, self.max_cname_depth)?
+            {
+                return Ok(lookup);
+            }
+        }
+    }
+
+    // EFFECTS: Looks up qname/qtype as lookup does, recording every query
+    //          sent on the way, whether or not the lookup succeeds
+    pub fn trace(&self, qname: &str, qtype: QType) -> Trace
+    {
+        let steps = Arc::new(Mutex::new(Vec::new()));
+        let tracer = IterativeResolver { trace: Some(steps.clone()), ..self.clone() };
+        let lookup = tracer.lookup(qname, qtype);
+
+        let steps = std::mem::take(&mut *steps.lock().unwrap());
+        Trace { steps, lookup }
+    }
+
+    // EFFECTS: Returns a fresh budget for a lookup of qname
+    fn budget(&self, qname: &str) -> Budget
+    {
+        Budget {
+            qname: qname.to_string(),
+            queries: Cell::new(0),
+            glueless_names: Cell::new(0),
+            deadline: Instant::now() + self.lookup_deadline,
+        }
+    }
+
+    // EFFECTS: Counts a query against budget and returns how long it may
+    //          wait for a response, failing if the lookup is out of
+    //          queries or time
+    fn spend_query(&self, budget: &Budget) -> Result<Duration, Box<dyn Error + Send + Sync>>
+    {
+        let left = budget.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero()
+        {
+            return Err(budget.exceeded(Limit::Deadline(self.lookup_deadline)));
+        }
+        if budget.queries.get() >= self.max_queries
+        {
+            return Err(budget.exceeded(Limit::Queries(self.max_queries)));
+        }
+        budget.queries.set(budget.queries.get() + 1);
+
+        Ok(self.timeout.min(left))
+    }
+
+    fn resolve_at_depth(&self, qname: &str, qtype: QType, depth: usize, budget: &Budget)
+                        -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        let mut zone = String::new();
+        let mut servers = self.roots.clone();
+        let mut referrals = 0;
+
+        // The deepest name asked about so far that proved to exist, from
+        // which minimised queries reveal one more label
+        let mut known = String::new();
+        let mut minimise = self.qname_minimisation;
+        let mut minimised = 0;
+
+        loop
+        {
+            let next = one_more_label(qname, &known);
+            let (resp, step) = if minimise && minimised < self.max_minimise_queries && !dns::name_eq(&next, qname)
+            {
+                minimised += 1;
+
+                match self.ask(&servers, &zone, &next, QType::A, depth, budget)
+                {
+                    Ok((resp, step @ Step::Referral(..))) => (resp, step),
+                    Ok((resp, Step::Done)) if resp.header.rcode == RespCode::Ok => {
+                        // No zone cut here; the next query reveals another label
+                        known = next;
+                        continue;
+                    },
+                    Err(e) if e.is::<LimitExceeded>() => return Err(e),
+                    _ => {
+                        minimise = false;
+                        continue;
+                    },
+                }
+            } else {
+                self.ask(&servers, &zone, qname, qtype, depth, budget)?
+            };
+
+            match step
+            {
+                Step::Referral(child, ns_names) => {
+                    if referrals == self.max_referrals
+                    {
+                        return Err(budget.exceeded(Limit::Referrals(self.max_referrals)));
+                    }
+                    referrals += 1;
+
+                    servers = self.nameserver_addrs(&resp, &ns_names, depth, budget)?;
+                    known = child.clone();
+                    zone = child;
+                },
+                _ => return Ok(resp),
+            }
+        }
+    }
+
+    // EFFECTS: Queries servers, fastest first by srtt, until one gives an
+    //          answer or a referral, tracing each query at depth and
+    //          spending budget on it
+    fn ask(&self, servers: &[IpAddr], zone: &str, qname: &str, qtype: QType, depth: usize, budget: &Budget)
+           -> Result<(Message, Step), Box<dyn Error + Send + Sync>>
+    {
+        let mut last_err: Box<dyn Error + Send + Sync> = format!("No nameservers for zone '{}'", zone).into();
+        let addrs: Vec<SocketAddr> = servers.iter().map(|ip| SocketAddr::new(*ip, self.port)).collect();
+
+        for server in self.srtt.order(&addrs)
+        {
+            let q = Message::build_query(rand::random(), vec![
+                Question {
+                    qname: qname.to_string(),
+                    qtype,
+                    qclass: QClass::IN,
+                },
+            ]);
+
+            let timeout = self.spend_query(budget)?;
+            let sent = Instant::now();
+            let result = self.transport.exchange(&q, server, timeout);
+            let rtt = sent.elapsed();
+            let record = |outcome| self.record(TraceStep {
+                depth,
+                zone: zone.to_string(),
+                server,
+                qname: qname.to_string(),
+                qtype,
+                rtt,
+                outcome,
+            });
+
+            match result
+            {
+                Ok(resp) => {
+                    let resp = scrub(resp, zone);
+                    self.remember(&resp);
+
+                    let step = classify(&resp, qname, zone);
+                    record(traced(&resp, &step));
+
+                    match step
+                    {
+                        // However quick, a lame server mustn't be tried first
+                        Step::Lame => {
+                            self.srtt.record_failure(server, timeout);
+                            last_err = format!("Lame response from {} for zone '{}'", server.ip(), zone).into();
+                        },
+                        step => {
+                            self.srtt.record_response(server, rtt);
+                            return Ok((resp, step));
+                        },
+                    }
+                },
+                Err(e) => {
+                    self.srtt.record_failure(server, timeout);
+                    record(TraceOutcome::Failed(e.to_string()));
+                    last_err = e;
+                },
+            }
+        }
+
+        Err(last_err)
+    }
+
+    // EFFECTS: Appends step to the running trace, if any
+    fn record(&self, step: TraceStep)
+    {
+        if let Some(trace) = &self.trace
+        {
+            trace.lock().unwrap().push(step);
+        }
+    }
+
+    // EFFECTS: Caches the records of resp, a response scrubbed to the
+    //          bailiwick of its server, as credible as their sections are
+    fn remember(&self, resp: &Message)
+    {
+        let (answs, auths) = if resp.header.auth_answ
+        {
+            (Credibility::AuthAnswer, Credibility::AuthAuthority)
+        } else {
+            (Credibility::Answer, Credibility::Additional)
+        };
+
+        self.cache.insert_ranked(resp.answs.as_deref().unwrap_or_default(), answs);
+        self.cache.insert_ranked(resp.auths.as_deref().unwrap_or_default(), auths);
+        self.cache.insert_ranked(resp.adds.as_deref().unwrap_or_default(), Credibility::Additional);
+    }
+
+    // EFFECTS: Collects addresses for ns_names from the glue in resp, then
+    //          from the cache, resolving the names from the root when
+    //          neither has any, as far as budget allows
+    fn nameserver_addrs(&self, resp: &Message, ns_names: &[String], depth: usize, budget: &Budget)
+                        -> Result<Vec<IpAddr>, Box<dyn Error + Send + Sync>>
+    {
+        let glue: Vec<IpAddr> = glue(resp, ns_names).filter_map(|rr| rr.rdata.to_ip()).collect();
+
+        if !glue.is_empty()
+        {
+            return Ok(glue);
+        }
+
+        let cached: Vec<IpAddr> = ns_names.iter()
+                                          .flat_map(|ns| [self.cache.get(ns, Type::A), self.cache.get(ns, Type::AAAA)])
+                                          .flat_map(|c| match c {
+                                              Some(Cached::Records(rrs)) => rrs,
+                                              _ => Vec::new(),
+                                          })
+                                          .filter_map(|rr| rr.rdata.to_ip())
+                                          .collect();
+
+        if !cached.is_empty()
+        {
+            return Ok(cached);
+        }
+
+        if depth >= self.max_glueless_depth
+        {
+            return Err(budget.exceeded(Limit::GluelessDepth(self.max_glueless_depth)));
+        }
+
+        let mut last_err: Box<dyn Error + Send + Sync> = "Referral named no nameservers".into();
+
+        for ns in ns_names
+        {
+            if budget.glueless_names.get() >= self.max_glueless_names
+            {
+                return Err(budget.exceeded(Limit::GluelessNames(self.max_glueless_names)));
+            }
+            budget.glueless_names.set(budget.glueless_names.get() + 1);
+
+            match self.resolve_at_depth(ns, QType::A, depth + 1, budget)
+            {
+                Ok(m) => {
+                    let addrs: Vec<IpAddr> = m.answs.iter()
+                                                    .flatten()
+                                                    .filter(|rr| rr.rr_type == Type::A)
+                                                    .filter_map(|rr| rr.rdata.to_ip())
+                                                    .collect();
+                    if !addrs.is_empty()
+                    {
+                        return Ok(addrs);
+                    }
+                },
+                Err(e) if e.is::<LimitExceeded>() => return Err(e),
+                Err(e) => last_err = e,
+            }
+        }
+
+        Err(last_err)
+    }
+}
+
+// EFFECTS: Describes resp, which classified as step, for a trace
+fn traced(resp: &Message, step: &Step) -> TraceOutcome
+{
+    match step
+    {
+        Step::Done => TraceOutcome::Answer(resp.header.rcode, resp.answs.clone().unwrap_or_default()),
+        Step::Referral(zone, ns_names) => TraceOutcome::Referral {
+            zone: zone.clone(),
+            nameservers: ns_names.clone(),
+            glue: glue(resp, ns_names).cloned().collect(),
+        },
+        Step::Lame => TraceOutcome::Lame(resp.header.rcode),
+    }
+}
+
+// EFFECTS: Returns the address records in the additional section of resp
+//          for any of ns_names
+fn glue<'a>(resp: &'a Message, ns_names: &'a [String]) -> impl Iterator<Item = &'a ResourceRecord>
+{
+    resp.adds.iter()
+             .flatten()
+             .filter(move |rr| matches!(rr.rr_type, Type::A | Type::AAAA))
+             .filter(move |rr| ns_names.iter().any(|ns| dns::name_eq(ns, &rr.name)))
+}
+
+// EFFECTS: Drops the records in resp that a server for zone has no say
+//          over: any owned by a name outside zone, and in the authority
+//          section anything but the NS and SOA records a referral or
+//          negative answer needs. Such records may have been planted to
+//          poison the cache
+fn scrub(mut resp: Message, zone: &str) -> Message
+{
+    let in_bailiwick = |rr: &ResourceRecord| dns::in_zone(&rr.name, zone);
+
+    if let Some(answs) = resp.answs.as_mut()
+    {
+        answs.retain(in_bailiwick);
+    }
+    if let Some(auths) = resp.auths.as_mut()
+    {
+        auths.retain(|rr| in_bailiwick(rr) && matches!(rr.rr_type, Type::NS | Type::SOA));
+    }
+    if let Some(adds) = resp.adds.as_mut()
+    {
+        adds.retain(in_bailiwick);
+    }
+
+    resp.header.an_count = resp.answs.as_ref().map_or(0, |a| a.len() as u16);
+    resp.header.ns_count = resp.auths.as_ref().map_or(0, |a| a.len() as u16);
+    resp.header.ar_count = resp.adds.as_ref().map_or(0, |a| a.len() as u16) + resp.edns.is_some() as u16;
+
+    resp
+}
+
+// EFFECTS: Returns the name under qname with one label more than ancestor,
+//          an ancestor of qname (or qname itself, which is returned)
+fn one_more_label(qname: &str, ancestor: &str) -> String
+{
+    let labels: Vec<&str> = dns::trim_root(qname).split('.').collect();
+    let known = dns::trim_root(ancestor).split('.').filter(|l| !l.is_empty()).count();
+    let start = labels.len().saturating_sub(known + 1);
+
+    labels[start..].join(".")
+}
+
+// EFFECTS: Decides whether resp from a server for zone finishes the lookup of
+//          qname, delegates to a closer zone, or is unusable
+fn classify(resp: &Message, qname: &str, zone: &str) -> Step
+{
+    match resp.header.rcode
+    {
+        RespCode::Ok => {},
+        RespCode::NameError => return Step::Done,
+        _ => return Step::Lame,
+    }
+
+    if resp.answs.as_ref().is_some_and(|a| !a.is_empty())
+    {
+        return Step::Done;
+    }
+
+    let mut child: Option<String> = None;
+    let mut ns_names = Vec::new();
+
+    for rr in resp.auths.iter().flatten()
+    {
+        if let RData::NS(ns) = &rr.rdata
+        {
+            let closer = dns::in_zone(qname, &rr.name)
+                && dns::in_zone(&rr.name, zone)
+                && !dns::name_eq(&rr.name, zone);
+            let same_child = child.as_ref().is_none_or(|c| dns::name_eq(c, &rr.name));
+
+            if closer && same_child
+            {
+                child.get_or_insert_with(|| dns::trim_root(&rr.name).to_string());
+                ns_names.push(ns.clone());
+            }
+        }
+    }
+
+    match child
+    {
+        Some(c) => Step::Referral(c, ns_names),
+        None if resp.header.auth_answ => Step::Done,
+        None => Step::Lame,
+    }
+}
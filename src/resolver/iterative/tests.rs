@@ -0,0 +1,518 @@
+use super::*;
+
+use std::net::Ipv4Addr;
+use std::thread;
+
+use crate::dns::resourcerecord::ResourceRecord;
+use crate::resolver::standin::{ bind_loopback, response, StandIn, Zone };
+
+fn lo(host: u8) -> IpAddr
+{
+    IpAddr::V4(Ipv4Addr::new(127, 0, 0, host))
+}
+
+fn glue(host: u8) -> RData
+{
+    lo(host).into()
+}
+
+// root (.10) -> com (.11) -> example.com (.12)
+//            -> net (.13), which also holds the address for the glueless
+//               nameserver of glueless.com (.14)
+// .15 never answers
+fn hierarchy() -> (u16, Vec<StandIn>)
+{
+    let (port, mut socks) = bind_loopback(&[10, 11, 12, 13, 14, 15]);
+
+    let root = Zone::new("")
+        .with("com", RData::NS("a.gtld.com".into()))
+        .with("a.gtld.com", glue(11))
+        .with("net", RData::NS("a.gtld.net".into()))
+        .with("a.gtld.net", glue(13));
+    let com = Zone::new("com")
+        .with("example.com", RData::NS("ns1.example.com".into()))
+        .with("ns1.example.com", glue(12))
+        .with("glueless.com", RData::NS("ns.hosting.net".into()));
+    let example = Zone::new("example.com")
+        .with("example.com", RData::NS("ns1.example.com".into()))
+        .with("www.example.com", RData::A(93, 184, 216, 34))
+        .with("db.corp.example.com", RData::A(10, 0, 1, 1))
+        .with("web.example.com", RData::CNAME("www.example.com".into()))
+        .with("cdn.example.com", RData::CNAME("www.glueless.com".into()))
+        .with("loop1.example.com", RData::CNAME("loop.glueless.com".into()))
+        .with("ns1.example.com", glue(12));
+    let net = Zone::new("net")
+        .with("ns.hosting.net", glue(14));
+    let glueless = Zone::new("glueless.com")
+        .with("www.glueless.com", RData::A(10, 0, 0, 1))
+        .with("loop.glueless.com", RData::CNAME("loop1.example.com".into()));
+
+    let servers = vec![
+        StandIn::serve(socks.remove(0), root),
+        StandIn::serve(socks.remove(0), com),
+        StandIn::serve(socks.remove(0), example),
+        StandIn::serve(socks.remove(0), net),
+        StandIn::serve(socks.remove(0), glueless),
+        StandIn::spawn(socks.remove(0), |_| None),
+    ];
+
+    (port, servers)
+}
+
+fn resolver(port: u16) -> IterativeResolver
+{
+    let mut r = IterativeResolver::with_roots(vec![lo(10)], port);
+    r.timeout = Duration::from_millis(200);
+    r
+}
+
+#[test]
+fn test_follows_referrals_to_authoritative_answer()
+{
+    let (port, servers) = hierarchy();
+
+    let m = resolver(port).resolve("www.example.com", QType::A).unwrap();
+
+    assert!(m.header.auth_answ);
+    assert_eq!(Some(RData::A(93, 184, 216, 34)),
+               m.answs.and_then(|a| a.first().map(|rr| rr.rdata.clone())));
+    assert_eq!(1, servers[0].query_count());
+    assert_eq!(1, servers[1].query_count());
+    assert_eq!(1, servers[2].query_count());
+}
+
+#[test]
+fn test_returns_name_error_from_authority()
+{
+    let (port, _servers) = hierarchy();
+
+    let m = resolver(port).resolve("nope.example.com", QType::A).unwrap();
+
+    assert_eq!(RespCode::NameError, m.header.rcode);
+    assert_eq!(None, m.answs);
+}
+
+#[test]
+fn test_resolves_glueless_nameserver()
+{
+    let (port, servers) = hierarchy();
+
+    let m = resolver(port).resolve("www.glueless.com", QType::A).unwrap();
+
+    assert_eq!(Some(RData::A(10, 0, 0, 1)),
+               m.answs.and_then(|a| a.first().map(|rr| rr.rdata.clone())));
+    assert_eq!(1, servers[3].query_count());
+    assert_eq!(1, servers[4].query_count());
+}
+
+#[test]
+fn test_lookup_follows_cnames_within_and_across_zones()
+{
+    let (port, _servers) = hierarchy();
+    let r = resolver(port);
+
+    let l = r.lookup("web.example.com", QType::A).unwrap();
+    assert_eq!(vec!["web.example.com"], l.aliases());
+    assert_eq!(vec![IpAddr::from([93, 184, 216, 34])], l.ips());
+
+    let l = r.lookup("cdn.example.com", QType::A).unwrap();
+    assert_eq!(vec!["cdn.example.com"], l.aliases());
+    assert_eq!("www.glueless.com", l.canonical_name());
+    assert_eq!(vec![IpAddr::from([10, 0, 0, 1])], l.ips());
+}
+
+#[test]
+fn test_lookup_detects_loop_across_zones()
+{
+    let (port, _servers) = hierarchy();
+
+    assert!(resolver(port).lookup("loop1.example.com", QType::A).is_err());
+}
+
+#[test]
+fn test_skips_unresponsive_root()
+{
+    let (port, servers) = hierarchy();
+
+    let mut r = resolver(port);
+    r.roots = vec![lo(15), lo(10)];
+
+    let m = r.resolve("www.example.com", QType::A).unwrap();
+
+    assert!(m.answs.is_some());
+    assert_eq!(1, servers[5].query_count());
+}
+
+#[test]
+fn test_lame_referral_is_an_error()
+{
+    let (port, mut socks) = bind_loopback(&[16]);
+    // Refers every query back to the root zone
+    let _server = StandIn::spawn(socks.remove(0), |q| {
+        Some(response(q, RespCode::Ok, false, vec![],
+                      vec![ResourceRecord::new("", 60, RData::NS("a.root".into()))],
+                      vec![]))
+    });
+
+    let r = IterativeResolver::with_roots(vec![lo(16)], port);
+
+    assert!(r.resolve("www.example.com", QType::A).is_err());
+}
+
+#[test]
+fn test_classify_referral()
+{
+    let q = Message::build_query(1, vec![
+        Question { qname: "www.example.com".into(), qtype: QType::A, qclass: QClass::IN },
+    ]);
+    let resp = response(&q, RespCode::Ok, false, vec![],
+                        vec![ResourceRecord::new("Example.COM.", 60, RData::NS("ns1.example.com".into()))],
+                        vec![]);
+
+    assert_eq!(Step::Referral("Example.COM".into(), vec!["ns1.example.com".into()]),
+               classify(&resp, "www.example.com", "com"));
+    assert_eq!(Step::Lame, classify(&resp, "www.example.com", "example.com"));
+    assert_eq!(Step::Lame, classify(&resp, "www.example.org", ""));
+}
+
+// EFFECTS: Returns the names and types each of servers was asked for
+fn asked(servers: &[StandIn]) -> Vec<Vec<(String, QType)>>
+{
+    servers.iter()
+           .map(|s| s.queries.lock().unwrap().iter().map(|q| (q.qname.clone(), q.qtype)).collect())
+           .collect()
+}
+
+fn minimising(port: u16) -> IterativeResolver
+{
+    IterativeResolver { qname_minimisation: true, ..resolver(port) }
+}
+
+#[test]
+fn test_one_more_label()
+{
+    assert_eq!("com", one_more_label("www.example.com", ""));
+    assert_eq!("Example.com", one_more_label("www.Example.com.", "com"));
+    assert_eq!("www.example.com", one_more_label("www.example.com", "www.example.com"));
+    assert_eq!("", one_more_label("", ""));
+}
+
+#[test]
+fn test_minimises_qname_to_each_zone()
+{
+    let (port, servers) = hierarchy();
+
+    let m = minimising(port).resolve("www.example.com", QType::AAAA).unwrap();
+
+    assert!(m.header.auth_answ);
+    assert_eq!(vec![
+        vec![("com".to_string(), QType::A)],
+        vec![("example.com".to_string(), QType::A)],
+        vec![("www.example.com".to_string(), QType::AAAA)],
+    ], asked(&servers[..3]));
+}
+
+#[test]
+fn test_minimises_through_empty_non_terminal()
+{
+    let (port, servers) = hierarchy();
+
+    let m = minimising(port).resolve("db.corp.example.com", QType::A).unwrap();
+
+    assert_eq!(Some(RData::A(10, 0, 1, 1)), m.answs.and_then(|a| a.first().map(|rr| rr.rdata.clone())));
+    assert_eq!(vec![("corp.example.com".to_string(), QType::A), ("db.corp.example.com".to_string(), QType::A)],
+               asked(&servers[2..3])[0]);
+}
+
+#[test]
+fn test_limits_minimised_queries()
+{
+    let (port, servers) = hierarchy();
+
+    let r = IterativeResolver { max_minimise_queries: 1, ..minimising(port) };
+    assert!(r.resolve("www.example.com", QType::A).is_ok());
+
+    // Past the limit the full name is revealed
+    assert_eq!(vec![
+        vec![("com".to_string(), QType::A)],
+        vec![("www.example.com".to_string(), QType::A)],
+        vec![("www.example.com".to_string(), QType::A)],
+    ], asked(&servers[..3]));
+}
+
+#[test]
+fn test_falls_back_to_full_qname_on_broken_servers()
+{
+    let (port, mut socks) = bind_loopback(&[47, 48, 49]);
+    // Each knows only the full name, and fails any query for a minimised one
+    // with NXDOMAIN, REFUSED or silence respectively
+    let servers: Vec<StandIn> = [Some(RespCode::NameError), Some(RespCode::Refused), None]
+        .iter()
+        .map(|&rcode| {
+            let answer = ResourceRecord::new("www.broken.org", 60, RData::A(10, 0, 2, 1));
+            StandIn::spawn(socks.remove(0), move |q| {
+                if q.quests.as_ref().unwrap()[0].qname == "www.broken.org"
+                {
+                    Some(response(q, RespCode::Ok, true, vec![answer.clone()], vec![], vec![]))
+                } else {
+                    rcode.map(|rcode| response(q, rcode, true, vec![], vec![], vec![]))
+                }
+            })
+        })
+        .collect();
+
+    for (host, server) in [47, 48, 49].iter().zip(servers.iter())
+    {
+        let r = IterativeResolver { roots: vec![lo(*host)], ..minimising(port) };
+
+        let m = r.resolve("www.broken.org", QType::A).unwrap();
+        assert_eq!(1, m.header.an_count);
+        assert_eq!(vec!["org", "www.broken.org"],
+                   server.queries.lock().unwrap().iter().map(|q| q.qname.as_str()).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn test_prefers_responsive_root_after_timeout()
+{
+    let (port, servers) = hierarchy();
+
+    let mut r = resolver(port);
+    r.roots = vec![lo(15), lo(10)];
+    r.srtt = Arc::new(Srtt::new(0.0));
+
+    for _ in 0..3
+    {
+        assert!(r.resolve("www.example.com", QType::A).is_ok());
+    }
+
+    assert_eq!(1, servers[5].query_count());
+    assert_eq!(3, servers[0].query_count());
+    let stats = r.server_stats();
+    assert_eq!(Some(&(SocketAddr::new(lo(15), port), ServerStats { srtt: r.timeout, responses: 0, failures: 1 })),
+               stats.last());
+}
+
+#[test]
+fn test_lame_response_counts_against_server()
+{
+    let (port, mut socks) = bind_loopback(&[81, 82]);
+    // .81 refuses at once; .82 answers, but takes its time
+    let refusing = StandIn::spawn(socks.remove(0), |q| Some(response(q, RespCode::Refused, false, vec![], vec![], vec![])));
+    let z = Zone::new("").with("www.example.com", RData::A(10, 0, 0, 1));
+    let answering = StandIn::spawn(socks.remove(0), move |q| {
+        thread::sleep(Duration::from_millis(20));
+        Some(z.answer(q))
+    });
+
+    let mut r = resolver(port);
+    r.roots = vec![lo(81), lo(82)];
+    r.srtt = Arc::new(Srtt::new(0.0));
+
+    for _ in 0..3
+    {
+        assert!(r.resolve("www.example.com", QType::A).is_ok());
+    }
+
+    assert_eq!(1, refusing.query_count());
+    assert_eq!(3, answering.query_count());
+    let stats = r.server_stats();
+    assert_eq!(Some(&(SocketAddr::new(lo(81), port), ServerStats { srtt: r.timeout, responses: 0, failures: 1 })),
+               stats.last());
+}
+
+#[test]
+fn test_traces_each_query_from_the_root()
+{
+    let (port, _servers) = hierarchy();
+
+    let mut r = resolver(port);
+    r.roots = vec![lo(15), lo(10)];
+    r.srtt = Arc::new(Srtt::new(0.0));
+
+    let trace = r.trace("www.glueless.com", QType::A);
+    assert_eq!(vec![IpAddr::from([10, 0, 0, 1])], trace.lookup.unwrap().ips());
+
+    let summary: Vec<_> = trace.steps.iter().map(|s| (s.depth, s.zone.as_str(), s.server.ip(), s.qname.as_str())).collect();
+    assert_eq!(vec![
+        (0, "", lo(15), "www.glueless.com"),
+        (0, "", lo(10), "www.glueless.com"),
+        (0, "com", lo(11), "www.glueless.com"),
+        (1, "", lo(10), "ns.hosting.net"),
+        (1, "net", lo(13), "ns.hosting.net"),
+        (0, "glueless.com", lo(14), "www.glueless.com"),
+    ], summary);
+
+    assert!(matches!(trace.steps[0].outcome, TraceOutcome::Failed(_)));
+    assert_eq!(TraceOutcome::Referral {
+        zone: "com".into(),
+        nameservers: vec!["a.gtld.com".into()],
+        glue: vec![ResourceRecord::new("a.gtld.com", 300, glue(11))],
+    }, trace.steps[1].outcome);
+    assert_eq!(TraceOutcome::Referral {
+        zone: "glueless.com".into(),
+        nameservers: vec!["ns.hosting.net".into()],
+        glue: vec![],
+    }, trace.steps[2].outcome);
+    assert_eq!(TraceOutcome::Answer(RespCode::Ok, vec![ResourceRecord::new("www.glueless.com", 300, RData::A(10, 0, 0, 1))]),
+               trace.steps[5].outcome);
+
+    // Tracing leaves the resolver itself untraced
+    assert!(r.trace.is_none());
+}
+
+#[test]
+fn test_trace_keeps_steps_of_failed_lookup()
+{
+    let (port, _servers) = hierarchy();
+
+    let trace = resolver(port).trace("www.missing.com", QType::A);
+
+    assert!(trace.lookup.is_ok_and(|l| l.rcode == RespCode::NameError));
+    assert_eq!(Some(&TraceOutcome::Answer(RespCode::NameError, vec![])), trace.steps.last().map(|s| &s.outcome));
+
+    let mut r = resolver(port);
+    r.roots = vec![lo(15)];
+    let trace = r.trace("www.example.com", QType::A);
+
+    assert!(trace.lookup.is_err());
+    assert_eq!(1, trace.steps.len());
+    assert!(matches!(trace.steps[0].outcome, TraceOutcome::Failed(_)));
+}
+
+// root (.70) -> com (.71) -> evil.com (.72), which answers with records
+//                                for names it has no authority over
+//                         -> example.com, served by ns1.example.net
+//            -> net (.73), which holds the address of ns1.example.net (.74)
+fn poisoned_hierarchy() -> (u16, Vec<StandIn>)
+{
+    let (port, mut socks) = bind_loopback(&[70, 71, 72, 73, 74]);
+
+    let root = Zone::new("")
+        .with("com", RData::NS("a.gtld.com".into()))
+        .with("a.gtld.com", glue(71))
+        .with("net", RData::NS("a.gtld.net".into()))
+        .with("a.gtld.net", glue(73));
+    let com = Zone::new("com")
+        .with("evil.com", RData::NS("ns.evil.com".into()))
+        .with("ns.evil.com", glue(72))
+        .with("example.com", RData::NS("ns1.example.net".into()));
+    let net = Zone::new("net")
+        .with("ns1.example.net", glue(74));
+    let example = Zone::new("example.com")
+        .with("www.example.com", RData::A(93, 184, 216, 34));
+
+    let evil = |q: &Message| {
+        let rr = |name: &str, rdata| ResourceRecord::new(name, 3600, rdata);
+        Some(response(q, RespCode::Ok, true,
+                      vec![rr("www.evil.com", RData::A(10, 6, 6, 6)), rr("www.example.com", RData::A(10, 6, 6, 6))],
+                      vec![rr("example.com", RData::NS("ns.evil.com".into())), rr("evil.com", RData::NS("ns.evil.com".into()))],
+                      vec![rr("ns1.example.net", glue(72)), rr("ns.evil.com", glue(72))]))
+    };
+
+    let servers = vec![
+        StandIn::serve(socks.remove(0), root),
+        StandIn::serve(socks.remove(0), com),
+        StandIn::spawn(socks.remove(0), evil),
+        StandIn::serve(socks.remove(0), net),
+        StandIn::serve(socks.remove(0), example),
+    ];
+
+    (port, servers)
+}
+
+#[test]
+fn test_scrubs_records_outside_bailiwick()
+{
+    let (port, servers) = poisoned_hierarchy();
+    let mut r = resolver(port);
+    r.roots = vec![lo(70)];
+
+    let m = r.resolve("www.evil.com", QType::A).unwrap();
+    assert_eq!(1, m.header.an_count);
+    assert_eq!((1, 1), (m.header.ns_count, m.header.ar_count));
+
+    // Only what evil.com may speak for was cached
+    assert!(r.cache.get("www.evil.com", Type::A).is_some());
+    assert!(r.cache.get("ns.evil.com", Type::A).is_some());
+    for (name, t) in [("www.example.com", Type::A), ("ns1.example.net", Type::A), ("example.com", Type::NS)]
+    {
+        assert!(r.cache.get(name, t).is_none(), "{} {:?}", name, t);
+    }
+
+    // So example.com is still found through its real nameserver
+    let l = r.lookup("www.example.com", QType::A).unwrap();
+    assert_eq!(vec![IpAddr::from([93, 184, 216, 34])], l.ips());
+    assert_eq!(1, servers[2].query_count());
+    assert_eq!(1, servers[4].query_count());
+}
+
+// EFFECTS: Returns the limit result failed on, panicking if it didn't fail
+//          for want of one
+fn limit<T: std::fmt::Debug>(result: Result<T, Box<dyn Error + Send + Sync>>) -> Limit
+{
+    result.unwrap_err().downcast_ref::<LimitExceeded>().expect("Failed on a limit").limit
+}
+
+#[test]
+fn test_limits_queries_referrals_and_cnames_per_lookup()
+{
+    let (port, _servers) = hierarchy();
+
+    let mut r = resolver(port);
+    r.max_queries = 2;
+    assert_eq!(Limit::Queries(2), limit(r.lookup("www.example.com", QType::A)));
+    // Aliases share the budget of the lookup that followed them
+    r.max_queries = 4;
+    assert_eq!(Limit::Queries(4), limit(r.lookup("cdn.example.com", QType::A)));
+
+    let mut r = resolver(port);
+    r.max_referrals = 1;
+    assert_eq!(Limit::Referrals(1), limit(r.resolve("www.example.com", QType::A)));
+
+    let mut r = resolver(port);
+    r.max_cname_depth = 0;
+    let err = r.lookup("web.example.com", QType::A).unwrap_err();
+    assert_eq!(Some(&LimitExceeded { limit: Limit::CnameDepth(0), qname: "web.example.com".into() }),
+               err.downcast_ref::<LimitExceeded>());
+    assert_eq!("Exceeded a CNAME chain of 0 resolving web.example.com", err.to_string());
+}
+
+#[test]
+fn test_limits_nameservers_resolved_without_glue()
+{
+    // Delegates to 10 nameservers, none of which exist (NXNSAttack)
+    let (port, mut socks) = bind_loopback(&[76]);
+    let root = (0..10).fold(Zone::new(""), |z, i| {
+        z.with("nxns.com", RData::NS(format!("ns{}.nowhere.com", i)))
+    });
+    let server = StandIn::serve(socks.remove(0), root);
+
+    let mut r = IterativeResolver::with_roots(vec![lo(76)], port);
+    r.max_glueless_names = 4;
+
+    assert_eq!(Limit::GluelessNames(4), limit(r.resolve("www.nxns.com", QType::A)));
+    assert_eq!(5, server.query_count());
+
+    let (port, _servers) = hierarchy();
+    let mut r = resolver(port);
+    r.max_glueless_depth = 0;
+    assert_eq!(Limit::GluelessDepth(0), limit(r.resolve("www.glueless.com", QType::A)));
+}
+
+#[test]
+fn test_limits_time_per_lookup()
+{
+    let (port, servers) = hierarchy();
+
+    let mut r = resolver(port);
+    r.roots = vec![lo(15), lo(10)];
+    r.lookup_deadline = Duration::from_millis(50);
+
+    let started = Instant::now();
+    assert_eq!(Limit::Deadline(r.lookup_deadline), limit(r.lookup("www.example.com", QType::A)));
+    // The one query sent waited only as long as the lookup had left
+    assert!(started.elapsed() < r.timeout);
+    assert_eq!(1, servers[5].query_count());
+    assert_eq!(0, servers[0].query_count());
+}
@@ -0,0 +1,59 @@
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+// One of the bounds on the work a single lookup may do, and its value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit
+{
+    // Queries sent in all, including those for nameserver addresses
+    Queries(usize),
+    // Referrals followed resolving one name
+    Referrals(usize),
+    // CNAMEs followed from the name looked up
+    CnameDepth(usize),
+    // Nameserver names resolved for want of glue
+    GluelessNames(usize),
+    // Nameserver lookups nested within each other for want of glue
+    GluelessDepth(usize),
+    // Time allowed for the whole lookup
+    Deadline(Duration),
+}
+
+impl fmt::Display for Limit
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            Limit::Queries(n) => write!(f, "{} queries", n),
+            Limit::Referrals(n) => write!(f, "{} referrals", n),
+            Limit::CnameDepth(n) => write!(f, "a CNAME chain of {}", n),
+            Limit::GluelessNames(n) => write!(f, "{} nameservers resolved without glue", n),
+            Limit::GluelessDepth(n) => write!(f, "glueless delegations {} deep", n),
+            Limit::Deadline(d) => write!(f, "the deadline of {:?}", d),
+        }
+    }
+}
+
+// ------------- Limit Exceeded -------------
+// The error a lookup of qname fails with once it would go past limit, so
+// that callers can tell a lookup cut short (perhaps by a zone built to
+// amplify queries) from one the nameservers failed. Boxed like any other
+// error; downcast to recognise it
+#[derive(Clone, Debug, PartialEq)]
+pub struct LimitExceeded
+{
+    pub limit: Limit,
+    pub qname: String,
+}
+
+impl fmt::Display for LimitExceeded
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "Exceeded {} resolving {}", self.limit, self.qname)
+    }
+}
+
+impl Error for LimitExceeded {}
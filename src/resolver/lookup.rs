@@ -0,0 +1,161 @@
+use std::error::Error;
+use std::net::IpAddr;
+
+use crate::dns::{
+    self, QType, Type,
+    header::RespCode,
+    message::Message,
+    resourcerecord::{ RData, ResourceRecord },
+};
+
+use super::limits::{ Limit, LimitExceeded };
+
+#[cfg(test)]
+mod tests;
+
+// ------------- Lookup -------------
+// The outcome of resolving one name and type, including the aliases that
+// were followed to reach the canonical name
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lookup
+{
+    pub qname: String,
+    pub qtype: QType,
+    pub rcode: RespCode,
+    // CNAME records followed from qname, in order
+    pub chain: Vec<ResourceRecord>,
+    // Records of qtype owned by the canonical name
+    pub answers: Vec<ResourceRecord>,
+    // SOA of the zone that denied the canonical name or type, when answers
+    // is empty
+    pub soa: Option<ResourceRecord>,
+    // Answered from expired cache entries because no nameserver responded
+    pub stale: bool,
+}
+
+impl Lookup
+{
+    pub fn new(qname: &str, qtype: QType) -> Self
+    {
+        Lookup {
+            qname: qname.to_string(),
+            qtype,
+            rcode: RespCode::Ok,
+            chain: Vec::new(),
+            answers: Vec::new(),
+            soa: None,
+            stale: false,
+        }
+    }
+
+    // EFFECTS: Returns the target of the last alias followed, or qname
+    pub fn canonical_name(&self) -> &str
+    {
+        match self.chain.last().map(|rr| &rr.rdata)
+        {
+            Some(RData::CNAME(target)) => target,
+            _ => &self.qname,
+        }
+    }
+
+    // EFFECTS: Returns every name in the chain that is an alias, starting with qname
+    pub fn aliases(&self) -> Vec<&str>
+    {
+        self.chain.iter()
+                  .map(|rr| rr.name.as_str())
+                  .collect()
+    }
+
+    pub fn ips(&self) -> Vec<IpAddr>
+    {
+        self.answers.iter()
+                    .filter_map(|rr| rr.rdata.to_ip())
+                    .collect()
+    }
+
+    // EFFECTS: Returns the hostnames the PTR answers point to
+    pub fn hostnames(&self) -> Vec<String>
+    {
+        self.answers.iter()
+                    .filter_map(|rr| match &rr.rdata {
+                        RData::PTR(name) => Some(dns::trim_root(name).to_string()),
+                        _ => None,
+                    })
+                    .collect()
+    }
+
+    // EFFECTS: Follows the CNAMEs in the answer section of resp starting from
+    //          the canonical name, then takes any answers found there.
+    //          Returns Ok(true) once the lookup is finished and Ok(false) if
+    //          the chain leads to a name resp says nothing about, which must
+    //          be queried next. Fails if the chain loops or grows past max_depth
+    pub fn absorb(&mut self, resp: &Message, max_depth: usize) -> Result<bool, Box<dyn Error + Send + Sync>>
+    {
+        let answs = resp.answs.as_deref().unwrap_or_default();
+        let start = self.chain.len();
+
+        loop
+        {
+            let name = self.canonical_name().to_string();
+
+            let found: Vec<ResourceRecord> = answs.iter()
+                                                  .filter(|rr| self.qtype.matches(rr.rr_type)
+                                                          && dns::name_eq(&rr.name, &name))
+                                                  .cloned()
+                                                  .collect();
+            if !found.is_empty()
+            {
+                self.answers = found;
+                self.rcode = resp.header.rcode;
+                return Ok(true);
+            }
+
+            let cname = answs.iter()
+                             .find(|rr| rr.rr_type == Type::CNAME && dns::name_eq(&rr.name, &name));
+
+            match cname
+            {
+                Some(rr) => self.push_alias(rr, max_depth)?,
+                None => break,
+            }
+        }
+
+        self.rcode = resp.header.rcode;
+        self.soa = resp.auths
+                       .as_deref()
+                       .unwrap_or_default()
+                       .iter()
+                       .find(|rr| rr.rr_type == Type::SOA && dns::in_zone(self.canonical_name(), &rr.name))
+                       .cloned();
+
+        // NXDOMAIN after a CNAME speaks for the end of the chain (RFC 6604)
+        Ok(self.chain.len() == start || self.rcode != RespCode::Ok)
+    }
+
+    // EFFECTS: Appends the CNAME rr to the chain. Fails if its target was
+    //          already visited or the chain would grow past max_depth
+    pub fn push_alias(&mut self, rr: &ResourceRecord, max_depth: usize) -> Result<(), Box<dyn Error + Send + Sync>>
+    {
+        let target = match &rr.rdata
+        {
+            RData::CNAME(target) => target,
+            _ => return Ok(()),
+        };
+
+        if dns::name_eq(target, &self.qname)
+            || dns::name_eq(target, &rr.name)
+            || self.chain.iter().any(|c| dns::name_eq(&c.name, target))
+        {
+            return Err(format!("CNAME loop at {} -> {}", rr.name, target).into());
+        }
+
+        if self.chain.len() >= max_depth
+        {
+            return Err(LimitExceeded { limit: Limit::CnameDepth(max_depth), qname: self.qname.clone() }.into());
+        }
+
+        self.chain.push(rr.clone());
+
+        Ok(())
+    }
+}
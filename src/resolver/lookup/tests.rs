@@ -0,0 +1,150 @@
+use super::*;
+
+use crate::dns::{ QClass, question::Question };
+
+fn cname(name: &str, target: &str) -> ResourceRecord
+{
+    ResourceRecord::new(name, 60, RData::CNAME(target.to_string()))
+}
+
+fn a(name: &str, last: u8) -> ResourceRecord
+{
+    ResourceRecord::new(name, 60, RData::A(10, 0, 0, last))
+}
+
+fn resp(rcode: RespCode, answs: Vec<ResourceRecord>) -> Message
+{
+    let mut m = Message::build_query(7, vec![
+        Question { qname: "www.example.com".into(), qtype: QType::A, qclass: QClass::IN },
+    ]);
+    m.header.rcode = rcode;
+    m.header.an_count = answs.len() as u16;
+    m.answs = if answs.is_empty() { None } else { Some(answs) };
+    m
+}
+
+#[test]
+fn test_absorb_direct_answer()
+{
+    let mut l = Lookup::new("www.example.com", QType::A);
+
+    assert!(l.absorb(&resp(RespCode::Ok, vec![a("www.example.com", 1), a("other.example.com", 2)]), 8).unwrap());
+    assert_eq!(vec![IpAddr::from([10, 0, 0, 1])], l.ips());
+    assert!(l.aliases().is_empty());
+    assert_eq!("www.example.com", l.canonical_name());
+}
+
+#[test]
+fn test_absorb_chain_in_one_response()
+{
+    let mut l = Lookup::new("www.example.com", QType::A);
+    let m = resp(RespCode::Ok, vec![
+        a("edge.cdn.net", 9),
+        cname("WWW.example.com", "web.example.com"),
+        cname("web.example.com", "edge.cdn.net."),
+    ]);
+
+    assert!(l.absorb(&m, 8).unwrap());
+    assert_eq!(vec!["WWW.example.com", "web.example.com"], l.aliases());
+    assert_eq!("edge.cdn.net.", l.canonical_name());
+    assert_eq!(vec![IpAddr::from([10, 0, 0, 9])], l.ips());
+}
+
+#[test]
+fn test_absorb_partial_chain_needs_another_query()
+{
+    let mut l = Lookup::new("www.example.com", QType::A);
+
+    assert!(!l.absorb(&resp(RespCode::Ok, vec![cname("www.example.com", "edge.cdn.net")]), 8).unwrap());
+    assert_eq!("edge.cdn.net", l.canonical_name());
+
+    assert!(l.absorb(&resp(RespCode::Ok, vec![a("edge.cdn.net", 3)]), 8).unwrap());
+    assert_eq!(vec!["www.example.com"], l.aliases());
+    assert_eq!(vec![IpAddr::from([10, 0, 0, 3])], l.ips());
+}
+
+#[test]
+fn test_absorb_name_error_applies_to_chain_end()
+{
+    let mut l = Lookup::new("www.example.com", QType::A);
+
+    assert!(l.absorb(&resp(RespCode::NameError, vec![cname("www.example.com", "gone.example.com")]), 8).unwrap());
+    assert_eq!(RespCode::NameError, l.rcode);
+    assert_eq!("gone.example.com", l.canonical_name());
+}
+
+#[test]
+fn test_absorb_no_data()
+{
+    let mut l = Lookup::new("www.example.com", QType::A);
+
+    assert!(l.absorb(&resp(RespCode::Ok, vec![]), 8).unwrap());
+    assert!(l.answers.is_empty());
+    assert_eq!(RespCode::Ok, l.rcode);
+}
+
+#[test]
+fn test_absorb_keeps_soa_of_enclosing_zone()
+{
+    let soa = |zone: &str| ResourceRecord::new(zone, 60, RData::SOA {
+        mname: format!("ns.{}", zone),
+        rname: format!("hostmaster.{}", zone),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 30,
+    });
+    let mut negative = resp(RespCode::NameError, vec![]);
+    negative.header.ns_count = 2;
+    negative.auths = Some(vec![soa("example.net"), soa("example.com")]);
+
+    let mut l = Lookup::new("www.example.com", QType::A);
+
+    assert!(l.absorb(&negative, 8).unwrap());
+    assert_eq!(Some(soa("example.com")), l.soa);
+}
+
+#[test]
+fn test_absorb_cname_question_answers_with_alias()
+{
+    let mut l = Lookup::new("www.example.com", QType::CNAME);
+
+    assert!(l.absorb(&resp(RespCode::Ok, vec![cname("www.example.com", "web.example.com")]), 8).unwrap());
+    assert!(l.chain.is_empty());
+    assert_eq!(1, l.answers.len());
+}
+
+#[test]
+fn test_absorb_detects_loops()
+{
+    let mut l = Lookup::new("www.example.com", QType::A);
+    let m = resp(RespCode::Ok, vec![
+        cname("www.example.com", "a.example.com"),
+        cname("a.example.com", "b.example.com"),
+        cname("b.example.com", "a.example.com"),
+    ]);
+    assert!(l.absorb(&m, 8).is_err());
+
+    let mut l = Lookup::new("www.example.com", QType::A);
+    assert!(l.absorb(&resp(RespCode::Ok, vec![cname("www.example.com", "WWW.example.com.")]), 8).is_err());
+
+    // Loops that only close across queries are caught too
+    let mut l = Lookup::new("www.example.com", QType::A);
+    assert!(!l.absorb(&resp(RespCode::Ok, vec![cname("www.example.com", "a.example.com")]), 8).unwrap());
+    assert!(l.absorb(&resp(RespCode::Ok, vec![cname("a.example.com", "www.example.com")]), 8).is_err());
+}
+
+#[test]
+fn test_absorb_limits_depth()
+{
+    let chain: Vec<ResourceRecord> = (0..4)
+        .map(|i| cname(&format!("n{}.example.com", i), &format!("n{}.example.com", i + 1)))
+        .collect();
+
+    let mut l = Lookup::new("n0.example.com", QType::A);
+    assert!(!l.absorb(&resp(RespCode::Ok, chain.clone()), 4).unwrap());
+
+    let mut l = Lookup::new("n0.example.com", QType::A);
+    assert!(l.absorb(&resp(RespCode::Ok, chain), 3).is_err());
+}
@@ -0,0 +1,33 @@
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+
+// Root server addresses as published by IANA (https://www.iana.org/domains/root/servers).
+// IPv4 addresses are listed first so that hosts without IPv6 connectivity
+// reach a working server before exhausting the list.
+pub const ROOT_SERVERS: [IpAddr; 26] = [
+    IpAddr::V4(Ipv4Addr::new(198, 41, 0, 4)),       // a.root-servers.net
+    IpAddr::V4(Ipv4Addr::new(170, 247, 170, 2)),    // b.root-servers.net
+    IpAddr::V4(Ipv4Addr::new(192, 33, 4, 12)),      // c.root-servers.net
+    IpAddr::V4(Ipv4Addr::new(199, 7, 91, 13)),      // d.root-servers.net
+    IpAddr::V4(Ipv4Addr::new(192, 203, 230, 10)),   // e.root-servers.net
+    IpAddr::V4(Ipv4Addr::new(192, 5, 5, 241)),      // f.root-servers.net
+    IpAddr::V4(Ipv4Addr::new(192, 112, 36, 4)),     // g.root-servers.net
+    IpAddr::V4(Ipv4Addr::new(198, 97, 190, 53)),    // h.root-servers.net
+    IpAddr::V4(Ipv4Addr::new(192, 36, 148, 17)),    // i.root-servers.net
+    IpAddr::V4(Ipv4Addr::new(192, 58, 128, 30)),    // j.root-servers.net
+    IpAddr::V4(Ipv4Addr::new(193, 0, 14, 129)),     // k.root-servers.net
+    IpAddr::V4(Ipv4Addr::new(199, 7, 83, 42)),      // l.root-servers.net
+    IpAddr::V4(Ipv4Addr::new(202, 12, 27, 33)),     // m.root-servers.net
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xba3e, 0, 0, 0, 0x2, 0x30)),
+    IpAddr::V6(Ipv6Addr::new(0x2801, 0x1b8, 0x10, 0, 0, 0, 0, 0xb)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2, 0, 0, 0, 0, 0xc)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2d, 0, 0, 0, 0, 0xd)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0xa8, 0, 0, 0, 0, 0xe)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x2f, 0, 0, 0, 0, 0xf)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x12, 0, 0, 0, 0, 0xd0d)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x1, 0, 0, 0, 0, 0x53)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fe, 0, 0, 0, 0, 0, 0x53)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x503, 0xc27, 0, 0, 0, 0x2, 0x30)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fd, 0, 0, 0, 0, 0, 0x1)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x500, 0x9f, 0, 0, 0, 0, 0x42)),
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0xdc3, 0, 0, 0, 0, 0, 0x35)),
+];
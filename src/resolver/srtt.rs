@@ -0,0 +1,111 @@
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[cfg(test)]
+mod tests;
+
+// Chance that a query goes first to a server other than the fastest, so
+// that the estimates of slower servers are kept current
+const PROBE_CHANCE: f64 = 0.05;
+// Weight of a new sample in the smoothed RTT, alpha of RFC 6298
+const ALPHA: u32 = 8;
+const MAX_SRTT: Duration = Duration::from_secs(30);
+
+// Round-trip statistics for one server address
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ServerStats
+{
+    // Smoothed round-trip time, raised by a penalty for each failure
+    pub srtt: Duration,
+    pub responses: u64,
+    // Exchanges that timed out or otherwise failed
+    pub failures: u64,
+}
+
+// ------------- Srtt -------------
+// Tracks a smoothed round-trip time per server address and orders servers
+// fastest first. A server not heard from yet counts as fastest, so that
+// each is tried at least once. Shared by clones of a resolver
+#[derive(Debug)]
+pub struct Srtt
+{
+    servers: Mutex<HashMap<SocketAddr, ServerStats>>,
+    probe_chance: f64,
+}
+
+impl Default for Srtt
+{
+    fn default() -> Self
+    {
+        Srtt::new(PROBE_CHANCE)
+    }
+}
+
+impl Srtt
+{
+    // EFFECTS: Creates an empty table which puts a slower server first with
+    //          probability probe_chance (between 0 and 1)
+    pub fn new(probe_chance: f64) -> Self
+    {
+        Srtt { servers: Mutex::new(HashMap::new()), probe_chance: probe_chance.clamp(0.0, 1.0) }
+    }
+
+    // EFFECTS: Returns servers sorted by smoothed RTT, ties kept in the
+    //          given order. Once the fastest has answered, a slower one is
+    //          occasionally moved to the front
+    pub fn order(&self, servers: &[SocketAddr]) -> Vec<SocketAddr>
+    {
+        let mut ordered = servers.to_vec();
+        let table = self.servers.lock().unwrap();
+        ordered.sort_by_key(|s| table.get(s).map(|st| st.srtt).unwrap_or_default());
+
+        let measured = ordered.first().and_then(|s| table.get(s)).is_some_and(|st| st.responses > 0);
+        let mut rng = rand::thread_rng();
+        if ordered.len() > 1 && measured && rng.gen_bool(self.probe_chance)
+        {
+            let probed = ordered.remove(rng.gen_range(1..ordered.len()));
+            ordered.insert(0, probed);
+        }
+
+        ordered
+    }
+
+    // EFFECTS: Folds rtt into the smoothed RTT of server, taking it as is
+    //          for a server's first response
+    pub fn record_response(&self, server: SocketAddr, rtt: Duration)
+    {
+        let mut table = self.servers.lock().unwrap();
+        let stats = table.entry(server).or_default();
+
+        stats.srtt = if stats.responses == 0 && stats.failures == 0
+        {
+            rtt
+        } else {
+            (stats.srtt * (ALPHA - 1) + rtt) / ALPHA
+        };
+        stats.responses += 1;
+    }
+
+    // EFFECTS: Penalizes server for an exchange that failed after waited,
+    //          doubling its smoothed RTT and raising it to at least waited
+    pub fn record_failure(&self, server: SocketAddr, waited: Duration)
+    {
+        let mut table = self.servers.lock().unwrap();
+        let stats = table.entry(server).or_default();
+
+        stats.srtt = (stats.srtt * 2).max(waited).min(MAX_SRTT);
+        stats.failures += 1;
+    }
+
+    // EFFECTS: Returns the statistics of every server seen, fastest first
+    pub fn stats(&self) -> Vec<(SocketAddr, ServerStats)>
+    {
+        let mut stats: Vec<_> = self.servers.lock().unwrap().iter().map(|(s, st)| (*s, *st)).collect();
+        stats.sort_by_key(|(s, st)| (st.srtt, *s));
+
+        stats
+    }
+}
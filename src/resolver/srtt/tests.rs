@@ -0,0 +1,84 @@
+use super::*;
+
+use std::net::{ IpAddr, Ipv4Addr };
+
+fn server(last: u8) -> SocketAddr
+{
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, last)), 53)
+}
+
+fn ms(n: u64) -> Duration
+{
+    Duration::from_millis(n)
+}
+
+#[test]
+fn test_smooths_round_trip_times()
+{
+    let srtt = Srtt::new(0.0);
+
+    srtt.record_response(server(1), ms(80));
+    assert_eq!(ms(80), srtt.stats()[0].1.srtt);
+
+    // 7/8 of the old estimate and 1/8 of the sample
+    srtt.record_response(server(1), ms(160));
+    assert_eq!(ServerStats { srtt: ms(90), responses: 2, failures: 0 }, srtt.stats()[0].1);
+}
+
+#[test]
+fn test_penalizes_failures()
+{
+    let srtt = Srtt::new(0.0);
+
+    srtt.record_response(server(1), ms(80));
+    srtt.record_failure(server(1), ms(100));
+    assert_eq!(ms(160), srtt.stats()[0].1.srtt);
+
+    // A failure is at least as slow as the time waited on it
+    srtt.record_failure(server(2), ms(500));
+    assert_eq!(ServerStats { srtt: ms(500), responses: 0, failures: 1 }, srtt.stats()[1].1);
+
+    for _ in 0..16
+    {
+        srtt.record_failure(server(2), ms(500));
+    }
+    assert_eq!(MAX_SRTT, srtt.stats()[1].1.srtt);
+}
+
+#[test]
+fn test_orders_fastest_first()
+{
+    let srtt = Srtt::new(0.0);
+    let servers = [server(1), server(2), server(3), server(4)];
+
+    srtt.record_response(server(1), ms(50));
+    srtt.record_response(server(2), ms(10));
+    srtt.record_failure(server(3), ms(20));
+
+    // server(4) hasn't been tried yet, so goes first
+    assert_eq!(vec![server(4), server(2), server(3), server(1)], srtt.order(&servers));
+    assert_eq!(vec![server(2), server(3), server(1)],
+               srtt.stats().into_iter().map(|(s, _)| s).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_probes_slower_servers()
+{
+    let srtt = Srtt::new(1.0);
+    let servers = [server(1), server(2), server(3)];
+    srtt.record_response(server(1), ms(10));
+    srtt.record_response(server(2), ms(20));
+    srtt.record_response(server(3), ms(30));
+
+    for _ in 0..16
+    {
+        let order = srtt.order(&servers);
+        assert_ne!(server(1), order[0]);
+        assert_eq!(3, order.len());
+    }
+
+    // Nothing to probe for until the fastest has answered
+    let unmeasured = Srtt::new(1.0);
+    unmeasured.record_failure(server(2), ms(10));
+    assert_eq!(vec![server(1), server(3), server(2)], unmeasured.order(&servers));
+}
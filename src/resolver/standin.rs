@@ -0,0 +1,545 @@
+// Loopback nameservers standing in for real DNS servers in tests
+use std::io::{ Read, Write };
+use std::net::{ Ipv4Addr, SocketAddr, TcpListener, UdpSocket };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::{ Arc, Mutex };
+use std::thread::{ self, JoinHandle };
+use std::time::Duration;
+
+use crate::dns::{
+    self, Type,
+    header::{ QR, RespCode },
+    message::Message,
+    question::Question,
+    resourcerecord::{ RData, ResourceRecord },
+};
+use crate::parser::parse_msg;
+
+use super::transport::https::DNS_MESSAGE;
+
+use base64::{ engine::general_purpose::URL_SAFE_NO_PAD, Engine };
+use bytes::Bytes;
+use rcgen::{ BasicConstraints, CertificateParams, IsCa, KeyPair };
+use tokio_rustls::TlsAcceptor;
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{ CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer },
+    ServerConfig, ServerConnection, StreamOwned,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+// How long a stream stand-in keeps a connection open without a query
+pub const CONN_IDLE: Duration = Duration::from_millis(200);
+const ZONE_TTL: u32 = 300;
+// SOA minimum, bounding how long negative answers from a zone are cached
+pub const NEGATIVE_TTL: u32 = 60;
+
+// EFFECTS: Binds a UDP socket on each of 127.0.0.<host> for hosts, all
+//          sharing one port (glue records carry no port)
+pub fn bind_loopback(hosts: &[u8]) -> (u16, Vec<UdpSocket>)
+{
+    for _ in 0..32
+    {
+        let first = UdpSocket::bind((Ipv4Addr::new(127, 0, 0, hosts[0]), 0))
+            .expect("Couldn't bind stand-in socket");
+        let port = first.local_addr().unwrap().port();
+
+        let rest: Result<Vec<UdpSocket>, _> = hosts[1..].iter()
+                                                        .map(|h| UdpSocket::bind((Ipv4Addr::new(127, 0, 0, *h), port)))
+                                                        .collect();
+
+        if let Ok(mut socks) = rest
+        {
+            socks.insert(0, first);
+            return (port, socks);
+        }
+    }
+
+    panic!("Couldn't find a port free on all stand-in addresses");
+}
+
+// EFFECTS: Builds a response to query with the given sections
+pub fn response(query: &Message, rcode: RespCode, auth_answ: bool,
+                answs: Vec<ResourceRecord>, auths: Vec<ResourceRecord>, adds: Vec<ResourceRecord>)
+                -> Message
+{
+    let mut m = query.clone();
+
+    m.header.qr = QR::Response;
+    m.header.auth_answ = auth_answ;
+    m.header.rcode = rcode;
+    m.header.an_count = answs.len() as u16;
+    m.header.ns_count = auths.len() as u16;
+    m.header.ar_count = adds.len() as u16;
+    m.answs = if answs.is_empty() { None } else { Some(answs) };
+    m.auths = if auths.is_empty() { None } else { Some(auths) };
+    m.adds = if adds.is_empty() { None } else { Some(adds) };
+
+    m
+}
+
+// ------------- Zone -------------
+// The records one stand-in server is authoritative for, starting with the
+// SOA of its origin and including NS records (and glue) delegating child
+// zones
+#[derive(Clone, Debug)]
+pub struct Zone
+{
+    pub origin: String,
+    pub records: Vec<ResourceRecord>,
+}
+
+impl Zone
+{
+    pub fn new(origin: &str) -> Self
+    {
+        let soa = RData::SOA {
+            mname: format!("ns.{}", origin),
+            rname: format!("hostmaster.{}", origin),
+            serial: 1,
+            refresh: 3600,
+            retry: 600,
+            expire: 86400,
+            minimum: NEGATIVE_TTL,
+        };
+
+        Zone { origin: origin.to_string(), records: Vec::new() }.with(origin, soa)
+    }
+
+    pub fn with(mut self, name: &str, rdata: RData) -> Self
+    {
+        self.records.push(ResourceRecord::new(name, ZONE_TTL, rdata));
+        self
+    }
+
+    fn find(&self, name: &str, rr_type: Type) -> Vec<ResourceRecord>
+    {
+        self.records.iter()
+                    .filter(|rr| rr.rr_type == rr_type && dns::name_eq(&rr.name, name))
+                    .cloned()
+                    .collect()
+    }
+
+    // EFFECTS: Answers query the way an authoritative server for this zone would
+    pub fn answer(&self, query: &Message) -> Message
+    {
+        let q = match query.quests.as_ref().and_then(|qs| qs.first())
+        {
+            Some(q) => q,
+            None => return response(query, RespCode::FormatError, false, vec![], vec![], vec![]),
+        };
+
+        // Delegations to child zones take precedence over local data
+        let cut = self.records.iter()
+                              .filter(|rr| rr.rr_type == Type::NS
+                                      && !dns::name_eq(&rr.name, &self.origin)
+                                      && dns::in_zone(&q.qname, &rr.name))
+                              .map(|rr| rr.name.clone())
+                              .next();
+
+        if let Some(cut) = cut
+        {
+            let auths = self.find(&cut, Type::NS);
+            let adds = auths.iter()
+                            .flat_map(|ns| match &ns.rdata {
+                                RData::NS(n) => [self.find(n, Type::A), self.find(n, Type::AAAA)].concat(),
+                                _ => vec![],
+                            })
+                            .collect();
+
+            return response(query, RespCode::Ok, false, vec![], auths, adds);
+        }
+
+        let mut answs = Vec::new();
+        let mut name = q.qname.clone();
+        let mut answered = false;
+
+        for _ in 0..8
+        {
+            let found = self.find(&name, q.qtype.into());
+            if !found.is_empty()
+            {
+                answs.extend(found);
+                answered = true;
+                break;
+            }
+
+            match self.find(&name, Type::CNAME).pop()
+            {
+                Some(rr) => {
+                    if let RData::CNAME(target) = &rr.rdata
+                    {
+                        name = target.clone();
+                    }
+                    answs.push(rr);
+                },
+                None => break,
+            }
+        }
+
+        // As in RFC 6604, the rcode speaks for the end of any CNAME chain,
+        // unless the chain leaves the zone
+        let local = dns::in_zone(&name, &self.origin);
+        let exists = self.records.iter().any(|rr| dns::in_zone(&rr.name, &name));
+        let rcode = if answered || exists || !local { RespCode::Ok } else { RespCode::NameError };
+        // Negative answers carry the SOA of the zone (RFC 2308 section 3)
+        let auths = if answered || !local { vec![] } else { self.find(&self.origin, Type::SOA) };
+
+        response(query, rcode, true, answs, auths, vec![])
+    }
+}
+
+// ------------- TLS Identity -------------
+// A certificate for a TLS stand-in, issued for names (hostnames or
+// addresses) by a fresh self-signed CA
+pub struct TlsIdentity
+{
+    pub ca: CertificateDer<'static>,
+    pub cert: CertificateDer<'static>,
+    pub server: Arc<ServerConfig>,
+}
+
+impl TlsIdentity
+{
+    pub fn new(names: &[&str]) -> Self
+    {
+        TlsIdentity::with_extra_certs(names, &[])
+    }
+
+    // EFFECTS: Creates an identity as new does whose server sends extra
+    //          after its own certificate, as if they were intermediates
+    pub fn with_extra_certs(names: &[&str], extra: &[CertificateDer<'static>]) -> Self
+    {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let key = KeyPair::generate().unwrap();
+        let params = CertificateParams::new(names.iter().map(|n| n.to_string()).collect::<Vec<_>>()).unwrap();
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+
+        let server = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert([&[cert.der().clone()], extra].concat(),
+                              PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())))
+            .unwrap();
+
+        TlsIdentity { ca: ca.der().clone(), cert: cert.der().clone(), server: Arc::new(server) }
+    }
+}
+
+// ------------- StandIn -------------
+// A UDP server thread answering every query with a caller supplied function
+pub struct StandIn
+{
+    pub queries: Arc<Mutex<Vec<Question>>>,
+    pub clients: Arc<Mutex<Vec<SocketAddr>>>,
+    // The method and URI of each HTTPS request
+    pub requests: Arc<Mutex<Vec<String>>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StandIn
+{
+    // EFFECTS: Serves respond on sock; a None reply drops the query
+    pub fn spawn<F>(sock: UdpSocket, respond: F) -> Self
+    where F: Fn(&Message) -> Option<Message> + Send + 'static
+    {
+        StandIn::spawn_replies(sock, move |q| respond(q).into_iter().collect())
+    }
+
+    // EFFECTS: Serves respond on sock, sending each of its replies in turn,
+    //          as when a forged datagram races the real one
+    pub fn spawn_replies<F>(sock: UdpSocket, respond: F) -> Self
+    where F: Fn(&Message) -> Vec<Message> + Send + 'static
+    {
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        sock.set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+
+        let handle = {
+            let queries = queries.clone();
+            let clients = clients.clone();
+            let stop = stop.clone();
+
+            thread::spawn(move || {
+                let mut buf = [0; 4096];
+
+                while !stop.load(Ordering::SeqCst)
+                {
+                    let (len, src) = match sock.recv_from(&mut buf)
+                    {
+                        Ok(r) => r,
+                        Err(_) => continue,
+                    };
+
+                    if let Ok((_, query)) = parse_msg(&buf[..len])
+                    {
+                        queries.lock().unwrap().extend(query.quests.iter().flatten().cloned());
+                        clients.lock().unwrap().push(src);
+
+                        for resp in respond(&query)
+                        {
+                            let _ = sock.send_to(&resp.to_bytes(), src);
+                        }
+                    }
+                }
+            })
+        };
+
+        StandIn { queries, clients, requests: Default::default(), stop, handle: Some(handle) }
+    }
+
+    fn spawn_stream<F>(listener: TcpListener, tls: Option<Arc<ServerConfig>>, respond: F) -> Self
+    where F: Fn(&Message) -> Option<Message> + Send + 'static
+    {
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        listener.set_nonblocking(true).unwrap();
+
+        let handle = {
+            let queries = queries.clone();
+            let clients = clients.clone();
+            let stop = stop.clone();
+
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst)
+                {
+                    let (mut stream, src) = match listener.accept()
+                    {
+                        Ok(r) => r,
+                        Err(_) => {
+                            thread::sleep(POLL_INTERVAL);
+                            continue;
+                        },
+                    };
+
+                    clients.lock().unwrap().push(src);
+                    stream.set_nonblocking(false).unwrap();
+                    stream.set_read_timeout(Some(CONN_IDLE)).unwrap();
+
+                    match &tls
+                    {
+                        Some(config) => {
+                            let conn = ServerConnection::new(config.clone()).unwrap();
+                            answer_framed(&mut StreamOwned::new(conn, stream), &queries, &respond);
+                        },
+                        None => answer_framed(&mut stream, &queries, &respond),
+                    }
+                }
+            })
+        };
+
+        StandIn { queries, clients, requests: Default::default(), stop, handle: Some(handle) }
+    }
+
+    // EFFECTS: Serves respond to length prefixed queries on connections
+    //          accepted from listener, one connection at a time, each closed
+    //          once idle for CONN_IDLE
+    pub fn spawn_tcp<F>(listener: TcpListener, respond: F) -> Self
+    where F: Fn(&Message) -> Option<Message> + Send + 'static
+    {
+        StandIn::spawn_stream(listener, None, respond)
+    }
+
+    // EFFECTS: Serves respond as spawn_tcp does, inside TLS sessions
+    pub fn spawn_tls<F>(listener: TcpListener, tls: Arc<ServerConfig>, respond: F) -> Self
+    where F: Fn(&Message) -> Option<Message> + Send + 'static
+    {
+        StandIn::spawn_stream(listener, Some(tls), respond)
+    }
+
+    // EFFECTS: Serves respond to DNS-over-HTTPS requests (RFC 8484) for path
+    //          over HTTP/2 connections accepted from listener, any number at
+    //          a time. A None reply answers 404
+    pub fn spawn_https<F>(listener: TcpListener, tls: Arc<ServerConfig>, path: &str, respond: F) -> Self
+    where F: Fn(&Message) -> Option<Message> + Send + Sync + 'static
+    {
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let mut tls = (*tls).clone();
+        tls.alpn_protocols = vec![b"h2".to_vec()];
+        listener.set_nonblocking(true).unwrap();
+
+        let handle = {
+            let server = Arc::new(HttpsStandIn {
+                path: path.to_string(),
+                queries: queries.clone(),
+                requests: requests.clone(),
+                respond: Box::new(respond),
+            });
+            let clients = clients.clone();
+            let stop = stop.clone();
+
+            thread::spawn(move || {
+                let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+                rt.block_on(async move {
+                    let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                    let acceptor = TlsAcceptor::from(Arc::new(tls));
+
+                    while !stop.load(Ordering::SeqCst)
+                    {
+                        if let Ok(Ok((stream, src))) = tokio::time::timeout(POLL_INTERVAL, listener.accept()).await
+                        {
+                            clients.lock().unwrap().push(src);
+                            tokio::spawn(server.clone().serve(acceptor.clone(), stream));
+                        }
+                    }
+                });
+            })
+        };
+
+        StandIn { queries, clients, requests, stop, handle: Some(handle) }
+    }
+
+    pub fn serve(sock: UdpSocket, zone: Zone) -> Self
+    {
+        StandIn::spawn(sock, move |q| Some(zone.answer(q)))
+    }
+
+    pub fn serve_tcp(listener: TcpListener, zone: Zone) -> Self
+    {
+        StandIn::spawn_tcp(listener, move |q| Some(zone.answer(q)))
+    }
+
+    pub fn serve_tls(listener: TcpListener, tls: Arc<ServerConfig>, zone: Zone) -> Self
+    {
+        StandIn::spawn_tls(listener, tls, move |q| Some(zone.answer(q)))
+    }
+
+    pub fn query_count(&self) -> usize
+    {
+        self.queries.lock().unwrap().len()
+    }
+}
+
+// EFFECTS: Answers each query read from stream until it closes or idles
+fn answer_framed<S, F>(stream: &mut S, queries: &Mutex<Vec<Question>>, respond: &F)
+where S: Read + Write, F: Fn(&Message) -> Option<Message>
+{
+    while let Some(query) = read_framed(stream)
+    {
+        queries.lock().unwrap().extend(query.quests.iter().flatten().cloned());
+
+        if let Some(resp) = respond(&query)
+        {
+            let bytes = resp.to_bytes();
+            let _ = stream.write_all(&(bytes.len() as u16).to_be_bytes());
+            let _ = stream.write_all(&bytes);
+            let _ = stream.flush();
+        }
+    }
+}
+
+type Respond = Box<dyn Fn(&Message) -> Option<Message> + Send + Sync>;
+
+// What an HTTPS stand-in shares between its connections
+struct HttpsStandIn
+{
+    path: String,
+    queries: Arc<Mutex<Vec<Question>>>,
+    requests: Arc<Mutex<Vec<String>>>,
+    respond: Respond,
+}
+
+impl HttpsStandIn
+{
+    async fn serve(self: Arc<Self>, acceptor: TlsAcceptor, stream: tokio::net::TcpStream)
+    {
+        let mut conn = match acceptor.accept(stream).await
+        {
+            Ok(tls) => match h2::server::handshake(tls).await
+            {
+                Ok(conn) => conn,
+                Err(_) => return,
+            },
+            Err(_) => return,
+        };
+
+        // Each request gets a task, as its body only arrives while conn is
+        // being polled
+        while let Some(Ok((req, reply))) = conn.accept().await
+        {
+            tokio::spawn(self.clone().answer(req, reply));
+        }
+    }
+
+    async fn answer(self: Arc<Self>, req: http::Request<h2::RecvStream>, mut reply: h2::server::SendResponse<Bytes>)
+    {
+        self.requests.lock().unwrap().push(format!("{} {}", req.method(), req.uri()));
+
+        let body = if req.uri().path() != self.path
+        {
+            None
+        } else if req.method() == http::Method::GET {
+            req.uri()
+               .query()
+               .and_then(|q| q.split('&').find_map(|p| p.strip_prefix("dns=")))
+               .and_then(|dns| URL_SAFE_NO_PAD.decode(dns).ok())
+        } else {
+            let mut body = req.into_body();
+            let mut buf = Vec::new();
+            while let Some(Ok(chunk)) = body.data().await
+            {
+                let _ = body.flow_control().release_capacity(chunk.len());
+                buf.extend_from_slice(&chunk);
+            }
+            Some(buf)
+        };
+
+        let resp = body.and_then(|b| parse_msg(&b).ok().map(|(_, m)| m)).and_then(|query| {
+            self.queries.lock().unwrap().extend(query.quests.iter().flatten().cloned());
+            (self.respond)(&query)
+        });
+
+        let (status, body) = match resp
+        {
+            Some(resp) => (200, resp.to_bytes()),
+            None => (404, Vec::new()),
+        };
+        let head = http::Response::builder()
+            .status(status)
+            .header(http::header::CONTENT_TYPE, DNS_MESSAGE)
+            .body(())
+            .unwrap();
+
+        if let Ok(mut stream) = reply.send_response(head, false)
+        {
+            let _ = stream.send_data(Bytes::from(body), true);
+        }
+    }
+}
+
+fn read_framed<S: Read>(stream: &mut S) -> Option<Message>
+{
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).ok()?;
+    let mut buf = vec![0; u16::from_be_bytes(len).into()];
+    stream.read_exact(&mut buf).ok()?;
+
+    parse_msg(&buf).ok().map(|(_, m)| m)
+}
+
+impl Drop for StandIn
+{
+    fn drop(&mut self)
+    {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(h) = self.handle.take()
+        {
+            let _ = h.join();
+        }
+    }
+}
@@ -0,0 +1,919 @@
+use super::*;
+
+use std::fs;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::net::Ipv6Addr;
+use std::sync::atomic::AtomicBool;
+use std::time::Instant;
+
+use crate::dns::resourcerecord::RData;
+use cache::CacheConfig;
+use config::FamilyPreference;
+use standin::{ bind_loopback, response, StandIn, Zone, NEGATIVE_TTL };
+use transport::mock::MockTransport;
+
+fn lo(host: u8, port: u16) -> SocketAddr
+{
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, host)), port)
+}
+
+fn zone() -> Zone
+{
+    Zone::new("example.com")
+        .with("www.example.com", RData::A(10, 0, 0, 1))
+}
+
+// EFFECTS: A stub config using only the given upstreams, with no hosts file
+//          and no cache so that every lookup reaches them, resolving IPv4
+//          addresses only so that each resolve sends one query
+fn upstreams(nameservers: Vec<SocketAddr>) -> ResolverConfig
+{
+    ResolverConfig {
+        hosts_file: None,
+        nameservers,
+        timeout: Duration::from_millis(100),
+        attempts: 1,
+        cache: CacheConfig { max_entries: 0, ..Default::default() },
+        family: FamilyPreference::Ipv4Only,
+        ..Default::default()
+    }
+}
+
+// EFFECTS: Writes contents to a file unique to this test process and name
+fn temp_file(name: &str, contents: &str) -> PathBuf
+{
+    let path = std::env::temp_dir().join(format!("rresolve-{}-{}", std::process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn test_hosts_file_answers_without_network()
+{
+    let (port, mut socks) = bind_loopback(&[20]);
+    let upstream = StandIn::spawn(socks.remove(0), |_| None);
+
+    let config = ResolverConfig {
+        hosts_file: Some(temp_file("hosts", "10.1.2.3 db01.corp.example.com db01\n")),
+        nameservers: vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 20)), port)],
+        ..Default::default()
+    };
+
+    let r = Resolver::new(config);
+
+    assert_eq!(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3)), r.resolve("db01").unwrap());
+    assert_eq!(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3)), r.resolve("DB01.corp.example.com.").unwrap());
+    assert_eq!(0, upstream.query_count());
+}
+
+#[test]
+fn test_missing_hosts_file_is_empty()
+{
+    let config = ResolverConfig {
+        hosts_file: Some(PathBuf::from("/nonexistent/rresolve/hosts")),
+        ..Default::default()
+    };
+
+    assert!(Resolver::new(config).hosts.is_empty());
+}
+
+#[test]
+fn test_fails_over_to_next_nameserver()
+{
+    let (port, mut socks) = bind_loopback(&[21, 22]);
+    let silent = StandIn::spawn(socks.remove(0), |_| None);
+    let answering = StandIn::serve(socks.remove(0), zone());
+
+    let r = Resolver::new(upstreams(vec![lo(21, port), lo(22, port)]));
+
+    assert_eq!(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), r.resolve("www.example.com").unwrap());
+    assert_eq!(1, silent.query_count());
+    assert_eq!(1, answering.query_count());
+}
+
+#[test]
+fn test_retransmits_after_timeout()
+{
+    let (port, mut socks) = bind_loopback(&[23]);
+    let z = zone();
+    let dropped = AtomicBool::new(false);
+    // Drops the first query it sees
+    let server = StandIn::spawn(socks.remove(0), move |q| {
+        if dropped.swap(true, Ordering::SeqCst) { Some(z.answer(q)) } else { None }
+    });
+
+    let mut config = upstreams(vec![lo(23, port)]);
+    config.attempts = 2;
+
+    assert!(Resolver::new(config).resolve("www.example.com").is_ok());
+    assert_eq!(2, server.query_count());
+}
+
+#[test]
+fn test_backs_off_exponentially()
+{
+    let (port, mut socks) = bind_loopback(&[24]);
+    let server = StandIn::spawn(socks.remove(0), |_| None);
+
+    let mut config = upstreams(vec![lo(24, port)]);
+    config.timeout = Duration::from_millis(50);
+    config.attempts = 3;
+
+    let started = Instant::now();
+    assert!(Resolver::new(config).resolve("www.example.com").is_err());
+
+    // 50ms + 100ms + 200ms
+    assert!(started.elapsed() >= Duration::from_millis(350));
+    assert_eq!(3, server.query_count());
+}
+
+#[test]
+fn test_caps_backoff_of_many_attempts()
+{
+    let mut config = upstreams(vec![lo(1, DNS_PORT)]);
+    config.timeout = Duration::from_millis(50);
+    config.attempts = u8::MAX;
+    let r = Resolver::new(config);
+
+    assert_eq!(Duration::from_millis(800), r.attempt_timeout(4));
+    assert_eq!(Duration::from_millis(800), r.attempt_timeout(32));
+    assert_eq!(Duration::from_millis(800), r.attempt_timeout(u8::MAX));
+}
+
+#[test]
+fn test_rotates_across_nameservers()
+{
+    let (port, mut socks) = bind_loopback(&[25, 26]);
+    let first = StandIn::serve(socks.remove(0), zone());
+    let second = StandIn::serve(socks.remove(0), zone());
+
+    let mut config = upstreams(vec![lo(25, port), lo(26, port)]);
+    config.rotate = true;
+    let r = Resolver::new(config);
+
+    for _ in 0..4
+    {
+        r.lookup("www.example.com", QType::A).unwrap();
+    }
+
+    assert_eq!(2, first.query_count());
+    assert_eq!(2, second.query_count());
+}
+
+#[test]
+fn test_uses_a_fresh_source_port_per_query()
+{
+    let (port, mut socks) = bind_loopback(&[27]);
+    let server = StandIn::serve(socks.remove(0), zone());
+    let r = Resolver::new(upstreams(vec![lo(27, port)]));
+
+    for _ in 0..4
+    {
+        r.lookup("www.example.com", QType::A).unwrap();
+    }
+
+    let mut ports: Vec<u16> = server.clients.lock().unwrap().iter().map(|c| c.port()).collect();
+    ports.dedup();
+    assert!(ports.len() > 1);
+    assert!(ports.iter().all(|p| *p >= MIN_SRC_PORT));
+}
+
+// A tampering applied to the genuine answer, and whether to send the result
+// from the forger's socket rather than the server's
+type Forgery = (bool, Box<dyn Fn(&mut Message) + Send>);
+
+// EFFECTS: Serves a single query on sock, first sending each of the forged
+//          responses and a garbage datagram from forger, then the genuine
+//          answer when answer is set
+fn forge_once(sock: UdpSocket, forger: UdpSocket, forged: Vec<Forgery>, answer: bool)
+              -> std::thread::JoinHandle<()>
+{
+    std::thread::spawn(move || {
+        let mut buf = [0; 512];
+        let (len, client) = sock.recv_from(&mut buf).unwrap();
+        let (_, query) = parse_msg(&buf[..len]).unwrap();
+        let genuine = zone().answer(&query);
+
+        for (from_forger, tamper) in forged.iter()
+        {
+            let mut m = genuine.clone();
+            tamper(&mut m);
+            let from = if *from_forger { &forger } else { &sock };
+            from.send_to(&m.to_bytes(), client).unwrap();
+        }
+
+        forger.send_to(b"\x00\x01garbage", client).unwrap();
+
+        if answer
+        {
+            sock.send_to(&genuine.to_bytes(), client).unwrap();
+        }
+    })
+}
+
+fn forgeries() -> Vec<Forgery>
+{
+    vec![
+        // Right ID and question, wrong source address
+        (true, Box::new(|_: &mut Message| {})),
+        // Right source, wrong ID
+        (false, Box::new(|m: &mut Message| m.header.id = m.header.id.wrapping_add(1))),
+        // Right source and ID, wrong question
+        (false, Box::new(|m: &mut Message| {
+            m.quests.as_mut().unwrap()[0].qname = "evil.example.com".into();
+        })),
+    ]
+}
+
+#[test]
+fn test_discards_forged_responses()
+{
+    let (port, mut socks) = bind_loopback(&[28, 29]);
+    let sock = socks.remove(0);
+    let forger = socks.remove(0);
+    let server = forge_once(sock, forger, forgeries(), true);
+
+    let r = Resolver::new(upstreams(vec![lo(28, port)]));
+    let l = r.lookup("www.example.com", QType::A).unwrap();
+    server.join().unwrap();
+
+    assert_eq!(vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))], l.ips());
+    assert_eq!(4, r.rejected_responses());
+}
+
+#[test]
+fn test_times_out_when_only_forgeries_arrive()
+{
+    let (port, mut socks) = bind_loopback(&[30, 31]);
+    let sock = socks.remove(0);
+    let forger = socks.remove(0);
+    let server = forge_once(sock, forger, forgeries(), false);
+
+    let r = Resolver::new(upstreams(vec![lo(30, port)]));
+    assert!(r.lookup("www.example.com", QType::A).is_err());
+    server.join().unwrap();
+
+    assert_eq!(4, r.rejected_responses());
+}
+
+// EFFECTS: A zone whose answer for many.example.com is too big for 512 bytes
+fn big_zone() -> Zone
+{
+    (0..30).fold(zone(), |z, i| z.with("many.example.com", RData::A(10, 0, 1, i)))
+}
+
+#[test]
+fn test_retries_truncated_response_over_tcp()
+{
+    let (port, mut socks) = bind_loopback(&[32]);
+    let listener = TcpListener::bind(lo(32, port)).unwrap();
+    let z = big_zone();
+    // Over UDP, set TC and drop the answers as a server would
+    let udp = StandIn::spawn(socks.remove(0), move |q| {
+        let mut m = z.answer(q);
+        m.header.trunc_resp = true;
+        m.header.an_count = 0;
+        m.answs = None;
+        Some(m)
+    });
+    let tcp = StandIn::spawn_tcp(listener, {
+        let z = big_zone();
+        move |q| Some(z.answer(q))
+    });
+
+    let l = Resolver::new(upstreams(vec![lo(32, port)])).lookup("many.example.com", QType::A).unwrap();
+
+    assert_eq!(30, l.answers.len());
+    assert_eq!(1, udp.query_count());
+    assert_eq!(1, tcp.query_count());
+}
+
+#[test]
+fn test_tcp_only_mode_skips_udp()
+{
+    let (port, mut socks) = bind_loopback(&[33]);
+    let listener = TcpListener::bind(lo(33, port)).unwrap();
+    let udp = StandIn::serve(socks.remove(0), big_zone());
+    let tcp = StandIn::serve_tcp(listener, big_zone());
+
+    let mut config = upstreams(vec![lo(33, port)]);
+    config.tcp = true;
+    let l = Resolver::new(config).lookup("many.example.com", QType::A).unwrap();
+
+    assert_eq!(30, l.answers.len());
+    assert_eq!(0, udp.query_count());
+    assert_eq!(1, tcp.query_count());
+}
+
+#[test]
+fn test_edns_sizes_udp_buffer()
+{
+    let (port, mut socks) = bind_loopback(&[34]);
+    let z = big_zone();
+    let sizes = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let server = StandIn::spawn(socks.remove(0), {
+        let sizes = sizes.clone();
+        move |q| {
+            sizes.lock().unwrap().push(q.udp_payload_size());
+            Some(z.answer(q))
+        }
+    });
+
+    // Without EDNS the oversized datagram can't be taken whole
+    assert!(Resolver::new(upstreams(vec![lo(34, port)])).lookup("many.example.com", QType::A).is_err());
+
+    let mut config = upstreams(vec![lo(34, port)]);
+    config.edns0 = true;
+    let l = Resolver::new(config).lookup("many.example.com", QType::A).unwrap();
+
+    assert_eq!(30, l.answers.len());
+    assert_eq!(vec![512, usize::from(EDNS_PAYLOAD_SIZE)], *sizes.lock().unwrap());
+    assert_eq!(2, server.query_count());
+}
+
+#[test]
+fn test_answers_repeat_lookups_from_cache()
+{
+    let (port, mut socks) = bind_loopback(&[35]);
+    let z = zone().with("web.example.com", RData::CNAME("www.example.com".into()));
+    let server = StandIn::serve(socks.remove(0), z);
+
+    let mut config = upstreams(vec![lo(35, port)]);
+    config.cache = CacheConfig::default();
+    let r = Resolver::new(config);
+
+    let first = r.lookup("web.example.com", QType::A).unwrap();
+    let second = r.clone().lookup("WEB.example.com", QType::A).unwrap();
+
+    assert_eq!(first.ips(), second.ips());
+    assert_eq!(vec!["web.example.com"], second.aliases());
+    assert!(second.answers[0].ttl <= 300);
+    // The cached alias leads straight to the cached address
+    assert!(r.lookup("www.example.com", QType::A).is_ok());
+    assert_eq!(1, server.query_count());
+    assert_eq!(1, r.cache.stats().misses);
+    assert_eq!(3, r.cache.stats().hits);
+}
+
+// EFFECTS: A resolver whose every query times out at once, with records of
+//          the given TTL cached for www and web (an alias of it)
+fn unreachable_with_cached(ttl: u32, max_stale: u32) -> Resolver
+{
+    let mut config = upstreams(vec![lo(1, DNS_PORT)]);
+    config.cache = CacheConfig { max_stale, ..Default::default() };
+    let r = Resolver::with_transport(config, Arc::new(MockTransport::new()));
+
+    r.cache.insert(&[
+        ResourceRecord::new("web.example.com", ttl, RData::CNAME("www.example.com".into())),
+        ResourceRecord::new("www.example.com", ttl, RData::A(10, 0, 0, 1)),
+    ]);
+    r
+}
+
+#[test]
+fn test_serves_stale_answers_when_upstreams_fail()
+{
+    let r = unreachable_with_cached(1, 60);
+    std::thread::sleep(Duration::from_millis(1100));
+
+    let lookup = r.lookup("web.example.com", QType::A).unwrap();
+    assert!(lookup.stale);
+    assert_eq!(vec!["web.example.com"], lookup.aliases());
+    assert_eq!(vec![IpAddr::from([10, 0, 0, 1])], lookup.ips());
+    assert_eq!(r.config.cache.stale_answer_ttl, lookup.answers[0].ttl);
+    assert_eq!(2, r.cache.stats().stale_hits);
+
+    // Nothing cached at all is still a failure
+    assert!(r.lookup("ftp.example.com", QType::A).is_err());
+}
+
+#[test]
+fn test_serves_stale_answers_when_every_upstream_fails()
+{
+    let mut config = upstreams(vec![lo(1, DNS_PORT), lo(2, DNS_PORT)]);
+    config.cache = CacheConfig { max_stale: 60, ..Default::default() };
+    let mock = Arc::new(MockTransport::new().with_rcode("www.example.com", QType::A, RespCode::ServFail, vec![]));
+    let r = Resolver::with_transport(config, mock.clone());
+    r.cache.insert(&[ResourceRecord::new("www.example.com", 1, RData::A(10, 0, 0, 1))]);
+    std::thread::sleep(Duration::from_millis(1100));
+
+    // Both upstreams answer SERVFAIL, which RFC 8767 serves stale data for
+    let lookup = r.lookup("www.example.com", QType::A).unwrap();
+    assert!(lookup.stale);
+    assert_eq!(vec![IpAddr::from([10, 0, 0, 1])], lookup.ips());
+    assert_eq!(2, mock.queries().len());
+    assert_eq!(1, r.cache.stats().stale_hits);
+}
+
+#[test]
+fn test_fresh_answers_and_disabled_stale_window_are_not_stale()
+{
+    let r = unreachable_with_cached(300, 60);
+    assert!(!r.lookup("web.example.com", QType::A).unwrap().stale);
+
+    let r = unreachable_with_cached(1, 0);
+    std::thread::sleep(Duration::from_millis(1100));
+    assert!(r.lookup("web.example.com", QType::A).is_err());
+}
+
+#[test]
+fn test_prefetches_hot_entries_in_background()
+{
+    let (port, mut socks) = bind_loopback(&[75]);
+    let server = StandIn::serve(socks.remove(0), zone());
+
+    let mut config = upstreams(vec![lo(75, port)]);
+    // Every hit is late enough, so the second is due
+    config.cache = CacheConfig { prefetch_percent: 100, prefetch_min_hits: 2, ..Default::default() };
+    let r = Resolver::new(config);
+    r.cache.insert(&[ResourceRecord::new("www.example.com", 10, RData::A(10, 0, 0, 1))]);
+
+    assert!(r.lookup("www.example.com", QType::A).is_ok());
+    assert!(r.lookup("www.example.com", QType::A).is_ok());
+
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while r.cache.stats().inserts < 2 && Instant::now() < deadline
+    {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(1, server.query_count());
+    assert_eq!(1, r.cache.stats().prefetches);
+    // The refreshed entry carries the zone's own TTL
+    assert!(r.lookup("www.example.com", QType::A).unwrap().answers[0].ttl > 10);
+    assert_eq!(1, server.query_count());
+}
+
+#[test]
+fn test_caches_negative_answers()
+{
+    let (port, mut socks) = bind_loopback(&[36]);
+    let z = zone().with("gone.example.com", RData::CNAME("nope.example.com".into()));
+    let server = StandIn::serve(socks.remove(0), z);
+
+    let mut config = upstreams(vec![lo(36, port)]);
+    config.cache = CacheConfig::default();
+    let r = Resolver::new(config);
+
+    for _ in 0..2
+    {
+        let nx = r.lookup("gone.example.com", QType::A).unwrap();
+        assert_eq!(RespCode::NameError, nx.rcode);
+        assert_eq!(vec!["gone.example.com"], nx.aliases());
+
+        let nodata = r.lookup("www.example.com", QType::AAAA).unwrap();
+        assert_eq!(RespCode::Ok, nodata.rcode);
+        assert!(nodata.answers.is_empty());
+        assert!(nodata.soa.is_some());
+    }
+
+    // Cached for the SOA minimum rather than the SOA's own TTL
+    let cached = r.lookup("www.example.com", QType::AAAA).unwrap();
+    assert!(cached.soa.unwrap().ttl <= NEGATIVE_TTL);
+    // NXDOMAIN covers every type of the name
+    assert!(r.lookup("nope.example.com", QType::AAAA).unwrap().ips().is_empty());
+    assert_eq!(2, server.query_count());
+    assert_eq!(4, r.cache.stats().negative_hits);
+}
+
+fn dual_zone() -> Zone
+{
+    zone().with("dual.example.com", RData::A(10, 0, 0, 2))
+          .with("dual.example.com", RData::AAAA(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2))
+}
+
+fn v6(last: u16) -> IpAddr
+{
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, last))
+}
+
+#[test]
+fn test_sends_separate_concurrent_queries_per_family()
+{
+    let (port, mut socks) = bind_loopback(&[37]);
+    let sock = socks.remove(0);
+    let z = dual_zone();
+
+    // Answers nothing until both queries have arrived
+    let server = thread::spawn(move || {
+        let mut buf = [0; 512];
+        let queries: Vec<(Message, SocketAddr)> = (0..2).map(|_| {
+            let (len, src) = sock.recv_from(&mut buf).unwrap();
+            (parse_msg(&buf[..len]).unwrap().1, src)
+        }).collect();
+
+        for (q, src) in queries.iter()
+        {
+            sock.send_to(&z.answer(q).to_bytes(), src).unwrap();
+        }
+
+        queries.into_iter().map(|(q, _)| q.quests.unwrap()).collect::<Vec<_>>()
+    });
+
+    let mut config = upstreams(vec![lo(37, port)]);
+    config.timeout = Duration::from_secs(2);
+    config.family = FamilyPreference::Both;
+    let ips = Resolver::new(config).lookup_ip("dual.example.com").unwrap();
+
+    assert_eq!(vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), v6(2)], ips);
+
+    let mut qtypes: Vec<QType> = server.join()
+                                       .unwrap()
+                                       .into_iter()
+                                       .map(|qs| { assert_eq!(1, qs.len()); qs[0].qtype })
+                                       .collect();
+    qtypes.sort_by_key(|t| *t as u16);
+    assert_eq!(vec![QType::A, QType::AAAA], qtypes);
+}
+
+#[test]
+fn test_single_family_sends_one_query()
+{
+    let (port, mut socks) = bind_loopback(&[38]);
+    let server = StandIn::serve(socks.remove(0), dual_zone());
+
+    let mut config = upstreams(vec![lo(38, port)]);
+    config.family = FamilyPreference::Ipv6Only;
+
+    assert_eq!(v6(2), Resolver::new(config).resolve("dual.example.com").unwrap());
+    assert_eq!(vec![QType::AAAA], server.queries.lock().unwrap().iter().map(|q| q.qtype).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_merges_families_by_preference()
+{
+    let found = |ips: &[IpAddr]| -> Option<Result<Lookup, Box<dyn Error + Send + Sync>>> {
+        let mut l = Lookup::new("dual.example.com", QType::A);
+        l.answers = ips.iter().map(|ip| ResourceRecord::new("dual.example.com", 60, (*ip).into())).collect();
+        Some(Ok(l))
+    };
+    let v4 = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+    let merge = |family| merge_ips("dual.example.com", family, found(&[v4]), found(&[v6(2)])).unwrap();
+
+    assert_eq!(vec![v4, v6(2)], merge(FamilyPreference::Both));
+    assert_eq!(vec![v4], merge(FamilyPreference::Ipv4Only));
+    assert_eq!(vec![v4], merge(FamilyPreference::PreferIpv4));
+    assert_eq!(vec![v6(2)], merge(FamilyPreference::PreferIpv6));
+
+    // A preference falls back to the other family
+    assert_eq!(vec![v4], merge_ips("dual.example.com", FamilyPreference::PreferIpv6, found(&[v4]), found(&[])).unwrap());
+    // One failed query doesn't hide the other's addresses
+    assert_eq!(vec![v6(2)], merge_ips("dual.example.com", FamilyPreference::Both,
+                                      Some(Err("timed out".into())), found(&[v6(2)])).unwrap());
+    assert_eq!("timed out", merge_ips("dual.example.com", FamilyPreference::Both,
+                                      Some(Err("timed out".into())), found(&[])).unwrap_err().to_string());
+    assert!(merge_ips("dual.example.com", FamilyPreference::Both, found(&[]), found(&[])).is_err());
+}
+
+#[test]
+fn test_hosts_file_answers_both_families_without_network()
+{
+    let (port, mut socks) = bind_loopback(&[39]);
+    let upstream = StandIn::spawn(socks.remove(0), |_| None);
+
+    let mut config = upstreams(vec![lo(39, port)]);
+    config.hosts_file = Some(temp_file("hosts-v4", "10.1.2.3 db01\n"));
+    config.family = FamilyPreference::Both;
+    let r = Resolver::new(config.clone());
+
+    assert_eq!(vec![IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))], r.lookup_ip("db01").unwrap());
+    assert_eq!(0, upstream.query_count());
+
+    // Without an IPv6 entry, asking for IPv6 only goes to the nameservers
+    config.family = FamilyPreference::Ipv6Only;
+    assert!(Resolver::new(config).lookup_ip("db01").is_err());
+    assert_eq!(1, upstream.query_count());
+}
+
+#[test]
+fn test_reverse_lookup_queries_ptr_under_arpa_name()
+{
+    let (port, mut socks) = bind_loopback(&[29]);
+    let zone = Zone::new("2.0.192.in-addr.arpa")
+        .with("1.2.0.192.in-addr.arpa", RData::PTR("www.example.com.".into()))
+        .with("1.2.0.192.in-addr.arpa", RData::PTR("web.example.com.".into()));
+    let server = StandIn::serve(socks.remove(0), zone);
+    let r = Resolver::new(upstreams(vec![lo(29, port)]));
+
+    assert_eq!(vec!["www.example.com", "web.example.com"],
+               r.reverse_lookup(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))).unwrap());
+
+    let err = r.reverse_lookup(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))).unwrap_err();
+    assert!(err.to_string().contains("No hostname found for 192.0.2.2"), "{}", err);
+    assert_eq!(2, server.query_count());
+}
+
+#[test]
+fn test_hosts_file_answers_reverse_lookups_without_network()
+{
+    let (port, mut socks) = bind_loopback(&[31]);
+    let upstream = StandIn::spawn(socks.remove(0), |_| None);
+
+    let mut config = upstreams(vec![lo(31, port)]);
+    config.hosts_file = Some(temp_file("hosts-reverse", "10.1.2.3 db01.corp.example.com db01\n2001:db8::1 db02\n"));
+    let r = Resolver::new(config);
+
+    assert_eq!(vec!["db01.corp.example.com", "db01"], r.reverse_lookup(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))).unwrap());
+    assert_eq!(vec!["db02"], r.reverse_lookup(v6(1)).unwrap());
+    assert_eq!(0, upstream.query_count());
+
+    // A network prefix isn't an address the hosts file can name
+    assert!(r.lookup("2.1.10.in-addr.arpa", QType::PTR).is_err());
+    assert_eq!(1, upstream.query_count());
+}
+
+fn search_zone() -> Zone
+{
+    Zone::new("example.com")
+        .with("www.example.com", RData::A(10, 0, 0, 1))
+        .with("db01.corp.example.com", RData::A(10, 1, 0, 1))
+        .with("db02.example.com", RData::A(10, 1, 0, 2))
+}
+
+#[test]
+fn test_expands_short_names_with_search_list()
+{
+    let (port, mut socks) = bind_loopback(&[17]);
+    let server = StandIn::serve(socks.remove(0), search_zone());
+
+    let mut config = upstreams(vec![lo(17, port)]);
+    config.search = vec!["example.com".into(), "corp.example.com".into()];
+    let r = Resolver::new(config);
+
+    // db01.example.com doesn't exist, db01.corp.example.com does
+    assert_eq!(IpAddr::V4(Ipv4Addr::new(10, 1, 0, 1)), r.resolve("db01").unwrap());
+    assert_eq!(2, server.query_count());
+
+    // With at least ndots dots the name is tried as given first
+    assert_eq!(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), r.resolve("www.example.com").unwrap());
+    assert_eq!(3, server.query_count());
+}
+
+#[test]
+fn test_trailing_dot_skips_search_list()
+{
+    let (port, mut socks) = bind_loopback(&[18]);
+    let server = StandIn::serve(socks.remove(0), search_zone());
+
+    let mut config = upstreams(vec![lo(18, port)]);
+    config.search = vec!["corp.example.com".into()];
+    let r = Resolver::new(config);
+
+    assert!(r.resolve("db01.").is_err());
+    assert_eq!(1, server.query_count());
+}
+
+#[test]
+fn test_search_prefers_name_found_without_records()
+{
+    let (port, mut socks) = bind_loopback(&[19]);
+    let server = StandIn::serve(socks.remove(0), search_zone());
+
+    let mut config = upstreams(vec![lo(19, port)]);
+    config.search = vec!["example.com".into(), "corp.example.com".into()];
+    let r = Resolver::new(config);
+
+    // db02.example.com has no AAAA records and db02.corp.example.com and
+    // db02 don't exist; the name that does exist is the outcome
+    let l = r.search("db02", QType::AAAA).unwrap();
+    assert_eq!(("db02.example.com", RespCode::Ok), (l.qname.as_str(), l.rcode));
+    assert_eq!(3, server.query_count());
+
+    let l = r.search("db01", QType::A).unwrap();
+    assert_eq!("db01.corp.example.com", l.qname);
+}
+
+// EFFECTS: Returns the names a stand-in was asked, as sent
+fn qnames(server: &StandIn) -> Vec<String>
+{
+    server.queries.lock().unwrap().iter().map(|q| q.qname.clone()).collect()
+}
+
+#[test]
+fn test_randomizes_case_of_query_names()
+{
+    let (port, mut socks) = bind_loopback(&[57]);
+    let server = StandIn::serve(socks.remove(0), zone());
+
+    let mut config = upstreams(vec![lo(57, port)]);
+    config.randomize_case = true;
+    let r = Resolver::new(config);
+
+    for _ in 0..4
+    {
+        let l = r.lookup("www.example.com", QType::A).unwrap();
+        assert_eq!(vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))], l.ips());
+        assert_eq!("www.example.com", l.answers[0].name);
+    }
+
+    let sent = qnames(&server);
+    assert_eq!(4, sent.len());
+    assert!(sent.iter().all(|n| dns::name_eq(n, "www.example.com")));
+    assert!(sent.iter().any(|n| n != "www.example.com"));
+    assert!(r.case_blind_servers().is_empty());
+}
+
+// EFFECTS: Returns resp with the name of its question lowercased, as a
+//          server that doesn't preserve case sends it
+fn lowercased(mut resp: Message) -> Message
+{
+    for q in resp.quests.iter_mut().flatten()
+    {
+        q.qname = q.qname.to_ascii_lowercase();
+    }
+    resp
+}
+
+// EFFECTS: Returns resp with the case of the name of its question flipped,
+//          as a forger who guessed the ID but not the case sends it
+fn flipped_case(mut resp: Message) -> Message
+{
+    let q = &mut resp.quests.as_mut().unwrap()[0];
+    q.qname = q.qname
+               .chars()
+               .map(|c| if c.is_ascii_lowercase() { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() })
+               .collect();
+    resp
+}
+
+#[test]
+fn test_stops_randomizing_for_server_losing_case()
+{
+    let (port, mut socks) = bind_loopback(&[58]);
+    let listener = TcpListener::bind(lo(58, port)).unwrap();
+    let z = zone();
+    let udp = StandIn::spawn(socks.remove(0), move |q| Some(lowercased(z.answer(q))));
+    let z = zone();
+    let tcp = StandIn::spawn_tcp(listener, move |q| Some(lowercased(z.answer(q))));
+
+    let mut config = upstreams(vec![lo(58, port)]);
+    config.randomize_case = true;
+    let r = Resolver::new(config);
+
+    // The lowercased datagram proves nothing; the same over TCP does
+    assert!(r.resolve("www.example.com").is_ok());
+    assert_eq!(vec![lo(58, port)], r.case_blind_servers());
+    assert_eq!((1, 1), (udp.query_count(), tcp.query_count()));
+    assert_eq!(1, r.rejected_responses());
+
+    assert!(r.resolve("www.example.com").is_ok());
+    assert_eq!("www.example.com", qnames(&udp)[1]);
+    assert_eq!(1, tcp.query_count());
+}
+
+#[test]
+fn test_randomizes_case_again_once_mark_expires()
+{
+    let (port, mut socks) = bind_loopback(&[79]);
+    let server = StandIn::serve(socks.remove(0), zone());
+
+    let mut config = upstreams(vec![lo(79, port)]);
+    config.randomize_case = true;
+    let r = Resolver::new(config);
+    r.case_blind.lock().unwrap().insert(lo(79, port), Instant::now() - CASE_BLIND_FOR);
+
+    assert!(r.case_blind_servers().is_empty());
+    for _ in 0..4
+    {
+        assert!(r.resolve("www.example.com").is_ok());
+    }
+    assert!(qnames(&server).iter().any(|n| n != "www.example.com"));
+}
+
+#[test]
+fn test_discards_response_in_wrong_case()
+{
+    let (port, mut socks) = bind_loopback(&[59]);
+    let z = zone();
+    // A forged response in the wrong case arrives just ahead of the real one
+    let server = StandIn::spawn_replies(socks.remove(0), move |q| vec![flipped_case(z.answer(q)), z.answer(q)]);
+
+    let mut config = upstreams(vec![lo(59, port)]);
+    config.randomize_case = true;
+    let r = Resolver::new(config);
+
+    assert!(r.resolve("www.example.com").is_ok());
+    assert_eq!(1, server.query_count());
+    assert_eq!(1, r.rejected_responses());
+    assert!(r.case_blind_servers().is_empty());
+}
+
+#[test]
+fn test_forged_responses_in_wrong_case_dont_mark_server()
+{
+    let (port, mut socks) = bind_loopback(&[80]);
+    let listener = TcpListener::bind(lo(80, port)).unwrap();
+    let z = zone();
+    // Only forged responses come over UDP; TCP reaches the real server
+    let udp = StandIn::spawn(socks.remove(0), move |q| Some(flipped_case(z.answer(q))));
+    let tcp = StandIn::serve_tcp(listener, zone());
+
+    let mut config = upstreams(vec![lo(80, port)]);
+    config.randomize_case = true;
+    let r = Resolver::new(config);
+
+    let l = r.lookup("www.example.com", QType::A).unwrap();
+    assert_eq!("www.example.com", l.answers[0].name);
+    assert_eq!((1, 1), (udp.query_count(), tcp.query_count()));
+    assert!(r.case_blind_servers().is_empty());
+}
+
+#[test]
+fn test_prefers_fastest_nameserver()
+{
+    let (port, mut socks) = bind_loopback(&[66, 67]);
+    let z = zone();
+    let slow = StandIn::spawn(socks.remove(0), move |q| {
+        thread::sleep(Duration::from_millis(50));
+        Some(z.answer(q))
+    });
+    let fast = StandIn::serve(socks.remove(0), zone());
+
+    let mut r = Resolver::new(upstreams(vec![lo(66, port), lo(67, port)]));
+    r.srtt = Arc::new(Srtt::new(0.0));
+
+    // Each is tried once while unmeasured, then the faster is kept
+    for _ in 0..5
+    {
+        assert!(r.resolve("www.example.com").is_ok());
+    }
+
+    assert_eq!(1, slow.query_count());
+    assert_eq!(4, fast.query_count());
+    let stats = r.server_stats();
+    assert_eq!(vec![lo(67, port), lo(66, port)], stats.iter().map(|(s, _)| *s).collect::<Vec<_>>());
+    assert!(stats[1].1.srtt >= Duration::from_millis(50));
+}
+
+#[test]
+fn test_penalizes_nameserver_that_timed_out()
+{
+    let (port, mut socks) = bind_loopback(&[68, 69]);
+    let silent = StandIn::spawn(socks.remove(0), |_| None);
+    let answering = StandIn::serve(socks.remove(0), zone());
+
+    let mut r = Resolver::new(upstreams(vec![lo(68, port), lo(69, port)]));
+    r.srtt = Arc::new(Srtt::new(0.0));
+
+    for _ in 0..3
+    {
+        assert!(r.resolve("www.example.com").is_ok());
+    }
+
+    assert_eq!(1, silent.query_count());
+    assert_eq!(3, answering.query_count());
+    let (_, penalized) = r.server_stats()[1];
+    assert_eq!((0, 1), (penalized.responses, penalized.failures));
+    assert!(penalized.srtt >= Duration::from_millis(100));
+}
+
+#[test]
+fn test_fails_over_on_server_failure()
+{
+    let (port, mut socks) = bind_loopback(&[83, 84, 85]);
+    let failing = StandIn::spawn(socks.remove(0), |q| Some(response(q, RespCode::ServFail, false, vec![], vec![], vec![])));
+    let refusing = StandIn::spawn(socks.remove(0), |q| Some(response(q, RespCode::Refused, false, vec![], vec![], vec![])));
+    let answering = StandIn::serve(socks.remove(0), zone());
+
+    let mut r = Resolver::new(upstreams(vec![lo(83, port), lo(84, port), lo(85, port)]));
+    r.srtt = Arc::new(Srtt::new(0.0));
+
+    for _ in 0..3
+    {
+        assert!(r.resolve("www.example.com").is_ok());
+    }
+
+    assert_eq!((1, 1, 3), (failing.query_count(), refusing.query_count(), answering.query_count()));
+    let stats = r.server_stats();
+    assert_eq!(lo(85, port), stats[0].0);
+    assert!(stats[1..].iter().all(|(_, st)| (st.responses, st.failures) == (0, 1)));
+
+    // With nothing left to try, the failure is an error
+    let r = Resolver::new(upstreams(vec![lo(83, port)]));
+    assert!(r.lookup("www.example.com", QType::A).is_err());
+}
+
+#[test]
+fn test_answers_from_validated_denial_without_asking()
+{
+    let mock = Arc::new(MockTransport::new());
+    let mut config = upstreams(vec![lo(1, DNS_PORT)]);
+    config.cache = CacheConfig::default();
+    let r = Resolver::with_transport(config, mock.clone());
+
+    let soa = zone().answer(&Message::build_query(0, vec![
+        Question { qname: "nope.example.com".into(), qtype: QType::A, qclass: QClass::IN },
+    ])).auths.unwrap().remove(0);
+    let nsec = |owner: &str, next: &str| ResourceRecord::new(owner, 300, RData::NSEC {
+        next: next.into(),
+        types: vec![Type::A as u16, Type::NSEC as u16],
+    });
+    r.cache.insert_denial(&soa, &[nsec("example.com", "www.example.com"), nsec("www.example.com", "example.com")], true);
+
+    let l = r.lookup("nope.example.com", QType::A).unwrap();
+    assert_eq!((RespCode::NameError, true), (l.rcode, l.answers.is_empty()));
+    let l = r.lookup("www.example.com", QType::AAAA).unwrap();
+    assert_eq!((RespCode::Ok, true), (l.rcode, l.answers.is_empty()));
+    assert!(mock.queries().is_empty());
+}
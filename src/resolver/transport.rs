@@ -0,0 +1,103 @@
+use std::error::Error;
+use std::fmt::{ self, Debug };
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
+
+use crate::dns::message::Message;
+
+use super::{ check_case, exchange, exchange_tcp };
+
+pub mod https;
+pub mod mock;
+pub mod tls;
+
+#[cfg(test)]
+mod tests;
+
+// ------------- Transport -------------
+// How a query reaches a nameserver and its response comes back. Resolvers
+// hand every exchange to one, so lookups can be tested without sockets
+pub trait Transport: Debug + Send + Sync
+{
+    // EFFECTS: Sends query to server and returns its response, failing if
+    //          none that answers query arrives within timeout
+    fn exchange(&self, query: &Message, server: SocketAddr, timeout: Duration)
+                -> Result<Message, Box<dyn Error + Send + Sync>>;
+
+    // EFFECTS: Exchanges query as exchange does, but takes only a response
+    //          spelling the names of its question exactly as query does
+    //          ("0x20"), failing with a CaseMismatch otherwise. Over a
+    //          stream, where responses can't be forged, the first response
+    //          decides
+    fn exchange_exact(&self, query: &Message, server: SocketAddr, timeout: Duration)
+                      -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        check_case(self.exchange(query, server, timeout)?, query, server)
+    }
+}
+
+// ------------- Case Mismatch -------------
+// The error of an exact exchange that got responses only in the wrong case.
+// A response over a stream comes along, as proof that server doesn't
+// preserve case. Datagrams prove nothing, being easy to forge, so a
+// mismatch over UDP carries none
+#[derive(Debug)]
+pub struct CaseMismatch
+{
+    pub server: SocketAddr,
+    pub response: Option<Message>,
+}
+
+impl fmt::Display for CaseMismatch
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        write!(f, "Responses from {} spelled the question in the wrong case", self.server)
+    }
+}
+
+impl Error for CaseMismatch {}
+
+// ------------- UDP Transport -------------
+// One datagram from a random port per query, retried over TCP if the
+// response is truncated. Datagrams not answering the query are counted in
+// rejected
+#[derive(Debug, Default)]
+pub struct UdpTransport
+{
+    pub rejected: Arc<AtomicU64>,
+}
+
+impl Transport for UdpTransport
+{
+    fn exchange(&self, query: &Message, server: SocketAddr, timeout: Duration)
+                -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        exchange(query, server, timeout, &self.rejected, false)
+    }
+
+    // EFFECTS: Exchanges query as exchange does, counting any response in
+    //          the wrong case as rejected and waiting on for one in the
+    //          right case
+    fn exchange_exact(&self, query: &Message, server: SocketAddr, timeout: Duration)
+                      -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        exchange(query, server, timeout, &self.rejected, true)
+    }
+}
+
+// ------------- TCP Transport -------------
+// A connection per query, as resolv.conf's "use-vc" asks for
+#[derive(Debug, Default)]
+pub struct TcpTransport;
+
+impl Transport for TcpTransport
+{
+    fn exchange(&self, query: &Message, server: SocketAddr, timeout: Duration)
+                -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        exchange_tcp(query, server, timeout)
+    }
+}
@@ -0,0 +1,290 @@
+use base64::{ engine::general_purpose::URL_SAFE_NO_PAD, Engine };
+use bytes::Bytes;
+use h2::client::SendRequest;
+use http::{ header, Request, StatusCode, Uri };
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{ self, Arc };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::Duration;
+
+use rustls::{ pki_types::ServerName, ClientConfig };
+use tokio::net::TcpStream;
+use tokio::runtime::{ self, Runtime };
+use tokio::sync::Mutex;
+use tokio::time;
+use tokio_rustls::TlsConnector;
+
+use crate::dns::message::Message;
+use crate::parser::parse_msg;
+
+use super::{ tls::TlsConfig, Transport };
+
+#[cfg(test)]
+mod tests;
+
+pub const DOH_PORT: u16 = 443;
+pub const DNS_MESSAGE: &str = "application/dns-message";
+const MAX_BODY_SIZE: usize = 65_535;
+
+// How queries are carried, as RFC 8484 section 4.1 allows
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HttpsMethod
+{
+    // The message is the request body
+    #[default]
+    Post,
+    // The message is base64url encoded into the dns variable of the URI,
+    // which HTTP caches can key on
+    Get,
+}
+
+// ------------- HTTPS Config -------------
+// Where a DNS-over-HTTPS server takes queries, as a URI template such as
+// "https://dns.example.net/dns-query{?dns}" (RFC 8484 section 4.1). GET
+// expands its dns variable, or adds one if the template has none, while
+// POST leaves it out. The server is verified as tls says, against the
+// template's host unless tls has an auth_name
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HttpsConfig
+{
+    pub template: String,
+    pub method: HttpsMethod,
+    pub tls: TlsConfig,
+}
+
+impl HttpsConfig
+{
+    pub fn new(template: &str) -> Self
+    {
+        HttpsConfig { template: template.to_string(), ..Default::default() }
+    }
+
+    // EFFECTS: Expands the template with dns as the base64url value of its
+    //          dns variable, or with none
+    pub fn uri(&self, dns: Option<&[u8]>) -> Result<Uri, Box<dyn Error + Send + Sync>>
+    {
+        let param = dns.map(|d| format!("dns={}", URL_SAFE_NO_PAD.encode(d)));
+
+        let var = [("{?dns}", '?'), ("{&dns}", '&')].iter().find(|(var, _)| self.template.contains(var));
+        let expanded = match (var, &param)
+        {
+            (Some((var, sep)), Some(p)) => self.template.replace(var, &format!("{}{}", sep, p)),
+            (Some((var, _)), None) => self.template.replace(var, ""),
+            (None, Some(p)) if self.template.contains('?') => format!("{}&{}", self.template, p),
+            (None, Some(p)) => format!("{}?{}", self.template, p),
+            (None, None) => self.template.clone(),
+        };
+
+        let uri: Uri = expanded.parse()?;
+        if uri.scheme_str() != Some("https") || uri.host().is_none()
+        {
+            return Err(format!("'{}' is not an https URI template", self.template).into());
+        }
+
+        Ok(uri)
+    }
+}
+
+// The connection to one server, if any, numbered in the order opened so
+// that a query finding it closed clears only that one
+type Connection = Arc<Mutex<Option<(u64, SendRequest<Bytes>)>>>;
+
+// ------------- HTTPS Transport -------------
+// DNS over HTTPS (RFC 8484) over HTTP/2, connecting to each server address
+// but naming the template's host. Queries to a server share one connection
+// for as long as it stays open, concurrent ones as separate streams. Runs
+// its own tokio runtime, so exchange must not be called from within one,
+// though the transport may be dropped there
+#[derive(Debug)]
+pub struct HttpsTransport
+{
+    config: HttpsConfig,
+    client: Arc<ClientConfig>,
+    auth_name: ServerName<'static>,
+    // Only taken when dropped
+    runtime: Option<Runtime>,
+    connections: sync::Mutex<HashMap<SocketAddr, Connection>>,
+    opened: AtomicU64,
+}
+
+impl HttpsTransport
+{
+    // EFFECTS: Creates a transport for config, failing if its template or
+    //          TLS settings are malformed
+    pub fn new(config: HttpsConfig) -> Result<Self, Box<dyn Error + Send + Sync>>
+    {
+        let uri = config.uri(None)?;
+        let host = uri.host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+        let auth_name = ServerName::try_from(config.tls.auth_name.as_deref().unwrap_or(host))?.to_owned();
+
+        let mut client = config.tls.client_config()?;
+        client.alpn_protocols = vec![b"h2".to_vec()];
+
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+
+        Ok(HttpsTransport {
+            config,
+            client: Arc::new(client),
+            auth_name,
+            runtime: Some(runtime),
+            connections: sync::Mutex::new(HashMap::new()),
+            opened: AtomicU64::new(0),
+        })
+    }
+
+    // EFFECTS: Returns how many connections have been opened
+    pub fn connections_opened(&self) -> u64
+    {
+        self.opened.load(Ordering::Relaxed)
+    }
+
+    // EFFECTS: Returns the open connection to server once it can take
+    //          another stream, connecting anew if there is none or it closed.
+    //          Connecting to one server holds up only queries to it
+    async fn connection(&self, server: SocketAddr) -> Result<SendRequest<Bytes>, Box<dyn Error + Send + Sync>>
+    {
+        let slot = self.connections.lock().unwrap().entry(server).or_default().clone();
+
+        let cached = slot.lock().await.clone();
+        if let Some((number, h2)) = cached
+        {
+            if let Ok(h2) = h2.ready().await
+            {
+                return Ok(h2);
+            }
+
+            let mut conn = slot.lock().await;
+            if conn.as_ref().is_some_and(|(n, _)| *n == number)
+            {
+                *conn = None;
+            }
+        }
+
+        // Held while connecting, so that concurrent queries to server share
+        // the one connection made
+        let mut conn = slot.lock().await;
+        let h2 = match conn.as_ref()
+        {
+            Some((_, h2)) => h2.clone(),
+            None => {
+                let h2 = self.connect(server).await?;
+                *conn = Some((self.opened.fetch_add(1, Ordering::Relaxed), h2.clone()));
+                h2
+            },
+        };
+        drop(conn);
+
+        Ok(h2.ready().await?)
+    }
+
+    // EFFECTS: Opens a TLS connection to server and starts HTTP/2 on it
+    async fn connect(&self, server: SocketAddr) -> Result<SendRequest<Bytes>, Box<dyn Error + Send + Sync>>
+    {
+        let tcp = TcpStream::connect(server).await?;
+        let tls = TlsConnector::from(self.client.clone()).connect(self.auth_name.clone(), tcp).await?;
+        let (h2, conn) = h2::client::handshake(tls).await?;
+
+        // Drives the connection until the server or the last handle closes it
+        tokio::spawn(async move {
+            let _ = conn.await;
+        });
+
+        Ok(h2)
+    }
+
+    // EFFECTS: Sends query to server in an HTTP/2 request and parses the
+    //          message in the body of a successful response
+    async fn send(&self, query: &Message, server: SocketAddr) -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        let mut h2 = self.connection(server).await?;
+        let bytes = query.to_bytes();
+
+        let req = match self.config.method
+        {
+            HttpsMethod::Get => Request::get(self.config.uri(Some(&bytes))?),
+            HttpsMethod::Post => Request::post(self.config.uri(None)?)
+                .header(header::CONTENT_TYPE, DNS_MESSAGE)
+                .header(header::CONTENT_LENGTH, bytes.len()),
+        };
+        let req = req.header(header::ACCEPT, DNS_MESSAGE).body(())?;
+
+        let (resp, mut stream) = h2.send_request(req, self.config.method == HttpsMethod::Get)?;
+        if self.config.method == HttpsMethod::Post
+        {
+            stream.send_data(Bytes::from(bytes), true)?;
+        }
+
+        let resp = resp.await?;
+        if resp.status() != StatusCode::OK
+        {
+            return Err(format!("{} answered with HTTP status {}", server, resp.status()).into());
+        }
+        if resp.headers().get(header::CONTENT_TYPE).is_none_or(|t| t != DNS_MESSAGE)
+        {
+            return Err(format!("{} answered with something other than {}", server, DNS_MESSAGE).into());
+        }
+
+        let mut body = resp.into_body();
+        let mut buf = Vec::new();
+        while let Some(chunk) = body.data().await
+        {
+            let chunk = chunk?;
+            body.flow_control().release_capacity(chunk.len())?;
+            buf.extend_from_slice(&chunk);
+
+            if buf.len() > MAX_BODY_SIZE
+            {
+                return Err(format!("Response from {} is too large", server).into());
+            }
+        }
+
+        let (_, resp) = parse_msg(&buf).map_err(|e| format!("Malformed response from {}: {:?}", server, e))?;
+        if !resp.is_response_to(query)
+        {
+            return Err(format!("Response from {} over HTTPS doesn't match the query", server).into());
+        }
+
+        Ok(resp)
+    }
+}
+
+impl Transport for HttpsTransport
+{
+    // EFFECTS: Sends query to server with ID 0, as RFC 8484 section 4.1
+    //          recommends so that responses can be cached, and hands back
+    //          the response under the ID of query
+    fn exchange(&self, query: &Message, server: SocketAddr, timeout: Duration)
+                -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        let mut sent = query.clone();
+        sent.header.id = 0;
+
+        let runtime = self.runtime.as_ref().expect("runtime taken before drop");
+        let mut resp = runtime.block_on(async { time::timeout(timeout, self.send(&sent, server)).await })
+                              .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, format!("No response from {}", server)))??;
+        resp.header.id = query.header.id;
+
+        Ok(resp)
+    }
+}
+
+impl Drop for HttpsTransport
+{
+    // EFFECTS: Shuts the runtime down without waiting on its tasks, which
+    //          dropping it from within async code (as the last handle of an
+    //          AsyncResolver may be) would panic at
+    fn drop(&mut self)
+    {
+        if let Some(runtime) = self.runtime.take()
+        {
+            runtime.shutdown_background();
+        }
+    }
+}
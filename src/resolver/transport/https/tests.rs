@@ -0,0 +1,168 @@
+use super::*;
+
+use std::net::{ IpAddr, Ipv4Addr, TcpListener };
+use std::thread;
+use std::time::Instant;
+
+use crate::dns::{ resourcerecord::RData, QClass, QType, question::Question };
+use crate::resolver::{
+    asyncresolver::AsyncResolver,
+    cache::CacheConfig,
+    config::{ FamilyPreference, ResolverConfig },
+    standin::{ StandIn, TlsIdentity, Zone },
+    Resolver,
+};
+
+const TEMPLATE: &str = "https://dns.example.net/dns-query{?dns}";
+
+// EFFECTS: Serves a zone over DNS-over-HTTPS at /dns-query on 127.0.0.<host>
+//          with a certificate for dns.example.net, returning the CA too
+fn serve(host: u8) -> (SocketAddr, StandIn, TlsConfig)
+{
+    let identity = TlsIdentity::new(&["dns.example.net"]);
+    let listener = TcpListener::bind((Ipv4Addr::new(127, 0, 0, host), 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let zone = Zone::new("example.com")
+        .with("www.example.com", RData::A(10, 0, 0, 1))
+        .with("www.example.com", RData::AAAA(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+    let server = StandIn::spawn_https(listener, identity.server.clone(), "/dns-query", move |q| Some(zone.answer(q)));
+
+    (addr, server, TlsConfig { roots: vec![identity.ca], ..Default::default() })
+}
+
+fn transport(template: &str, method: HttpsMethod, tls: TlsConfig) -> Arc<HttpsTransport>
+{
+    Arc::new(HttpsTransport::new(HttpsConfig { template: template.into(), method, tls }).unwrap())
+}
+
+fn resolver(server: SocketAddr, transport: Arc<HttpsTransport>) -> Resolver
+{
+    let config = ResolverConfig {
+        hosts_file: None,
+        nameservers: vec![server],
+        timeout: Duration::from_secs(1),
+        attempts: 1,
+        cache: CacheConfig { max_entries: 0, ..Default::default() },
+        family: FamilyPreference::Ipv4Only,
+        ..Default::default()
+    };
+
+    Resolver::with_transport(config, transport)
+}
+
+fn query(id: u16) -> Message
+{
+    Message::build_query(id, vec![Question { qname: "www.example.com".into(), qtype: QType::A, qclass: QClass::IN }])
+}
+
+#[test]
+fn test_expands_uri_template()
+{
+    let uri = |template: &str, dns: Option<&[u8]>| HttpsConfig::new(template).uri(dns).unwrap().to_string();
+
+    assert_eq!("https://dns.example.net/dns-query", uri(TEMPLATE, None));
+    assert_eq!("https://dns.example.net/dns-query?dns=-_8", uri(TEMPLATE, Some(&[0xfb, 0xff])));
+    assert_eq!("https://dns.example.net/q?ct&dns=AA", uri("https://dns.example.net/q?ct{&dns}", Some(&[0])));
+    assert_eq!("https://dns.example.net/q?dns=AA", uri("https://dns.example.net/q", Some(&[0])));
+    assert!(HttpsConfig::new("http://dns.example.net/q{?dns}").uri(None).is_err());
+}
+
+#[test]
+fn test_posts_query_and_answers_under_its_id()
+{
+    let (addr, server, tls) = serve(60);
+    let doh = transport(TEMPLATE, HttpsMethod::Post, tls);
+    let q = query(4242);
+
+    let resp = doh.exchange(&q, addr, Duration::from_secs(1)).unwrap();
+
+    assert!(resp.is_response_to(&q));
+    assert_eq!(vec!["POST https://dns.example.net/dns-query"], *server.requests.lock().unwrap());
+}
+
+#[test]
+fn test_gets_query_as_base64url_parameter()
+{
+    let (addr, server, tls) = serve(61);
+    let r = resolver(addr, transport(TEMPLATE, HttpsMethod::Get, tls));
+
+    assert_eq!(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), r.resolve("www.example.com").unwrap());
+
+    let requests = server.requests.lock().unwrap();
+    let dns = requests[0].strip_prefix("GET https://dns.example.net/dns-query?dns=").unwrap();
+    let (_, sent) = parse_msg(&URL_SAFE_NO_PAD.decode(dns).unwrap()).unwrap();
+    assert_eq!(0, sent.header.id);
+    assert_eq!("www.example.com", sent.quests.unwrap()[0].qname);
+}
+
+#[test]
+fn test_shares_one_http2_connection()
+{
+    let (addr, server, tls) = serve(62);
+    let doh = transport(TEMPLATE, HttpsMethod::Post, tls);
+    let mut r = resolver(addr, doh.clone());
+    r.config.family = FamilyPreference::Both;
+
+    // Each lookup sends its A and AAAA queries as concurrent streams
+    for _ in 0..3
+    {
+        assert_eq!(2, r.lookup_ip("www.example.com").unwrap().len());
+    }
+
+    assert_eq!(1, doh.connections_opened());
+    assert_eq!(6, server.query_count());
+    assert_eq!(1, server.clients.lock().unwrap().len());
+}
+
+#[test]
+fn test_checks_certificate_names_template_host()
+{
+    let (addr, server, tls) = serve(63);
+    let r = resolver(addr, transport("https://doh.example.org/dns-query", HttpsMethod::Post, tls));
+
+    assert!(r.resolve("www.example.com").is_err());
+    assert_eq!(0, server.query_count());
+}
+
+#[test]
+fn test_fails_on_http_error_status()
+{
+    let (addr, _server, tls) = serve(64);
+    let r = resolver(addr, transport("https://dns.example.net/resolve{?dns}", HttpsMethod::Get, tls));
+
+    let err = r.resolve("www.example.com").unwrap_err();
+    assert!(err.to_string().contains("404"), "{}", err);
+}
+
+#[test]
+fn test_connecting_to_one_server_holds_up_no_other()
+{
+    let (addr, _server, tls) = serve(88);
+    // Accepts connections into its backlog but never speaks TLS
+    let blackhole = TcpListener::bind((Ipv4Addr::new(127, 0, 0, 89), 0)).unwrap();
+    let silent = blackhole.local_addr().unwrap();
+    let doh = transport(TEMPLATE, HttpsMethod::Post, tls);
+
+    let stuck = {
+        let doh = doh.clone();
+        thread::spawn(move || doh.exchange(&query(1), silent, Duration::from_secs(2)))
+    };
+    thread::sleep(Duration::from_millis(100));
+
+    let started = Instant::now();
+    assert!(doh.exchange(&query(2), addr, Duration::from_secs(1)).is_ok());
+    assert!(started.elapsed() < Duration::from_millis(500));
+    assert!(stuck.join().unwrap().is_err());
+}
+
+#[tokio::test]
+async fn test_async_resolver_drops_within_runtime()
+{
+    let (addr, _server, tls) = serve(90);
+    let r = AsyncResolver::from(resolver(addr, transport(TEMPLATE, HttpsMethod::Post, tls)));
+
+    assert_eq!(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), r.resolve("www.example.com").await.unwrap());
+
+    // The last handle to the transport, and so its runtime, goes here
+    drop(r);
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::dns::{
+    self, QType,
+    header::{ QR, RespCode },
+    message::Message,
+    question::Question,
+    resourcerecord::ResourceRecord,
+};
+
+use super::Transport;
+
+// ------------- Mock Transport -------------
+// Answers queries in memory from scripted responses, keyed on the question
+// asked whichever server it is sent to, and records every query it's given.
+// A question without a script times out at once
+#[derive(Debug, Default)]
+pub struct MockTransport
+{
+    // Keyed on the lowercased name without a trailing dot and the type
+    scripts: HashMap<(String, QType), Message>,
+    queries: Mutex<Vec<(SocketAddr, Question)>>,
+}
+
+impl MockTransport
+{
+    pub fn new() -> Self
+    {
+        MockTransport::default()
+    }
+
+    // EFFECTS: Scripts resp as the response to qname/qtype. Its ID and
+    //          question are replaced by those of each query it answers
+    pub fn with(mut self, qname: &str, qtype: QType, resp: Message) -> Self
+    {
+        self.scripts.insert(key(qname, qtype), resp);
+        self
+    }
+
+    // EFFECTS: Scripts a successful response to qname/qtype holding answs
+    pub fn with_answers(self, qname: &str, qtype: QType, answs: Vec<ResourceRecord>) -> Self
+    {
+        self.with(qname, qtype, response(RespCode::Ok, answs, Vec::new()))
+    }
+
+    // EFFECTS: Scripts a response to qname/qtype with rcode, no answers and
+    //          auths (typically the SOA of the zone) in its authority section
+    pub fn with_rcode(self, qname: &str, qtype: QType, rcode: RespCode, auths: Vec<ResourceRecord>) -> Self
+    {
+        self.with(qname, qtype, response(rcode, Vec::new(), auths))
+    }
+
+    // EFFECTS: Returns each server asked and the question it was asked, in
+    //          the order asked
+    pub fn queries(&self) -> Vec<(SocketAddr, Question)>
+    {
+        self.queries.lock().unwrap().clone()
+    }
+}
+
+impl Transport for MockTransport
+{
+    fn exchange(&self, query: &Message, server: SocketAddr, _timeout: Duration)
+                -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        let q = query.quests
+                     .as_ref()
+                     .and_then(|qs| qs.first())
+                     .ok_or("Query has no question")?;
+        self.queries.lock().unwrap().push((server, q.clone()));
+
+        let script = self.scripts.get(&key(&q.qname, q.qtype)).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::TimedOut, format!("No response from {}", server))
+        })?;
+
+        let mut resp = script.clone();
+        resp.header.id = query.header.id;
+        resp.header.qr = QR::Response;
+        resp.header.rec_desired = query.header.rec_desired;
+        resp.header.qd_count = query.header.qd_count;
+        resp.quests = query.quests.clone();
+
+        Ok(resp)
+    }
+}
+
+fn key(qname: &str, qtype: QType) -> (String, QType)
+{
+    (dns::trim_root(qname).to_ascii_lowercase(), qtype)
+}
+
+// EFFECTS: Builds a response from a recursive server with the given sections
+//          and no question, which exchange fills in
+pub fn response(rcode: RespCode, answs: Vec<ResourceRecord>, auths: Vec<ResourceRecord>) -> Message
+{
+    let mut m = Message::build_query(0, Vec::new());
+
+    m.header.qr = QR::Response;
+    m.header.rec_avail = true;
+    m.header.rcode = rcode;
+    m.header.an_count = answs.len() as u16;
+    m.header.ns_count = auths.len() as u16;
+    m.answs = if answs.is_empty() { None } else { Some(answs) };
+    m.auths = if auths.is_empty() { None } else { Some(auths) };
+
+    m
+}
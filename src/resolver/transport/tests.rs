@@ -0,0 +1,122 @@
+use super::*;
+use super::mock::MockTransport;
+
+use std::io;
+use std::net::{ IpAddr, Ipv4Addr };
+
+use crate::dns::{
+    QClass, QType,
+    header::{ QR, RespCode },
+    question::Question,
+    resourcerecord::{ RData, ResourceRecord },
+};
+use crate::resolver::{ cache::CacheConfig, config::{ FamilyPreference, ResolverConfig }, Resolver };
+
+// Never reached; the mock answers for every server
+fn server(last: u8) -> SocketAddr
+{
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, last)), 53)
+}
+
+fn query(id: u16, qname: &str, qtype: QType) -> Message
+{
+    Message::build_query(id, vec![Question { qname: qname.into(), qtype, qclass: QClass::IN }])
+}
+
+fn config(nameservers: Vec<SocketAddr>) -> ResolverConfig
+{
+    ResolverConfig {
+        hosts_file: None,
+        nameservers,
+        attempts: 1,
+        family: FamilyPreference::Ipv4Only,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_mock_answers_as_scripted()
+{
+    let mock = MockTransport::new()
+        .with_answers("www.example.com.", QType::A, vec![ResourceRecord::new("www.example.com", 300, RData::A(10, 0, 0, 1))]);
+    let q = query(4242, "WWW.example.com", QType::A);
+
+    let resp = mock.exchange(&q, server(1), Duration::from_secs(1)).unwrap();
+
+    assert!(resp.is_response_to(&q));
+    assert_eq!(QR::Response, resp.header.qr);
+    assert_eq!(1, resp.header.an_count);
+    assert_eq!(vec![(server(1), q.quests.unwrap()[0].clone())], mock.queries());
+}
+
+#[test]
+fn test_mock_times_out_unscripted_questions()
+{
+    let mock = MockTransport::new()
+        .with_answers("www.example.com", QType::A, Vec::new());
+
+    let err = mock.exchange(&query(1, "www.example.com", QType::AAAA), server(1), Duration::from_secs(1))
+                  .unwrap_err();
+
+    assert_eq!(io::ErrorKind::TimedOut, err.downcast::<io::Error>().unwrap().kind());
+    assert_eq!(1, mock.queries().len());
+}
+
+#[test]
+fn test_resolver_follows_aliases_across_queries()
+{
+    let mock = Arc::new(MockTransport::new()
+        .with_answers("web.example.com", QType::A, vec![
+            ResourceRecord::new("web.example.com", 300, RData::CNAME("cdn.example.net".into())),
+        ])
+        .with_answers("cdn.example.net", QType::A, vec![
+            ResourceRecord::new("cdn.example.net", 300, RData::A(10, 0, 0, 2)),
+        ]));
+    let r = Resolver::with_transport(config(vec![server(1)]), mock.clone());
+
+    let l = r.lookup("web.example.com", QType::A).unwrap();
+
+    assert_eq!(vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))], l.ips());
+    assert_eq!(vec!["web.example.com"], l.aliases());
+    let asked: Vec<_> = mock.queries().into_iter().map(|(_, q)| q.qname).collect();
+    assert_eq!(vec!["web.example.com", "cdn.example.net"], asked);
+}
+
+#[test]
+fn test_resolver_tries_each_server_per_attempt()
+{
+    let mock = Arc::new(MockTransport::new());
+    let mut config = config(vec![server(1), server(2)]);
+    config.attempts = 2;
+    let r = Resolver::with_transport(config, mock.clone());
+
+    assert!(r.resolve("www.example.com").is_err());
+    let servers: Vec<_> = mock.queries().into_iter().map(|(s, _)| s).collect();
+    assert_eq!(vec![server(1), server(2), server(1), server(2)], servers);
+}
+
+#[test]
+fn test_resolver_caches_scripted_nxdomain()
+{
+    let soa = ResourceRecord::new("example.com", 300, RData::SOA {
+        mname: "ns.example.com".into(),
+        rname: "hostmaster.example.com".into(),
+        serial: 1,
+        refresh: 3600,
+        retry: 600,
+        expire: 86400,
+        minimum: 60,
+    });
+    let mock = Arc::new(MockTransport::new()
+        .with_rcode("nope.example.com", QType::A, RespCode::NameError, vec![soa]));
+    let mut config = config(vec![server(1)]);
+    config.cache = CacheConfig::default();
+    let r = Resolver::with_transport(config, mock.clone());
+
+    for _ in 0..2
+    {
+        assert_eq!(RespCode::NameError, r.lookup("nope.example.com", QType::A).unwrap().rcode);
+    }
+
+    assert_eq!(1, mock.queries().len());
+}
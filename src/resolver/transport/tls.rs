@@ -0,0 +1,320 @@
+use base64::{ engine::general_purpose::STANDARD, Engine };
+use ring::digest::{ digest, SHA256 };
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::net::{ SocketAddr, TcpStream };
+use std::path::Path;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ Duration, Instant };
+
+use rustls::{
+    client::WebPkiServerVerifier,
+    client::danger::{ HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier },
+    crypto::{ self, WebPkiSupportedAlgorithms },
+    pki_types::{ pem::PemObject, CertificateDer, ServerName, TrustAnchor, UnixTime },
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme, StreamOwned,
+};
+
+use crate::dns::message::Message;
+use crate::resolver::exchange_stream;
+
+use super::Transport;
+
+#[cfg(test)]
+mod tests;
+
+pub const DOT_PORT: u16 = 853;
+// How long a connection may sit unused before it is closed rather than
+// reused (RFC 7858 section 3.4 asks clients not to hold them open for long)
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+// ------------- TLS Config -------------
+// How a DNS-over-TLS server is authenticated. With roots (the bundled
+// WebPKI roots if none are given) the server's chain must validate and name
+// auth_name, or its address when there is no auth_name. SPKI pins, each the
+// base64 SHA-256 of a SubjectPublicKeyInfo as in RFC 7858 section 4.2, must
+// then match a certificate on the validated path, or the root it ends at;
+// others the server sends don't count. Pins without roots authenticate
+// the server by its own key alone, with no chain validation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TlsConfig
+{
+    // Sent in SNI and checked against the certificate ("authentication
+    // domain name" in RFC 8310)
+    pub auth_name: Option<String>,
+    pub roots: Vec<CertificateDer<'static>>,
+    pub spki_pins: Vec<String>,
+}
+
+impl TlsConfig
+{
+    // EFFECTS: Reads the PEM certificates in path, for use as roots
+    pub fn read_roots<P: AsRef<Path>>(path: P) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error + Send + Sync>>
+    {
+        let roots = CertificateDer::pem_file_iter(path)?.collect::<Result<Vec<_>, _>>()?;
+        if roots.is_empty()
+        {
+            return Err("No certificates found".into());
+        }
+
+        Ok(roots)
+    }
+
+    // EFFECTS: Builds a TLS client config verifying servers as described
+    //          above, failing if a pin or root is malformed
+    pub fn client_config(&self) -> Result<ClientConfig, Box<dyn Error + Send + Sync>>
+    {
+        let provider = Arc::new(crypto::ring::default_provider());
+
+        let pins = self.spki_pins
+                       .iter()
+                       .map(|pin| STANDARD.decode(pin).map_err(|e| format!("Invalid SPKI pin '{}': {}", pin, e)))
+                       .collect::<Result<_, _>>()?;
+
+        let mut anchors = Vec::new();
+        let pkix = if self.spki_pins.is_empty() || !self.roots.is_empty()
+        {
+            let mut roots = RootCertStore::empty();
+            if self.roots.is_empty()
+            {
+                roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            }
+            roots.add_parsable_certificates(self.roots.iter().cloned());
+            anchors = roots.roots.clone();
+
+            Some(WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?)
+        } else {
+            None
+        };
+
+        let verifier = PinningVerifier { pkix, anchors, pins, algs: provider.signature_verification_algorithms };
+
+        Ok(ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth())
+    }
+}
+
+// EFFECTS: Returns the SPKI pin of cert, as TlsConfig::spki_pins holds them
+pub fn spki_pin(cert: &CertificateDer) -> Result<String, Box<dyn Error + Send + Sync>>
+{
+    let cert = webpki::EndEntityCert::try_from(cert)?;
+
+    Ok(STANDARD.encode(digest(&SHA256, &cert.subject_public_key_info())))
+}
+
+// ------------- TLS Transport -------------
+// DNS over TLS (RFC 7858): TCP framed messages inside a TLS session. After
+// an exchange the connection is kept for the next query to the same server,
+// and one that has gone stale is replaced by a fresh connection
+#[derive(Debug)]
+pub struct TlsTransport
+{
+    client: Arc<ClientConfig>,
+    auth_name: Option<ServerName<'static>>,
+    idle: Mutex<HashMap<SocketAddr, (TlsStream, Instant)>>,
+    opened: AtomicU64,
+}
+
+impl TlsTransport
+{
+    // EFFECTS: Creates a transport authenticating servers as config says,
+    //          failing if a pin, root or auth_name is malformed
+    pub fn new(config: &TlsConfig) -> Result<Self, Box<dyn Error + Send + Sync>>
+    {
+        let client = config.client_config()?;
+
+        let auth_name = config.auth_name
+                              .as_ref()
+                              .map(|name| ServerName::try_from(name.clone()))
+                              .transpose()?;
+
+        Ok(TlsTransport {
+            client: Arc::new(client),
+            auth_name,
+            idle: Mutex::new(HashMap::new()),
+            opened: AtomicU64::new(0),
+        })
+    }
+
+    // EFFECTS: Returns how many connections have been opened
+    pub fn connections_opened(&self) -> u64
+    {
+        self.opened.load(Ordering::Relaxed)
+    }
+
+    // EFFECTS: Takes the idle connection to server, unless it has sat idle
+    //          too long
+    fn take_idle(&self, server: SocketAddr) -> Option<TlsStream>
+    {
+        match self.idle.lock().unwrap().remove(&server)
+        {
+            Some((stream, since)) if since.elapsed() < IDLE_TIMEOUT => Some(stream),
+            _ => None,
+        }
+    }
+
+    // EFFECTS: Opens a connection to server. The handshake happens with the
+    //          first query written to it
+    fn connect(&self, server: SocketAddr, timeout: Duration) -> Result<TlsStream, Box<dyn Error + Send + Sync>>
+    {
+        let name = self.auth_name.clone().unwrap_or_else(|| server.ip().into());
+        let conn = ClientConnection::new(self.client.clone(), name)?;
+        let sock = TcpStream::connect_timeout(&server, timeout)?;
+        self.opened.fetch_add(1, Ordering::Relaxed);
+
+        Ok(StreamOwned::new(conn, sock))
+    }
+}
+
+impl Transport for TlsTransport
+{
+    // EFFECTS: Sends query over the idle connection to server if there is
+    //          one, or else (or if it fails) over a new one. Each step may
+    //          take up to timeout
+    fn exchange(&self, query: &Message, server: SocketAddr, timeout: Duration)
+                -> Result<Message, Box<dyn Error + Send + Sync>>
+    {
+        let send = |stream: &mut TlsStream| -> Result<Message, Box<dyn Error + Send + Sync>> {
+            stream.sock.set_read_timeout(Some(timeout))?;
+            stream.sock.set_write_timeout(Some(timeout))?;
+            exchange_stream(stream, query, server)
+        };
+
+        if let Some(mut stream) = self.take_idle(server)
+        {
+            // The server may have closed it meanwhile
+            if let Ok(resp) = send(&mut stream)
+            {
+                self.idle.lock().unwrap().insert(server, (stream, Instant::now()));
+                return Ok(resp);
+            }
+        }
+
+        let mut stream = self.connect(server, timeout)?;
+        let resp = send(&mut stream)?;
+        self.idle.lock().unwrap().insert(server, (stream, Instant::now()));
+
+        Ok(resp)
+    }
+}
+
+// ------------- Pinning Verifier -------------
+// Validates the server's chain with pkix if given, then requires a pin to
+// match a certificate on a path from the end entity to one of anchors (the
+// roots of pkix). Any other certificate the server sends is ignored, lest
+// a chain that validates through another CA carry a pinned certificate
+// along. Without pkix only the end entity's key can be trusted, having
+// signed the handshake, so only it may match
+#[derive(Debug)]
+struct PinningVerifier
+{
+    pkix: Option<Arc<WebPkiServerVerifier>>,
+    anchors: Vec<TrustAnchor<'static>>,
+    pins: Vec<Vec<u8>>,
+    algs: WebPkiSupportedAlgorithms,
+}
+
+impl PinningVerifier
+{
+    // EFFECTS: Returns true if spki, a DER SubjectPublicKeyInfo, is pinned
+    fn is_pinned(&self, spki: &[u8]) -> bool
+    {
+        let hash = digest(&SHA256, spki);
+
+        self.pins.iter().any(|pin| pin.as_slice() == hash.as_ref())
+    }
+
+    // EFFECTS: Returns true if a path webpki can build from end_entity
+    //          through intermediates to one of the anchors holds a pinned
+    //          certificate or ends at a pinned root
+    fn path_is_pinned(&self, end_entity: &CertificateDer, intermediates: &[CertificateDer], now: UnixTime) -> bool
+    {
+        let cert = match webpki::EndEntityCert::try_from(end_entity)
+        {
+            Ok(cert) => cert,
+            Err(_) => return false,
+        };
+
+        let pinned = |path: &webpki::VerifiedPath| {
+            // Anchors hold their SubjectPublicKeyInfo without its SEQUENCE
+            let anchor = der_sequence(&path.anchor().subject_public_key_info);
+            let on_path = self.is_pinned(&path.end_entity().subject_public_key_info())
+                || path.intermediate_certificates().any(|c| self.is_pinned(&c.subject_public_key_info()))
+                || self.is_pinned(&anchor);
+
+            if on_path { Ok(()) } else { Err(webpki::Error::UnknownIssuer) }
+        };
+
+        cert.verify_for_usage(self.algs.all, &self.anchors, intermediates, now,
+                              webpki::KeyUsage::server_auth(), None, Some(&pinned))
+            .is_ok()
+    }
+}
+
+// EFFECTS: Wraps contents in a DER SEQUENCE
+fn der_sequence(contents: &[u8]) -> Vec<u8>
+{
+    let len = contents.len();
+    let mut der = vec![0x30];
+
+    if len < 0x80
+    {
+        der.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len.to_be_bytes().iter().copied().skip_while(|b| *b == 0).collect();
+        der.push(0x80 | bytes.len() as u8);
+        der.extend(bytes);
+    }
+    der.extend_from_slice(contents);
+
+    der
+}
+
+impl ServerCertVerifier for PinningVerifier
+{
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, intermediates: &[CertificateDer<'_>],
+                          server_name: &ServerName<'_>, ocsp_response: &[u8], now: UnixTime)
+                          -> Result<ServerCertVerified, rustls::Error>
+    {
+        let pinned = match &self.pkix
+        {
+            Some(pkix) => {
+                pkix.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+                self.pins.is_empty() || self.path_is_pinned(end_entity, intermediates, now)
+            },
+            None => webpki::EndEntityCert::try_from(end_entity)
+                        .is_ok_and(|c| self.is_pinned(&c.subject_public_key_info())),
+        };
+
+        if pinned
+        {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General("No certificate matches the SPKI pins".into()))
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+                              -> Result<HandshakeSignatureValid, rustls::Error>
+    {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algs)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct)
+                              -> Result<HandshakeSignatureValid, rustls::Error>
+    {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algs)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme>
+    {
+        self.algs.supported_schemes()
+    }
+}
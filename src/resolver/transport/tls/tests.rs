@@ -0,0 +1,187 @@
+use super::*;
+
+use std::net::{ IpAddr, Ipv4Addr, TcpListener };
+use std::thread;
+
+use crate::dns::{ resourcerecord::RData, QType };
+use crate::resolver::{
+    cache::CacheConfig,
+    config::{ FamilyPreference, ResolverConfig },
+    standin::{ StandIn, TlsIdentity, Zone, CONN_IDLE },
+    Resolver,
+};
+
+const AUTH_NAME: &str = "dns.example.net";
+
+// EFFECTS: Serves a zone over TLS on 127.0.0.<host> as identity
+fn serve(host: u8, identity: &TlsIdentity) -> (SocketAddr, StandIn)
+{
+    let listener = TcpListener::bind((Ipv4Addr::new(127, 0, 0, host), 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let zone = Zone::new("example.com").with("www.example.com", RData::A(10, 0, 0, 1));
+
+    (addr, StandIn::serve_tls(listener, identity.server.clone(), zone))
+}
+
+fn resolver(server: SocketAddr, transport: Arc<TlsTransport>) -> Resolver
+{
+    let config = ResolverConfig {
+        hosts_file: None,
+        nameservers: vec![server],
+        timeout: Duration::from_millis(500),
+        attempts: 1,
+        cache: CacheConfig { max_entries: 0, ..Default::default() },
+        family: FamilyPreference::Ipv4Only,
+        ..Default::default()
+    };
+
+    Resolver::with_transport(config, transport)
+}
+
+fn trusting(identity: &TlsIdentity) -> TlsConfig
+{
+    TlsConfig { auth_name: Some(AUTH_NAME.into()), roots: vec![identity.ca.clone()], ..Default::default() }
+}
+
+#[test]
+fn test_answers_over_tls_from_trusted_server()
+{
+    let identity = TlsIdentity::new(&[AUTH_NAME]);
+    let (addr, server) = serve(50, &identity);
+    let r = resolver(addr, Arc::new(TlsTransport::new(&trusting(&identity)).unwrap()));
+
+    assert_eq!(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), r.resolve("www.example.com").unwrap());
+    assert_eq!(1, server.query_count());
+}
+
+#[test]
+fn test_reuses_connection_until_server_closes_it()
+{
+    let identity = TlsIdentity::new(&[AUTH_NAME]);
+    let (addr, server) = serve(51, &identity);
+    let tls = Arc::new(TlsTransport::new(&trusting(&identity)).unwrap());
+    let r = resolver(addr, tls.clone());
+
+    for _ in 0..3
+    {
+        r.lookup("www.example.com", QType::A).unwrap();
+    }
+    assert_eq!(1, tls.connections_opened());
+
+    // The stand-in hangs up on idle connections; the next query reconnects
+    thread::sleep(CONN_IDLE * 2);
+    r.lookup("www.example.com", QType::A).unwrap();
+
+    assert_eq!(2, tls.connections_opened());
+    assert_eq!(4, server.query_count());
+    assert_eq!(2, server.clients.lock().unwrap().len());
+}
+
+#[test]
+fn test_checks_certificate_names_auth_name()
+{
+    let identity = TlsIdentity::new(&[AUTH_NAME]);
+    let (addr, server) = serve(52, &identity);
+    let config = TlsConfig { auth_name: Some("other.example.net".into()), ..trusting(&identity) };
+    let r = resolver(addr, Arc::new(TlsTransport::new(&config).unwrap()));
+
+    assert!(r.resolve("www.example.com").is_err());
+    assert_eq!(0, server.query_count());
+}
+
+#[test]
+fn test_checks_address_without_auth_name()
+{
+    let identity = TlsIdentity::new(&["127.0.0.53"]);
+    let (addr, _server) = serve(53, &identity);
+    let config = TlsConfig { auth_name: None, ..trusting(&identity) };
+    let r = resolver(addr, Arc::new(TlsTransport::new(&config).unwrap()));
+
+    assert!(r.resolve("www.example.com").is_ok());
+}
+
+#[test]
+fn test_rejects_certificate_from_untrusted_ca()
+{
+    let identity = TlsIdentity::new(&[AUTH_NAME]);
+    let (addr, server) = serve(54, &identity);
+    let r = resolver(addr, Arc::new(TlsTransport::new(&trusting(&TlsIdentity::new(&[AUTH_NAME]))).unwrap()));
+
+    assert!(r.resolve("www.example.com").is_err());
+    assert_eq!(0, server.query_count());
+}
+
+#[test]
+fn test_pin_alone_authenticates_server_key()
+{
+    let identity = TlsIdentity::new(&[AUTH_NAME]);
+    let (addr, _server) = serve(55, &identity);
+    let config = TlsConfig { spki_pins: vec![spki_pin(&identity.cert).unwrap()], ..Default::default() };
+    let r = resolver(addr, Arc::new(TlsTransport::new(&config).unwrap()));
+
+    assert!(r.resolve("www.example.com").is_ok());
+}
+
+#[test]
+fn test_rejects_server_not_matching_pins()
+{
+    let identity = TlsIdentity::new(&[AUTH_NAME]);
+    let (addr, server) = serve(56, &identity);
+    let other = spki_pin(&TlsIdentity::new(&[AUTH_NAME]).cert).unwrap();
+
+    // Neither alone nor on top of a valid chain
+    for config in [
+        TlsConfig { spki_pins: vec![other.clone()], ..Default::default() },
+        TlsConfig { spki_pins: vec![other], ..trusting(&identity) },
+    ]
+    {
+        let r = resolver(addr, Arc::new(TlsTransport::new(&config).unwrap()));
+        assert!(r.resolve("www.example.com").is_err());
+    }
+
+    assert_eq!(0, server.query_count());
+}
+
+#[test]
+fn test_ignores_pinned_certificate_off_the_validated_path()
+{
+    let genuine = TlsIdentity::new(&[AUTH_NAME]);
+    // Validly issued by another trusted CA, and sending the genuine
+    // server's certificate along as if it were an intermediate
+    let impostor = TlsIdentity::with_extra_certs(&[AUTH_NAME], &[genuine.cert.clone(), genuine.ca.clone()]);
+    let (addr, server) = serve(77, &impostor);
+
+    for pinned in [&genuine.cert, &genuine.ca]
+    {
+        let config = TlsConfig {
+            auth_name: Some(AUTH_NAME.into()),
+            roots: vec![impostor.ca.clone(), genuine.ca.clone()],
+            spki_pins: vec![spki_pin(pinned).unwrap()],
+        };
+        let r = resolver(addr, Arc::new(TlsTransport::new(&config).unwrap()));
+
+        assert!(r.resolve("www.example.com").is_err());
+    }
+
+    assert_eq!(0, server.query_count());
+}
+
+#[test]
+fn test_pin_matches_root_of_validated_path()
+{
+    let identity = TlsIdentity::new(&[AUTH_NAME]);
+    let (addr, server) = serve(78, &identity);
+    let config = TlsConfig { spki_pins: vec![spki_pin(&identity.ca).unwrap()], ..trusting(&identity) };
+    let r = resolver(addr, Arc::new(TlsTransport::new(&config).unwrap()));
+
+    assert!(r.resolve("www.example.com").is_ok());
+    assert_eq!(1, server.query_count());
+}
+
+#[test]
+fn test_rejects_malformed_pins()
+{
+    let config = TlsConfig { spki_pins: vec!["not base64!".into()], ..Default::default() };
+
+    assert!(TlsTransport::new(&config).is_err());
+}